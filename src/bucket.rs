@@ -0,0 +1,121 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use s3::{Bucket, Region, creds::Credentials};
+
+/// Build a bucket handle from the standard AWS environment variables.
+///
+/// Credentials are read from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+/// `AWS_SESSION_TOKEN` (falling back to the shared profile). The region comes from
+/// `AWS_REGION` / `AWS_DEFAULT_REGION`. When `AWS_ENDPOINT_URL_S3` or `AWS_ENDPOINT_URL`
+/// is set, that endpoint is used with path-style addressing, which is what MinIO and most
+/// other S3-compatible stores expect.
+pub fn open(name: &str) -> Result<Box<Bucket>> {
+    let region_name = std::env::var("AWS_REGION")
+        .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+        .unwrap_or_else(|_| "us-east-1".to_string());
+    let endpoint = std::env::var("AWS_ENDPOINT_URL_S3")
+        .or_else(|_| std::env::var("AWS_ENDPOINT_URL"))
+        .ok();
+
+    let credentials = Credentials::default().context("Failed to load S3 credentials")?;
+    let bucket = match endpoint {
+        Some(endpoint) => {
+            let region = Region::Custom {
+                region: region_name,
+                endpoint,
+            };
+            Bucket::new(name, region, credentials)?.with_path_style()
+        }
+        None => {
+            let region = region_name
+                .parse::<Region>()
+                .with_context(|| format!("invalid AWS region: {region_name}"))?;
+            Bucket::new(name, region, credentials)?
+        }
+    };
+    Ok(bucket)
+}
+
+/// Returns true if `key` already exists in `bucket`.
+pub async fn exists(bucket: &Bucket, key: &str) -> Result<bool> {
+    match bucket.head_object(key).await {
+        Ok((_, code)) => Ok(code == 200),
+        Err(s3::error::S3Error::HttpFailWithBody(404, _)) => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("while checking s3://{}/{key}", bucket.name())),
+    }
+}
+
+/// The ETag of `key`, if the store reports one.
+pub async fn etag(bucket: &Bucket, key: &str) -> Result<Option<String>> {
+    let (head, _) = bucket
+        .head_object(key)
+        .await
+        .with_context(|| format!("while checking s3://{}/{key}", bucket.name()))?;
+    Ok(head.e_tag)
+}
+
+/// Upload a local file to `bucket`. Large files are sent as a multipart upload.
+pub async fn upload(local: &Path, bucket: &Bucket, key: &str) -> Result<()> {
+    let mut file = tokio::fs::File::open(local)
+        .await
+        .with_context(|| format!("Failed to open {}", local.display()))?;
+    bucket
+        .put_object_stream(&mut file, key)
+        .await
+        .with_context(|| format!("Failed to upload to s3://{}/{key}", bucket.name()))?;
+    Ok(())
+}
+
+/// These run against a real S3-compatible store, e.g. a local MinIO:
+///
+/// ```sh
+/// docker run -d -p 9000:9000 minio/minio server /data
+/// export AWS_ENDPOINT_URL=http://localhost:9000
+/// export AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin
+/// aws s3 mb s3://test && export S3_TEST_BUCKET=test
+/// cargo test -- --ignored bucket
+/// ```
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn test_bucket() -> Box<Bucket> {
+        let name = std::env::var("S3_TEST_BUCKET").expect("S3_TEST_BUCKET is not set");
+        open(&name).unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible store, see S3_TEST_BUCKET"]
+    async fn upload_then_check() {
+        let bucket = test_bucket();
+        let key = "bucket-test/upload.bin";
+        let mut local = tempfile::NamedTempFile::new().unwrap();
+        local.write_all(b"tile data").unwrap();
+
+        upload(local.path(), &bucket, key).await.unwrap();
+        assert!(exists(&bucket, key).await.unwrap());
+        assert!(etag(&bucket, key).await.unwrap().is_some());
+        bucket.delete_object(key).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible store, see S3_TEST_BUCKET"]
+    async fn missing_key() {
+        let bucket = test_bucket();
+        let key = "bucket-test/missing.bin";
+        assert!(!exists(&bucket, key).await.unwrap());
+        // An input's fingerprint can't do without its ETag
+        assert!(etag(&bucket, key).await.is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an S3-compatible store, see S3_TEST_BUCKET"]
+    async fn upload_to_missing_bucket_fails() {
+        let bucket = open("pmtiles-raster-tool-missing-bucket").unwrap();
+        let local = tempfile::NamedTempFile::new().unwrap();
+        assert!(upload(local.path(), &bucket, "upload.bin").await.is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Error, anyhow};
+use tokio::sync::Notify;
+
+/// A byte count given as a plain number or with a K, M, G or T suffix (powers of 1024).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let upper = s.trim().to_ascii_uppercase();
+        let digits = upper.trim_end_matches(['B', 'I']);
+        let (number, shift) = match digits.chars().last() {
+            Some('K') => (&digits[..digits.len() - 1], 10),
+            Some('M') => (&digits[..digits.len() - 1], 20),
+            Some('G') => (&digits[..digits.len() - 1], 30),
+            Some('T') => (&digits[..digits.len() - 1], 40),
+            _ => (digits, 0),
+        };
+        let number: f64 = number
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid size: {s}. expected e.g. 512M, 4G or 1073741824"))?;
+        Ok(Self((number * (1u64 << shift) as f64) as u64))
+    }
+}
+
+struct State {
+    used: u64,
+    /// The writer's next index
+    next: usize,
+}
+
+/// Bounds the tile data held between the reader and the writer, most of which sits in the
+/// writer's reorder buffer while it waits for a slow tile.
+///
+/// The reader reserves each tile's size before sending it on, and the reservation is
+/// released once the tile is written. The tile the writer is waiting for is always let
+/// through, even over budget, so a full budget can't stall the pipeline.
+pub struct MemoryBudget {
+    limit: u64,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            state: Mutex::new(State { used: 0, next: 0 }),
+            notify: Notify::new(),
+        })
+    }
+
+    /// Wait until `bytes` fit in the budget, or until `index` is the tile the writer needs.
+    pub async fn reserve(self: &Arc<Self>, index: usize, bytes: u64) -> Reservation {
+        loop {
+            // Registered before checking, so a release in between isn't missed
+            let released = self.notify.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if index <= state.next || state.used + bytes <= self.limit {
+                    state.used += bytes;
+                    return Reservation {
+                        budget: self.clone(),
+                        bytes,
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Called by the writer when `next` is the index it waits for.
+    pub fn advance(&self, next: usize) {
+        self.state.lock().unwrap().next = next;
+        self.notify.notify_waiters();
+    }
+}
+
+/// Bytes held in a [`MemoryBudget`] until dropped.
+pub struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Account for the tile's data changing size, e.g. after transforming it. Never waits.
+    pub fn resize(&mut self, bytes: u64) {
+        let mut state = self.budget.state.lock().unwrap();
+        state.used = state.used - self.bytes + bytes;
+        drop(state);
+        if bytes < self.bytes {
+            self.budget.notify.notify_waiters();
+        }
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.state.lock().unwrap().used -= self.bytes;
+        self.budget.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn used(budget: &MemoryBudget) -> u64 {
+        budget.state.lock().unwrap().used
+    }
+
+    /// Whether `reserve` gets through within a moment.
+    async fn reserves(budget: &Arc<MemoryBudget>, index: usize, bytes: u64) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), budget.reserve(index, bytes))
+            .await
+            .map(std::mem::forget)
+            .is_ok()
+    }
+
+    #[test]
+    fn parses_sizes() {
+        let parse = |s: &str| s.parse::<ByteSize>().unwrap().0;
+        assert_eq!(parse("1073741824"), 1 << 30);
+        assert_eq!(parse("512M"), 512 << 20);
+        assert_eq!(parse("4g"), 4 << 30);
+        assert_eq!(parse("2GiB"), 2 << 30);
+        assert_eq!(parse("2 GB"), 2 << 30);
+        assert_eq!(parse("1.5K"), 1536);
+        assert_eq!(parse("1T"), 1 << 40);
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for s in ["", "M", "abc", "12X", "1.2.3G"] {
+            assert!(s.parse::<ByteSize>().is_err(), "{s} was accepted");
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_a_reservation_releases_it() {
+        let budget = MemoryBudget::new(100);
+        budget.advance(0);
+        let first = budget.reserve(1, 60).await;
+        assert_eq!(used(&budget), 60);
+
+        let waiting = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.reserve(2, 60).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        let second = waiting.await.unwrap();
+        assert_eq!(used(&budget), 60);
+        drop(second);
+        assert_eq!(used(&budget), 0);
+    }
+
+    #[tokio::test]
+    async fn lets_the_next_index_through_over_budget() {
+        let budget = MemoryBudget::new(100);
+        budget.advance(5);
+        assert!(reserves(&budget, 7, 100).await);
+        assert!(!reserves(&budget, 8, 1).await);
+        // The writer is waiting for these
+        assert!(reserves(&budget, 5, 1000).await);
+        budget.advance(8);
+        assert!(reserves(&budget, 8, 1).await);
+    }
+
+    #[tokio::test]
+    async fn shrinking_a_reservation_wakes_waiters() {
+        let budget = MemoryBudget::new(100);
+        budget.advance(0);
+        let mut first = budget.reserve(1, 100).await;
+        let waiting = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.reserve(2, 50).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+
+        first.resize(40);
+        let _second = waiting.await.unwrap();
+        assert_eq!(used(&budget), 90);
+    }
+}
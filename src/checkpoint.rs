@@ -0,0 +1,296 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, bail};
+use pmtiles::TileCoord;
+use serde_json::{Value, json};
+
+use crate::stats::{Counters, TileStats};
+
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+const JOURNAL_FILE: &str = "tiles.journal";
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+/// Version of the checkpoint layout, part of the fingerprint so that a checkpoint left by an
+/// older layout isn't resumed.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Lets an interrupted run pick up where it left off.
+///
+/// The PMTiles writer can't be reopened once the process dies, so every tile written to the
+/// output is also appended to a journal. A checkpoint periodically records how many tiles (by
+/// reorder index) have been written and how much of the journal covers them, along with the
+/// counters of those tiles. Resuming replays the journal into a fresh output and continues
+/// reading from the next index.
+///
+/// The journal layout per tile: z (u8), x (u32 LE), y (u32 LE), length (u32 LE), data.
+pub struct Checkpoint {
+    dir: PathBuf,
+    /// Identifies the inputs and options; a checkpoint is only resumed with the same one.
+    fingerprint: String,
+    journal: BufWriter<File>,
+    journal_len: u64,
+    /// Index to resume from, and the journal length at that point
+    resume: Option<(usize, u64)>,
+    /// Counts of the tiles written so far, including those of the run being resumed
+    counters: Counters,
+    last_saved: Instant,
+}
+
+impl Checkpoint {
+    /// Open the checkpoint state in `dir`, creating it if there is none.
+    pub fn open(dir: PathBuf, fingerprint: String) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create checkpoint directory {}", dir.display()))?;
+
+        let checkpoint_path = dir.join(CHECKPOINT_FILE);
+        let mut counters = Counters::default();
+        let resume = match fs::read_to_string(&checkpoint_path) {
+            Ok(s) => {
+                let saved: Value = serde_json::from_str(&s).with_context(|| {
+                    format!("invalid checkpoint: {}", checkpoint_path.display())
+                })?;
+                if saved["fingerprint"].as_str() != Some(fingerprint.as_str()) {
+                    bail!(
+                        "The inputs or options have changed since the checkpoint in {} was written. Remove it to start over.",
+                        dir.display()
+                    );
+                }
+                let (Some(next), Some(journal_len)) =
+                    (saved["next"].as_u64(), saved["journal_len"].as_u64())
+                else {
+                    bail!("invalid checkpoint: {}", checkpoint_path.display());
+                };
+                counters = Counters::from_json(&saved["counters"]).with_context(|| {
+                    format!("invalid checkpoint: {}", checkpoint_path.display())
+                })?;
+                Some((next as usize, journal_len))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read {}", checkpoint_path.display()));
+            }
+        };
+
+        // Drop whatever was journaled after the last checkpoint
+        let journal_len = resume.map_or(0, |(_, len)| len);
+        let mut journal = File::options()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(dir.join(JOURNAL_FILE))
+            .context("Failed to open checkpoint journal")?;
+        if journal.metadata()?.len() < journal_len {
+            bail!(
+                "checkpoint journal in {} is shorter than recorded. Remove it to start over.",
+                dir.display()
+            );
+        }
+        journal.set_len(journal_len)?;
+        journal.seek(SeekFrom::End(0))?;
+
+        Ok(Self {
+            dir,
+            fingerprint,
+            journal: BufWriter::new(journal),
+            journal_len,
+            resume,
+            counters,
+            last_saved: Instant::now(),
+        })
+    }
+
+    /// True if there was a checkpoint to resume from.
+    pub fn is_resuming(&self) -> bool {
+        self.resume.is_some()
+    }
+
+    /// The first reorder index that still has to be processed.
+    pub fn resume_from(&self) -> usize {
+        self.resume.map_or(0, |(next, _)| next)
+    }
+
+    /// The counts of the tiles written up to the checkpoint being resumed, or nothing.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Feed every journaled tile up to the checkpoint to `f`.
+    pub fn replay(&self, mut f: impl FnMut(TileCoord, &[u8]) -> Result<()>) -> Result<()> {
+        let Some((_, journal_len)) = self.resume else {
+            return Ok(());
+        };
+        let file = File::open(self.dir.join(JOURNAL_FILE))?;
+        let mut journal = BufReader::new(file.take(journal_len));
+        let mut head = [0u8; 13];
+        let mut data = Vec::new();
+        loop {
+            match journal.read_exact(&mut head) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e).context("read checkpoint journal"),
+            }
+            let z = head[0];
+            let x = u32::from_le_bytes(head[1..5].try_into().unwrap());
+            let y = u32::from_le_bytes(head[5..9].try_into().unwrap());
+            let len = u32::from_le_bytes(head[9..13].try_into().unwrap());
+            data.resize(len as usize, 0);
+            journal
+                .read_exact(&mut data)
+                .context("checkpoint journal is truncated")?;
+            f(TileCoord::new(z, x, y)?, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Journal a tile that was written to the output.
+    pub fn record(&mut self, coord: TileCoord, data: &[u8]) -> Result<()> {
+        self.journal.write_all(&[coord.z()])?;
+        self.journal.write_all(&coord.x().to_le_bytes())?;
+        self.journal.write_all(&coord.y().to_le_bytes())?;
+        self.journal.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.journal.write_all(data)?;
+        self.journal_len += 13 + data.len() as u64;
+        Ok(())
+    }
+
+    /// Add a tile at zoom `z` to the counters. Called as it is written, before [`Self::tick`].
+    pub fn count(&mut self, z: u8, stats: &TileStats) {
+        self.counters.record(z, stats);
+    }
+
+    /// Called after each index is written; saves a checkpoint now and then. `next` is the
+    /// first index not yet written.
+    pub fn tick(&mut self, next: usize) -> Result<()> {
+        if self.last_saved.elapsed() >= SAVE_INTERVAL {
+            self.save(next)?;
+        }
+        Ok(())
+    }
+
+    /// Save a checkpoint now. `next` is the first index not yet written.
+    pub fn save(&mut self, next: usize) -> Result<()> {
+        self.journal.flush()?;
+        self.journal.get_ref().sync_data()?;
+        let checkpoint = json!({
+            "fingerprint": self.fingerprint,
+            "next": next,
+            "journal_len": self.journal_len,
+            "counters": self.counters.to_json(),
+        });
+        // Write then rename, so a crash never leaves a half-written checkpoint
+        let tmp = self.dir.join(format!("{CHECKPOINT_FILE}.tmp"));
+        fs::write(&tmp, checkpoint.to_string())?;
+        fs::rename(&tmp, self.dir.join(CHECKPOINT_FILE))?;
+        self.last_saved = Instant::now();
+        Ok(())
+    }
+
+    /// Delete the checkpoint state once the output is complete.
+    pub fn remove(self) -> Result<()> {
+        drop(self.journal);
+        fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("Failed to remove {}", self.dir.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::TileOutcome;
+
+    fn coord(z: u8, x: u32, y: u32) -> TileCoord {
+        TileCoord::new(z, x, y).unwrap()
+    }
+
+    fn replayed(checkpoint: &Checkpoint) -> Vec<(TileCoord, Vec<u8>)> {
+        let mut tiles = Vec::new();
+        checkpoint
+            .replay(|coord, data| {
+                tiles.push((coord, data.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        tiles
+    }
+
+    const SKIPPED: TileStats = TileStats {
+        outcome: TileOutcome::Skipped,
+        bytes_in: 10,
+        bytes_out: None,
+        reused: false,
+        elevation: None,
+    };
+
+    #[test]
+    fn fresh_checkpoint_has_nothing_to_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let checkpoint = Checkpoint::open(dir.path().join("out.resume"), "a".into()).unwrap();
+        assert!(!checkpoint.is_resuming());
+        assert_eq!(checkpoint.resume_from(), 0);
+        assert!(replayed(&checkpoint).is_empty());
+    }
+
+    #[test]
+    fn replays_up_to_the_last_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.resume");
+        let mut checkpoint = Checkpoint::open(path.clone(), "a".into()).unwrap();
+        checkpoint.record(coord(1, 0, 1), b"first").unwrap();
+        checkpoint.record(coord(2, 3, 2), b"").unwrap();
+        checkpoint.count(2, &SKIPPED);
+        checkpoint.save(3).unwrap();
+        // Written after the last save, so dropped on resume
+        checkpoint.record(coord(2, 3, 3), b"lost").unwrap();
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::open(path.clone(), "a".into()).unwrap();
+        assert!(checkpoint.is_resuming());
+        assert_eq!(checkpoint.resume_from(), 3);
+        assert_eq!(checkpoint.counters().failed(), 1);
+        assert_eq!(
+            replayed(&checkpoint),
+            vec![
+                (coord(1, 0, 1), b"first".to_vec()),
+                (coord(2, 3, 2), Vec::new())
+            ]
+        );
+        let journal_len = fs::metadata(path.join(JOURNAL_FILE)).unwrap().len();
+        assert_eq!(journal_len, 2 * 13 + 5);
+        checkpoint.remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refuses_a_different_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.resume");
+        Checkpoint::open(path.clone(), "a".into())
+            .unwrap()
+            .save(0)
+            .unwrap();
+        assert!(Checkpoint::open(path, "b".into()).is_err());
+    }
+
+    #[test]
+    fn refuses_a_truncated_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.resume");
+        let mut checkpoint = Checkpoint::open(path.clone(), "a".into()).unwrap();
+        checkpoint.record(coord(0, 0, 0), b"tile").unwrap();
+        checkpoint.save(1).unwrap();
+        drop(checkpoint);
+
+        let journal = File::options()
+            .write(true)
+            .open(path.join(JOURNAL_FILE))
+            .unwrap();
+        journal.set_len(5).unwrap();
+        assert!(Checkpoint::open(path, "a".into()).is_err());
+    }
+}
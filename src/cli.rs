@@ -1,29 +1,255 @@
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::{path::PathBuf, str::FromStr};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Result, bail};
+use clap::{Args, Parser, Subcommand};
 
-use crate::transform::Transform;
+use pmtiles_raster_tool::{
+    ConflictPolicy, ConvertOptions, ErrorPolicy, Location, ProgressMode, PruneOptions, Transform,
+    commands::{Bbox, ByteSize, Crs, TileCodec},
+};
 
 /// CLI definition matching README usage:
-/// pmtiles-raster-tool in.pmtiles transform out.pmtiles
+/// pmtiles-raster-tool in.pmtiles [in2.pmtiles ...] transform out.pmtiles
+/// pmtiles-raster-tool <COMMAND> ...
 #[derive(Debug, Parser)]
 #[command(name = "pmtiles-raster-tool")]
 #[command(about = "A tool to transform raster tiles", version)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
-    /// Input PMTiles file path
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// One or more input PMTiles paths (or s3://bucket/key), the transform to apply, and the
+    /// output PMTiles path (or s3://bucket/key)
+    #[arg(value_name = "INPUT... TRANSFORM OUTPUT", num_args = 3.., required = true)]
+    pub args: Vec<String>,
+
+    #[arg(long, short, help = "Overwrite output if it already exists")]
+    pub force: bool,
+
+    /// How to resolve tiles present in more than one input
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::First)]
+    pub on_conflict: ConflictPolicy,
+
+    /// Archive used to fill no-data pixels of the inputs' tiles, from the nearest lower zoom
+    /// level if it lacks the tile itself. May be given more than once; earlier fallbacks take
+    /// priority. The inputs and fallbacks must be PNG archives.
+    #[arg(long, value_name = "PATH")]
+    pub fallback: Vec<Location>,
+
+    /// What to do with a tile that fails to transform
+    #[arg(long, value_enum, default_value_t = ErrorPolicy::Abort)]
+    pub on_error: ErrorPolicy,
+
+    /// JSON-lines file listing the tiles that failed with --on-error skip or passthrough.
+    /// Defaults to the output name with `.errors.jsonl` appended.
+    #[arg(long, value_name = "PATH")]
+    pub error_report: Option<PathBuf>,
+
+    /// Save checkpoints while running, and continue from the last one if a previous run with
+    /// --resume was interrupted
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Roughly how much tile data to hold in memory between reading and writing, e.g. 512M
+    /// or 4G. Reading is paused while the writer waits for a slow tile.
+    #[arg(long, value_name = "SIZE", default_value = "1G")]
+    pub max_memory: ByteSize,
+
+    /// Leave out transformed tiles without any data: fully transparent or no-data rasters,
+    /// and vector tiles without features
+    #[arg(long)]
+    pub drop_empty: bool,
+
+    /// Leave out transformed raster tiles that are a single flat value, e.g. sea level
+    #[arg(long)]
+    pub drop_constant: bool,
+
+    /// Write statistics about the run to this JSON file when it finishes: tile counts and
+    /// bytes per zoom level, time per stage, failures and, for DEM outputs, elevations
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub global: GlobalArgs,
+}
+
+/// Options that apply to every command.
+#[derive(Clone, Debug, Args)]
+pub struct GlobalArgs {
+    /// How to show progress: bars on a terminal, periodic plain or JSON lines for logs, or
+    /// nothing but log messages. auto picks bar or plain depending on whether stderr is a
+    /// terminal.
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto, global = true)]
+    pub progress: ProgressMode,
+
+    /// Log more: -v for debug, -vv for trace. RUST_LOG overrides this, e.g.
+    /// RUST_LOG=pmtiles_raster_tool::writer=trace
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Write a Chrome trace of the run's spans to this file, to open in Perfetto or
+    /// chrome://tracing
+    #[arg(long, value_name = "PATH", global = true)]
+    pub trace: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Cut a GeoTIFF into Web Mercator tiles and write them to a PMTiles archive
+    IngestGeotiff(IngestGeotiffArgs),
+    /// Mosaic the tiles covering a bounding box into a single EPSG:3857 GeoTIFF
+    ExportGeotiff(ExportGeotiffArgs),
+    /// Combine 2x2 tiles into one tile of twice the size at the zoom level above, or split
+    /// each tile into its four children
+    Retile(RetileArgs),
+    /// Print an archive's header, metadata, tile counts and sizes per zoom level, and the
+    /// formats of a sample of its tiles
+    Info(InfoArgs),
+    /// Check that an archive's header matches its contents and that every tile decodes
+    Verify(VerifyArgs),
+    /// Compare two archives tile by tile
+    Diff(DiffArgs),
+    /// List the transforms that can be named, with what each does
+    Transforms,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// The archive to compare against: PMTiles file path or s3://bucket/key
+    #[arg(value_name = "A")]
+    pub a: Location,
+
+    /// The changed archive: PMTiles file path or s3://bucket/key
+    #[arg(value_name = "B")]
+    pub b: Location,
+
+    /// How to decode the tiles: rgb to compare color channels, or gsidem, terrainrgb,
+    /// terrarium or float32 to compare elevations in meters. Detected from A's metadata when
+    /// not given.
+    #[arg(long)]
+    pub encoding: Option<TileCodec>,
+
+    /// Write a PMTiles archive visualizing the changed pixels: red where B is higher, blue
+    /// where it is lower
+    #[arg(long, value_name = "PATH")]
+    pub output: Option<Location>,
+
+    #[arg(long, short, help = "Overwrite output if it already exists")]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Input PMTiles file path or s3://bucket/key
     #[arg(value_name = "INPUT")]
-    pub input: PathBuf,
+    pub input: Location,
 
-    /// Transform to apply
-    #[arg(value_name = "TRANSFORM")]
-    pub transform: Transform,
+    /// How to decode the tiles: rgb for imagery, or gsidem, terrainrgb, terrarium or float32
+    /// to also check elevations. Detected from the metadata when not given.
+    #[arg(long)]
+    pub encoding: Option<TileCodec>,
+
+    /// JSON-lines file listing the invalid tiles. Defaults to the input name with
+    /// `.verify.jsonl` appended.
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct InfoArgs {
+    /// Input PMTiles file path or s3://bucket/key
+    #[arg(value_name = "INPUT")]
+    pub input: Location,
+
+    /// Number of tiles to read to find out their formats
+    #[arg(long, default_value_t = 20)]
+    pub sample: usize,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct RetileArgs {
+    /// Input PMTiles file path or s3://bucket/key
+    #[arg(value_name = "INPUT")]
+    pub input: Location,
 
-    /// Output PMTiles file path
+    /// Output PMTiles file path or s3://bucket/key
+    #[arg(value_name = "OUTPUT")]
+    pub output: Location,
+
+    /// Output tile size in pixels: 512 to merge 256px tiles, or 256 to split 512px tiles
+    #[arg(long, default_value_t = 512)]
+    pub tile_size: u32,
+
+    /// Tile encoding: rgb (default) for imagery, or gsidem, terrainrgb, terrarium or float32
+    /// for elevation
+    #[arg(long, default_value = "rgb")]
+    pub encoding: TileCodec,
+
+    #[arg(long, short, help = "Overwrite output if it already exists")]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportGeotiffArgs {
+    /// Input PMTiles file path or s3://bucket/key
+    #[arg(value_name = "INPUT")]
+    pub input: Location,
+
+    /// Output GeoTIFF path
     #[arg(value_name = "OUTPUT")]
     pub output: PathBuf,
 
+    /// Area to export: west,south,east,north in degrees
+    #[arg(long, allow_hyphen_values = true)]
+    pub bbox: Bbox,
+
+    /// Zoom level of the tiles to mosaic
+    #[arg(long)]
+    pub zoom: u8,
+
+    /// How to decode the tiles: rgb (default) to export imagery as RGBA, or gsidem,
+    /// terrainrgb, terrarium or float32 to export float32 elevations
+    #[arg(long, default_value = "rgb")]
+    pub encoding: TileCodec,
+
+    #[arg(long, short, help = "Overwrite output if it already exists")]
+    pub force: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct IngestGeotiffArgs {
+    /// Input GeoTIFF (or Cloud-Optimized GeoTIFF) path
+    #[arg(value_name = "INPUT")]
+    pub input: PathBuf,
+
+    /// Output PMTiles file path or s3://bucket/key
+    #[arg(value_name = "OUTPUT")]
+    pub output: Location,
+
+    /// Tile encoding: gsidem, terrainrgb, terrarium or float32 for elevation, or rgb for imagery
+    #[arg(long, default_value = "terrainrgb")]
+    pub encoding: TileCodec,
+
+    #[arg(long, default_value_t = 0)]
+    pub min_zoom: u8,
+
+    #[arg(long)]
+    pub max_zoom: u8,
+
+    /// Tile size in pixels: 256 or 512
+    #[arg(long, default_value_t = 256)]
+    pub tile_size: u32,
+
+    /// CRS of the input, overriding the one declared in the file: EPSG:3857, EPSG:4326,
+    /// EPSG:6668 (JGD2011) or EPSG:4612 (JGD2000)
+    #[arg(long)]
+    pub crs: Option<Crs>,
+
     #[arg(long, short, help = "Overwrite output if it already exists")]
     pub force: bool,
 }
@@ -31,28 +257,77 @@ pub struct Cli {
 /// Resolved, strongly-typed arguments
 #[derive(Debug)]
 pub struct ResolvedCli {
-    pub input: PathBuf,
+    pub inputs: Vec<Location>,
     pub transform: Transform,
-    pub output: PathBuf,
-    pub force: bool,
+    pub output: Location,
+    pub options: ConvertOptions,
+}
+
+/// What to run
+#[derive(Debug)]
+pub enum Action {
+    /// Transform tiles from one or more archives into a new archive
+    Convert(ResolvedCli),
+    IngestGeotiff(IngestGeotiffArgs),
+    ExportGeotiff(ExportGeotiffArgs),
+    Retile(RetileArgs),
+    Info(InfoArgs),
+    Verify(VerifyArgs),
+    Diff(DiffArgs),
+    Transforms,
 }
 
 impl Cli {
-    /// Parse args and resolve optional transform vs output positionally.
-    pub fn parse_resolved() -> Result<ResolvedCli> {
+    /// Parse args and resolve inputs, transform and output positionally.
+    pub fn parse_resolved() -> Result<(Action, GlobalArgs)> {
         let cli = Self::parse();
-        // Transform is now a required positional argument
-        // Validate transform via FromStr (clap already invokes it)
-        // But ensure we fail early if somehow empty
-        Transform::from_str(match cli.transform {
-            Transform::GsiDemPngToTerrainRgbPng(_) => "gsidempng-to-terrainrgbpng",
-        })?;
-
-        Ok(ResolvedCli {
-            input: cli.input,
-            transform: cli.transform,
-            output: cli.output,
-            force: cli.force,
-        })
+        if let Some(command) = cli.command {
+            let action = match command {
+                Command::IngestGeotiff(args) => Action::IngestGeotiff(args),
+                Command::ExportGeotiff(args) => Action::ExportGeotiff(args),
+                Command::Retile(args) => Action::Retile(args),
+                Command::Info(args) => Action::Info(args),
+                Command::Verify(args) => Action::Verify(args),
+                Command::Diff(args) => Action::Diff(args),
+                Command::Transforms => Action::Transforms,
+            };
+            return Ok((action, cli.global));
+        }
+
+        let [inputs @ .., transform, output] = cli.args.as_slice() else {
+            bail!("expected INPUT... TRANSFORM OUTPUT");
+        };
+        if inputs.is_empty() {
+            bail!("at least one INPUT is required");
+        }
+
+        let mut options = ConvertOptions::default()
+            .with_fallbacks(cli.fallback)
+            .with_on_conflict(cli.on_conflict)
+            .with_on_error(cli.on_error)
+            .with_force(cli.force)
+            .with_resume(cli.resume)
+            .with_max_memory(cli.max_memory.0)
+            .with_prune(PruneOptions {
+                empty: cli.drop_empty,
+                constant: cli.drop_constant,
+            })
+            .with_progress(cli.global.progress);
+        if let Some(path) = cli.error_report {
+            options = options.with_error_report(path);
+        }
+        if let Some(path) = cli.report {
+            options = options.with_report(path);
+        }
+        let action = Action::Convert(ResolvedCli {
+            inputs: inputs
+                .iter()
+                .map(|s| Location::from_str(s))
+                .collect::<Result<_>>()?,
+            transform: Transform::from_str(transform)?,
+            output: Location::from_str(output)?,
+            options,
+        });
+        Ok((action, cli.global))
     }
 }
@@ -0,0 +1,187 @@
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::raster::{Raster, is_nodata};
+
+/// The data of a tile used for filling, decoded once however many tiles it fills.
+pub struct FillSource {
+    pub data: Bytes,
+    raster: OnceLock<Raster>,
+}
+
+impl FillSource {
+    pub fn new(data: Bytes) -> Arc<Self> {
+        Arc::new(Self {
+            data,
+            raster: OnceLock::new(),
+        })
+    }
+
+    fn raster(&self) -> Result<&Raster> {
+        if let Some(raster) = self.raster.get() {
+            return Ok(raster);
+        }
+        let raster = Raster::decode_png(&self.data)?;
+        Ok(self.raster.get_or_init(|| raster))
+    }
+}
+
+/// A tile used to fill no-data pixels of another tile.
+///
+/// It may come from a lower zoom level than the tile being filled, in which case the quadrant
+/// covering that tile is upsampled (nearest neighbour, so packed DEM encodings stay valid).
+pub struct FillTile {
+    pub source: Arc<FillSource>,
+    /// How many zoom levels above the target tile `data` is
+    pub dz: u8,
+    /// Position of the target tile within `data`, in tiles at the target zoom
+    pub dx: u32,
+    pub dy: u32,
+}
+
+impl FillTile {
+    /// A fill tile at the same coordinate as the target.
+    pub fn same_zoom(data: Bytes) -> Self {
+        Self {
+            source: FillSource::new(data),
+            dz: 0,
+            dx: 0,
+            dy: 0,
+        }
+    }
+}
+
+/// Whether `base` has pixels to fill. Fallbacks are only allowed for PNG archives, so a tile
+/// that isn't PNG is mislabeled; it is reported as needing it so that [`fill_nodata`] says so
+/// when a fill tile turns up.
+pub fn has_nodata(base: &[u8], gsi: bool) -> Result<bool> {
+    if !base.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Ok(true);
+    }
+    let raster = Raster::decode_png(base)?;
+    Ok(raster.data.chunks_exact(4).any(|px| is_nodata(px, gsi)))
+}
+
+/// Fill the no-data pixels of `base` with the first valid pixel found in `fill`, in order.
+/// `gsi` tells whether the tiles are GSI DEM, whose no-data sentinel counts as no-data too.
+///
+/// `base` is returned as-is when it has no no-data pixels, so tiles that don't need
+/// compositing are not re-encoded.
+pub fn fill_nodata(base: &Bytes, fill: &[FillTile], gsi: bool) -> Result<Bytes> {
+    if fill.is_empty() {
+        return Ok(base.clone());
+    }
+    let mut raster = Raster::decode_png(base)?;
+    if !raster.data.chunks_exact(4).any(|px| is_nodata(px, gsi)) {
+        return Ok(base.clone());
+    }
+
+    let (w, h) = (raster.width as u64, raster.height as u64);
+    for f in fill {
+        let src = f.source.raster()?;
+        // Size of the (virtual) upsampled source, in target pixels
+        let (full_w, full_h) = (w << f.dz, h << f.dz);
+        let (off_x, off_y) = (f.dx as u64 * w, f.dy as u64 * h);
+
+        let mut remaining = false;
+        for y in 0..raster.height {
+            let sy = ((off_y + y as u64) * src.height as u64 / full_h) as u32;
+            for x in 0..raster.width {
+                if !is_nodata(raster.pixel(x, y), gsi) {
+                    continue;
+                }
+                let sx = ((off_x + x as u64) * src.width as u64 / full_w) as u32;
+                let s = src.pixel(sx, sy);
+                if is_nodata(s, gsi) {
+                    remaining = true;
+                } else {
+                    raster.pixel_mut(x, y).copy_from_slice(s);
+                }
+            }
+        }
+        if !remaining {
+            break;
+        }
+    }
+    raster.encode_png()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+    const CLEAR: [u8; 4] = [0, 0, 0, 0];
+    const GSI_NODATA: [u8; 4] = [0x80, 0, 0, 255];
+
+    fn png(width: u32, pixels: &[[u8; 4]]) -> Bytes {
+        Raster {
+            width,
+            height: pixels.len() as u32 / width,
+            data: pixels.concat(),
+        }
+        .encode_png()
+        .unwrap()
+    }
+
+    fn pixels(data: &[u8]) -> Vec<[u8; 4]> {
+        let raster = Raster::decode_png(data).unwrap();
+        raster
+            .data
+            .chunks_exact(4)
+            .map(|px| px.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn finds_nodata() {
+        assert!(!has_nodata(&png(2, &[RED; 4]), false).unwrap());
+        assert!(has_nodata(&png(2, &[RED, RED, CLEAR, RED]), false).unwrap());
+        let gsi = png(2, &[RED, GSI_NODATA, RED, RED]);
+        assert!(has_nodata(&gsi, true).unwrap());
+        assert!(!has_nodata(&gsi, false).unwrap());
+    }
+
+    #[test]
+    fn fills_from_the_same_zoom() {
+        let base = png(2, &[RED, CLEAR, RED, GSI_NODATA]);
+        let fill = [FillTile::same_zoom(png(2, &[BLUE; 4]))];
+        let filled = fill_nodata(&base, &fill, true).unwrap();
+        assert_eq!(pixels(&filled), [RED, BLUE, RED, BLUE]);
+    }
+
+    #[test]
+    fn leaves_complete_tiles_alone() {
+        let base = png(2, &[RED; 4]);
+        let fill = [FillTile::same_zoom(png(2, &[BLUE; 4]))];
+        assert_eq!(fill_nodata(&base, &fill, false).unwrap(), base);
+    }
+
+    #[test]
+    fn upsamples_the_ancestor_quadrant() {
+        let base = png(2, &[CLEAR; 4]);
+        // The target is the top-right quadrant of its parent
+        let fill = [FillTile {
+            source: FillSource::new(png(2, &[RED, BLUE, RED, RED])),
+            dz: 1,
+            dx: 1,
+            dy: 0,
+        }];
+        let filled = fill_nodata(&base, &fill, false).unwrap();
+        assert_eq!(pixels(&filled), [BLUE; 4]);
+    }
+
+    #[test]
+    fn later_fills_cover_what_earlier_ones_lack() {
+        let base = png(2, &[CLEAR; 4]);
+        let fill = [
+            FillTile::same_zoom(png(2, &[RED, CLEAR, CLEAR, RED])),
+            FillTile::same_zoom(png(2, &[BLUE; 4])),
+        ];
+        let filled = fill_nodata(&base, &fill, false).unwrap();
+        assert_eq!(pixels(&filled), [RED, BLUE, BLUE, RED]);
+    }
+}
@@ -0,0 +1,233 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use tokio::task::JoinSet;
+
+use crate::{
+    QUEUE_CAPACITY,
+    budget::MemoryBudget,
+    checkpoint::{self, Checkpoint},
+    dem::DemEncoding,
+    error_report::ErrorReport,
+    location::Location,
+    progress::{Progress, ProgressMode, ProgressMsg, ProgressSender},
+    prune::PruneOptions,
+    reader::{ConflictPolicy, ReadMsg, Reader},
+    transform::{Transform, TransformProcess},
+    transformer::{ErrorPolicy, Transformer},
+    writer::{self, WriteMsg, Writer},
+};
+
+/// How much tile data [`convert`] holds in memory by default: 1 GiB.
+pub const DEFAULT_MAX_MEMORY: u64 = 1 << 30;
+
+/// Options for [`convert`], built up from [`ConvertOptions::default`]:
+///
+/// ```no_run
+/// # use pmtiles_raster_tool::{ConvertOptions, ErrorPolicy};
+/// let options = ConvertOptions::default()
+///     .with_on_error(ErrorPolicy::Skip)
+///     .with_max_memory(512 << 20)
+///     .with_force(true);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    fallbacks: Vec<Location>,
+    on_conflict: ConflictPolicy,
+    on_error: ErrorPolicy,
+    error_report: Option<PathBuf>,
+    force: bool,
+    resume: bool,
+    max_memory: u64,
+    prune: PruneOptions,
+    report: Option<PathBuf>,
+    progress: ProgressMode,
+    progress_tx: Option<ProgressSender>,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> Self {
+        Self {
+            fallbacks: Vec::new(),
+            on_conflict: ConflictPolicy::default(),
+            on_error: ErrorPolicy::default(),
+            error_report: None,
+            force: false,
+            resume: false,
+            max_memory: DEFAULT_MAX_MEMORY,
+            prune: PruneOptions::default(),
+            report: None,
+            // A library caller has its own way of showing progress
+            progress: ProgressMode::None,
+            progress_tx: None,
+        }
+    }
+}
+
+impl ConvertOptions {
+    /// Archives to fill the inputs' no-data pixels from, in order of preference.
+    pub fn with_fallbacks(mut self, fallbacks: Vec<Location>) -> Self {
+        self.fallbacks = fallbacks;
+        self
+    }
+
+    /// Which input wins when several have the same tile.
+    pub fn with_on_conflict(mut self, on_conflict: ConflictPolicy) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// What to do with tiles that fail to transform.
+    pub fn with_on_error(mut self, on_error: ErrorPolicy) -> Self {
+        self.on_error = on_error;
+        self
+    }
+
+    /// Where to record failed tiles, instead of `<output>.errors.jsonl`.
+    pub fn with_error_report(mut self, path: PathBuf) -> Self {
+        self.error_report = Some(path);
+        self
+    }
+
+    /// Overwrite the output if it already exists.
+    pub fn with_force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Keep a checkpoint next to the output and pick up from it if a previous run with the
+    /// same inputs and options was interrupted.
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Roughly how many bytes of tile data to hold between reading and writing.
+    pub fn with_max_memory(mut self, bytes: u64) -> Self {
+        self.max_memory = bytes;
+        self
+    }
+
+    /// Leave out tiles that are empty or a single value after transforming.
+    pub fn with_prune(mut self, prune: PruneOptions) -> Self {
+        self.prune = prune;
+        self
+    }
+
+    /// Write statistics about the run to `path` as JSON when it finishes.
+    pub fn with_report(mut self, path: PathBuf) -> Self {
+        self.report = Some(path);
+        self
+    }
+
+    /// How to show progress on stderr. Defaults to [`ProgressMode::None`], which still
+    /// prints log messages.
+    pub fn with_progress(mut self, progress: ProgressMode) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Send progress and log messages to `tx` instead of printing anything. Takes precedence
+    /// over [`Self::with_progress`].
+    pub fn with_progress_sender(mut self, tx: ProgressSender) -> Self {
+        self.progress_tx = Some(tx);
+        self
+    }
+}
+
+/// Read `inputs`, apply `transform` to every tile and write the result to `output`: the
+/// whole pipeline the command line runs, with a [`Reader`], a [`Transformer`] and a
+/// [`Writer`] connected by bounded channels.
+pub async fn convert(
+    inputs: Vec<Location>,
+    transform: Transform,
+    output: Location,
+    options: ConvertOptions,
+) -> Result<()> {
+    let (reader_tx, reader_rx) = flume::bounded::<ReadMsg>(QUEUE_CAPACITY);
+    let (writer_tx, writer_rx) = flume::bounded::<WriteMsg>(QUEUE_CAPACITY);
+    let (progress_tx, progress_rx) = flume::unbounded::<ProgressMsg>();
+
+    let mut js = JoinSet::new();
+    let reader = Reader::new(
+        inputs,
+        options.fallbacks,
+        options.on_conflict,
+        transform.needs_neighbors(),
+    )
+    .await?
+    .with_dedupe(!transform.uses_coordinate())
+    .with_gsi_nodata(transform.input_encoding() == Some(DemEncoding::Gsi));
+    let mut header = reader.output_header().await?;
+    transform.update_header(&mut header)?;
+    let checkpoint = if options.resume {
+        let fingerprint = serde_json::json!({
+            "format": checkpoint::FORMAT_VERSION,
+            "version": env!("CARGO_PKG_VERSION"),
+            "inputs": reader.fingerprint().await?,
+            "transform": format!("{:?}", transform),
+            "on_conflict": format!("{:?}", options.on_conflict),
+            "on_error": format!("{:?}", options.on_error),
+            "prune": format!("{:?}", options.prune),
+        });
+        Some(Checkpoint::open(
+            output.sidecar_path(".resume"),
+            fingerprint.to_string(),
+        )?)
+    } else {
+        None
+    };
+    let resuming = checkpoint.as_ref().is_some_and(Checkpoint::is_resuming);
+    let start = checkpoint.as_ref().map_or(0, Checkpoint::resume_from);
+    // Failures, dropped tiles and the report carry on from where the interrupted run stopped
+    let counters = checkpoint
+        .as_ref()
+        .map(|c| c.counters().clone())
+        .unwrap_or_default();
+
+    let report = ErrorReport::new(
+        options
+            .error_report
+            .unwrap_or_else(|| output.sidecar_path(".errors.jsonl")),
+        resuming,
+    )
+    .with_count(counters.failed());
+    // Elevations are only decoded for the run report
+    let elevation = options
+        .report
+        .as_ref()
+        .and_then(|_| transform.output_encoding());
+    let transformer = Transformer::new(transform, options.on_error, report, options.prune)
+        .with_counters(&counters)
+        .with_elevation_stats(elevation)
+        .with_output_type(header.tile_type);
+    // A resumed run rewrites the partial output it left behind
+    let writer = Writer::new(output, options.force || resuming, header, checkpoint).await?;
+    let mut progress = Progress::new(options.progress).resumed_from(start as u64);
+    if let Some(path) = options.report {
+        progress = progress.with_report(path, counters);
+    }
+    if let Some(tx) = options.progress_tx {
+        progress = progress.forward_to(tx);
+    }
+    if resuming {
+        progress_tx.send(ProgressMsg::Log(format!(
+            "Resuming from a checkpoint: {start} tiles already done"
+        )))?;
+    }
+
+    let budget = MemoryBudget::new(options.max_memory);
+    let reader_budget = budget.clone();
+    let reader_progress_tx = progress_tx.clone();
+    js.spawn(async move {
+        reader
+            .run(start, reader_budget, reader_tx, reader_progress_tx)
+            .await
+    });
+    let transformer_progress_tx = progress_tx.clone();
+    js.spawn_blocking(move || transformer.run(reader_rx, writer_tx, transformer_progress_tx));
+    js.spawn_blocking(move || writer.write(writer_rx, Some(budget), progress_tx));
+    js.spawn_blocking(move || progress.run(progress_rx));
+
+    writer::join_stages(js).await
+}
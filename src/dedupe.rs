@@ -0,0 +1,202 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::watch;
+
+/// Identifies the input bytes of a tile: `(input index, offset in its tile data)` for each
+/// input the tile is read from. Tiles in a run, and tiles deduplicated by the archive's
+/// writer, share their offset.
+pub type TileKey = Vec<(usize, u64)>;
+
+/// The result of transforming a tile, shared by every tile with the same [`TileKey`].
+#[derive(Clone)]
+pub struct Cached {
+    /// The input tile, for `--on-error passthrough`
+    pub input: Bytes,
+    /// The transformed tile, or the error chain
+    pub output: Result<Bytes, String>,
+}
+
+/// Filled in once by the tile that owns it; the others wait for it.
+///
+/// The owner is queued for transforming before any tile that reuses its result, so a
+/// transformer thread waiting for a result never holds up the owner.
+pub struct Slot {
+    value: Mutex<Option<Cached>>,
+    ready: Condvar,
+    /// Whether the owner has been sent to the transformer
+    queued: watch::Sender<bool>,
+}
+
+impl Default for Slot {
+    fn default() -> Self {
+        Self {
+            value: Mutex::new(None),
+            ready: Condvar::new(),
+            queued: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Slot {
+    pub fn mark_queued(&self) {
+        self.queued.send_replace(true);
+    }
+
+    pub async fn wait_queued(&self) {
+        // The sender lives in `self`, so this can't fail
+        let _ = self.queued.subscribe().wait_for(|queued| *queued).await;
+    }
+
+    pub fn set(&self, cached: Cached) {
+        *self.value.lock().unwrap() = Some(cached);
+        self.ready.notify_all();
+    }
+
+    pub fn wait(&self) -> Cached {
+        let value = self.value.lock().unwrap();
+        let value = self.ready.wait_while(value, |v| v.is_none()).unwrap();
+        value.clone().unwrap()
+    }
+}
+
+/// A tile's place in the cache: the owner reads and transforms the tile, the others reuse
+/// its result.
+pub struct CacheSlot {
+    pub slot: Arc<Slot>,
+    pub owner: bool,
+}
+
+/// Recently seen input tiles, looked up in index order. Bounded, so tiles that only repeat
+/// far apart are transformed again, but long runs and frequently repeated tiles (e.g. empty
+/// ocean) are not.
+pub struct TileCache {
+    capacity: usize,
+    /// Slot and the tick it was last used
+    slots: Mutex<(HashMap<TileKey, (Arc<Slot>, u64)>, u64)>,
+}
+
+impl TileCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            slots: Mutex::new((HashMap::new(), 0)),
+        }
+    }
+
+    pub fn get(&self, key: TileKey) -> CacheSlot {
+        let mut guard = self.slots.lock().unwrap();
+        let (slots, tick) = &mut *guard;
+        *tick += 1;
+        if let Some((slot, used)) = slots.get_mut(&key) {
+            *used = *tick;
+            return CacheSlot {
+                slot: slot.clone(),
+                owner: false,
+            };
+        }
+        if slots.len() >= self.capacity {
+            // Evict the least recently used half at once to keep this cheap on average
+            let mut ticks = slots.values().map(|(_, used)| *used).collect::<Vec<_>>();
+            let (_, median, _) = ticks.select_nth_unstable(slots.len() / 2);
+            let median = *median;
+            slots.retain(|_, (_, used)| *used > median);
+        }
+        let slot = Arc::new(Slot::default());
+        slots.insert(key, (slot.clone(), *tick));
+        CacheSlot { slot, owner: true }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn key(offset: u64) -> TileKey {
+        vec![(0, offset)]
+    }
+
+    fn cached(output: Result<&'static str, &str>) -> Cached {
+        Cached {
+            input: Bytes::from_static(b"input"),
+            output: output.map(Bytes::from_static).map_err(str::to_string),
+        }
+    }
+
+    #[test]
+    fn repeated_tiles_share_the_owners_slot() {
+        let cache = TileCache::new(16);
+        let owner = cache.get(key(1));
+        let other = cache.get(key(2));
+        let repeat = cache.get(key(1));
+        assert!(owner.owner && other.owner);
+        assert!(!repeat.owner);
+        assert!(Arc::ptr_eq(&owner.slot, &repeat.slot));
+        assert!(!Arc::ptr_eq(&owner.slot, &other.slot));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let cache = TileCache::new(4);
+        for offset in 0..4 {
+            assert!(cache.get(key(offset)).owner);
+        }
+        // Used again, so it's the most recent
+        assert!(!cache.get(key(0)).owner);
+        assert!(cache.get(key(4)).owner);
+        assert!(!cache.get(key(0)).owner);
+        assert!(!cache.get(key(4)).owner);
+        assert!(cache.get(key(1)).owner);
+    }
+
+    #[test]
+    fn waiters_receive_the_owners_result() {
+        let slot = Arc::new(Slot::default());
+        let waiter = {
+            let slot = slot.clone();
+            thread::spawn(move || slot.wait())
+        };
+        slot.set(cached(Ok("output")));
+        let result = waiter.join().unwrap();
+        assert_eq!(result.output.unwrap(), "output");
+        assert_eq!(result.input, "input");
+    }
+
+    #[test]
+    fn waiters_receive_the_owners_failure() {
+        let slot = Arc::new(Slot::default());
+        let waiters = (0..2)
+            .map(|_| {
+                let slot = slot.clone();
+                thread::spawn(move || slot.wait())
+            })
+            .collect::<Vec<_>>();
+        slot.set(cached(Err("decode frame: invalid PNG")));
+        for waiter in waiters {
+            let result = waiter.join().unwrap();
+            assert_eq!(result.output.unwrap_err(), "decode frame: invalid PNG");
+            // Still there for --on-error passthrough
+            assert_eq!(result.input, "input");
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_owner_to_be_queued() {
+        let slot = Arc::new(Slot::default());
+        let waiter = tokio::spawn({
+            let slot = slot.clone();
+            async move { slot.wait_queued().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        slot.mark_queued();
+        waiter.await.unwrap();
+        // Already queued
+        slot.wait_queued().await;
+    }
+}
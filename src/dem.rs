@@ -0,0 +1,362 @@
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result, anyhow, bail};
+use bytes::Bytes;
+use pmtiles::TileType;
+use png::{BitDepth, ColorType, Encoder};
+use serde_json::json;
+
+use crate::{raster::Raster, transform::NoDataPolicy, writer::OutputHeader};
+
+/// Ways elevation is packed into raster tiles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DemEncoding {
+    /// Japan's GSI DEM PNG: 24-bit two's complement centimeters, `0x800000` for no-data
+    Gsi,
+    /// Mapbox Terrain-RGB PNG: `-10000 + (R * 65536 + G * 256 + B) * 0.1` meters
+    TerrainRgb,
+    /// Terrarium PNG: `(R * 256 + G + B / 256) - 32768` meters
+    Terrarium,
+    /// Raw little-endian float32 meters with a small header, NaN for no-data. See
+    /// [`FLOAT32_MAGIC`] for the layout.
+    Float32,
+}
+
+impl FromStr for DemEncoding {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "gsidem" => Ok(Self::Gsi),
+            "terrainrgb" => Ok(Self::TerrainRgb),
+            "terrarium" => Ok(Self::Terrarium),
+            "float32" => Ok(Self::Float32),
+            _ => Err(anyhow!(
+                "invalid DEM encoding: {s}. valid values: gsidem, terrainrgb, terrarium, float32"
+            )),
+        }
+    }
+}
+
+/// A decoded elevation tile.
+#[derive(Clone, Debug)]
+pub struct DemGrid {
+    pub width: u32,
+    pub height: u32,
+    /// Row-major elevations in meters; `None` for no-data
+    pub values: Vec<Option<f64>>,
+    /// Alpha of each cell in the source tile, if it had one
+    pub alpha: Option<Vec<u8>>,
+}
+
+impl DemEncoding {
+    /// Set the tile type and the metadata describing this encoding on the output header.
+    pub fn update_header(self, header: &mut OutputHeader) -> Result<()> {
+        header.tile_type = match self {
+            Self::Float32 => TileType::Unknown,
+            _ => TileType::Png,
+        };
+        header.edit_metadata(|m| {
+            m.remove(FLOAT32_METADATA_KEY);
+            // TileJSON raster-dem `encoding` values, plus our own for float32
+            match self {
+                Self::Gsi => m.remove("encoding"),
+                Self::TerrainRgb => m.insert("encoding".into(), json!("mapbox")),
+                Self::Terrarium => m.insert("encoding".into(), json!("terrarium")),
+                Self::Float32 => {
+                    m.insert(
+                        FLOAT32_METADATA_KEY.into(),
+                        json!({
+                            "magic": String::from_utf8_lossy(FLOAT32_MAGIC),
+                            "header": "magic (4 bytes), width (u32 LE), height (u32 LE)",
+                            "samples": "width * height f32 LE, row-major",
+                            "units": "meters",
+                            "nodata": "NaN",
+                        }),
+                    );
+                    m.insert("encoding".into(), json!("float32"))
+                }
+            };
+        })
+    }
+
+    pub fn decode(self, input: &[u8]) -> Result<DemGrid> {
+        match self {
+            Self::Gsi => decode_rgba_png(input, gsi_rgb_to_meters),
+            Self::TerrainRgb => {
+                decode_rgba_png(input, |r, g, b| Some(terrain_rgb_to_meters(r, g, b)))
+            }
+            Self::Terrarium => {
+                decode_rgba_png(input, |r, g, b| Some(terrarium_rgb_to_meters(r, g, b)))
+            }
+            Self::Float32 => decode_float32(input),
+        }
+    }
+
+    /// Encode `grid`, first resolving its no-data cells with `nodata`. Cells left as no-data
+    /// are transparent in Terrain-RGB and Terrarium, the sentinel in GSI DEM, and NaN in
+    /// float32.
+    pub fn encode(self, mut grid: DemGrid, nodata: NoDataPolicy) -> Result<Bytes> {
+        let valid = grid.values.iter().map(Option::is_some).collect::<Vec<_>>();
+        nodata.resolve(&mut grid.values, grid.width as usize);
+        match self {
+            Self::Gsi => {
+                let data = grid
+                    .values
+                    .iter()
+                    .flat_map(|m| gsi_rgb_from_cm(m.map(meters_to_cm)))
+                    .collect::<Vec<_>>();
+                encode_png(&data, ColorType::Rgb, grid.width, grid.height)
+            }
+            Self::TerrainRgb | Self::Terrarium => {
+                let mut data = vec![255u8; grid.values.len() * 4];
+                for (i, (px, m)) in data.chunks_exact_mut(4).zip(&grid.values).enumerate() {
+                    let m = *m;
+                    let rgb = match self {
+                        Self::Terrarium => terrarium_rgb_from_meters(m.unwrap_or(0.0)),
+                        _ => terrain_rgb_from_cm(m.map(meters_to_cm).unwrap_or(0)),
+                    };
+                    px[..3].copy_from_slice(&rgb);
+                    if m.is_none() {
+                        px[3] = 0;
+                    } else if valid[i] {
+                        // Keep the source alpha for cells that had a value to begin with
+                        if let Some(alpha) = &grid.alpha {
+                            px[3] = alpha[i];
+                        }
+                    }
+                }
+                encode_png(&data, ColorType::Rgba, grid.width, grid.height)
+            }
+            Self::Float32 => Ok(encode_float32(&grid)),
+        }
+    }
+}
+
+// --- GSI DEM ---
+
+#[inline]
+fn sign_extend_24(d: i32) -> i32 {
+    (d << 8) >> 8
+}
+
+const GSI_NODATA: i32 = -8_388_608;
+
+/// Decode a GSI DEM-packed RGB triplet to centimeters. `None` for the no-data sentinel.
+#[inline]
+pub(crate) fn gsi_rgb_to_cm(r: u8, g: u8, b: u8) -> Option<i32> {
+    let d = ((r as i32) << 16) | ((g as i32) << 8) | (b as i32);
+    let cm = sign_extend_24(d);
+    (cm != GSI_NODATA).then_some(cm)
+}
+
+#[inline]
+pub(crate) fn gsi_rgb_to_meters(r: u8, g: u8, b: u8) -> Option<f64> {
+    gsi_rgb_to_cm(r, g, b).map(|cm| cm as f64 / 100.0)
+}
+
+#[inline]
+fn gsi_rgb_from_cm(cm: Option<i32>) -> [u8; 3] {
+    let d = cm.map_or(GSI_NODATA, |cm| cm.clamp(GSI_NODATA + 1, -GSI_NODATA - 1));
+    [
+        ((d >> 16) & 0xFF) as u8,
+        ((d >> 8) & 0xFF) as u8,
+        (d & 0xFF) as u8,
+    ]
+}
+
+// --- Terrain-RGB ---
+
+#[inline]
+fn round_decimeters(cm: i32) -> i32 {
+    // JS Math.round semantics for cm/10: floor(x + 0.5)
+    let a = cm + 5;
+    if a >= 0 { a / 10 } else { -(((-a) + 9) / 10) }
+}
+
+#[inline]
+fn terrain_value_from_cm(cm: i32) -> i32 {
+    100_000 + round_decimeters(cm)
+}
+
+#[inline]
+pub(crate) fn terrain_rgb_from_cm(cm: i32) -> [u8; 3] {
+    let v = terrain_value_from_cm(cm);
+    [
+        ((v >> 16) & 0xFF) as u8,
+        ((v >> 8) & 0xFF) as u8,
+        (v & 0xFF) as u8,
+    ]
+}
+
+#[inline]
+fn terrain_rgb_to_meters(r: u8, g: u8, b: u8) -> f64 {
+    let v = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
+    -10_000.0 + v as f64 * 0.1
+}
+
+// --- Terrarium ---
+
+#[inline]
+fn terrarium_rgb_to_meters(r: u8, g: u8, b: u8) -> f64 {
+    r as f64 * 256.0 + g as f64 + b as f64 / 256.0 - 32_768.0
+}
+
+#[inline]
+fn terrarium_rgb_from_meters(m: f64) -> [u8; 3] {
+    let v = (m + 32_768.0).clamp(0.0, 65_535.996);
+    let whole = v.floor();
+    [
+        (whole / 256.0) as u8,
+        (whole % 256.0) as u8,
+        ((v - whole) * 256.0) as u8,
+    ]
+}
+
+#[inline]
+pub(crate) fn meters_to_cm(m: f64) -> i32 {
+    (m * 100.0).round() as i32
+}
+
+// --- Float32 ---
+
+/// Float32 tiles start with this magic, followed by the width and height as little-endian
+/// u32s, followed by `width * height` little-endian f32 samples in row-major order.
+pub const FLOAT32_MAGIC: &[u8; 4] = b"F32R";
+/// Metadata key describing the float32 tile format, since PMTiles has no tile type for it.
+pub(crate) const FLOAT32_METADATA_KEY: &str = "float32_tile";
+pub(crate) const FLOAT32_HEADER_LEN: usize = 12;
+
+fn encode_float32(grid: &DemGrid) -> Bytes {
+    let mut out = Vec::with_capacity(FLOAT32_HEADER_LEN + grid.values.len() * 4);
+    out.extend_from_slice(FLOAT32_MAGIC);
+    out.extend_from_slice(&grid.width.to_le_bytes());
+    out.extend_from_slice(&grid.height.to_le_bytes());
+    for m in &grid.values {
+        out.extend_from_slice(&m.map_or(f32::NAN, |m| m as f32).to_le_bytes());
+    }
+    out.into()
+}
+
+fn decode_float32(input: &[u8]) -> Result<DemGrid> {
+    if input.len() < FLOAT32_HEADER_LEN || &input[..4] != FLOAT32_MAGIC {
+        bail!("not a float32 DEM tile");
+    }
+    let width = u32::from_le_bytes(input[4..8].try_into().unwrap());
+    let height = u32::from_le_bytes(input[8..12].try_into().unwrap());
+    let samples = &input[FLOAT32_HEADER_LEN..];
+    let expected = (width as usize)
+        .checked_mul(height as usize)
+        .and_then(|n| n.checked_mul(4))
+        .with_context(|| format!("float32 DEM tile is too large: {width}x{height}"))?;
+    if samples.len() != expected {
+        bail!(
+            "float32 DEM tile has {} bytes of samples, expected {}x{}x4",
+            samples.len(),
+            width,
+            height
+        );
+    }
+    let values = samples
+        .chunks_exact(4)
+        .map(|b| {
+            let v = f32::from_le_bytes(b.try_into().unwrap());
+            (!v.is_nan()).then_some(v as f64)
+        })
+        .collect();
+    Ok(DemGrid {
+        width,
+        height,
+        values,
+        alpha: None,
+    })
+}
+
+// --- PNG ---
+
+/// Decode any PNG to RGBA8 and unpack each pixel with `unpack`. Transparent pixels are no-data.
+fn decode_rgba_png(input: &[u8], unpack: impl Fn(u8, u8, u8) -> Option<f64>) -> Result<DemGrid> {
+    let raster = Raster::decode_png(input)?;
+    Ok(rgba8_to_grid(
+        &raster.data,
+        raster.width,
+        raster.height,
+        unpack,
+    ))
+}
+
+pub(crate) fn rgba8_to_grid(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    unpack: impl Fn(u8, u8, u8) -> Option<f64>,
+) -> DemGrid {
+    let values = data
+        .chunks_exact(4)
+        .map(|px| {
+            if px[3] == 0 {
+                None
+            } else {
+                unpack(px[0], px[1], px[2])
+            }
+        })
+        .collect();
+    let alpha = data.chunks_exact(4).map(|px| px[3]).collect();
+    DemGrid {
+        width,
+        height,
+        values,
+        alpha: Some(alpha),
+    }
+}
+
+fn encode_png(data: &[u8], color: ColorType, width: u32, height: u32) -> Result<Bytes> {
+    let mut out = Vec::with_capacity(data.len() + 1024);
+    {
+        let mut enc = Encoder::new(&mut out, width, height);
+        enc.set_color(color);
+        enc.set_depth(BitDepth::Eight);
+        let mut writer = enc.write_header().context("write header")?;
+        writer.write_image_data(data).context("encode image data")?;
+    }
+    Ok(out.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn float32_round_trip() {
+        let grid = DemGrid {
+            width: 2,
+            height: 2,
+            values: vec![Some(-12.5), None, Some(0.0), Some(3776.25)],
+            alpha: None,
+        };
+        let encoded = DemEncoding::Float32
+            .encode(grid.clone(), NoDataPolicy::Transparent)
+            .unwrap();
+        assert_eq!(encoded.len(), FLOAT32_HEADER_LEN + 4 * 4);
+        let decoded = DemEncoding::Float32.decode(&encoded).unwrap();
+        assert_eq!((decoded.width, decoded.height), (2, 2));
+        assert_eq!(decoded.values, grid.values);
+    }
+
+    #[test]
+    fn float32_rejects_bad_lengths() {
+        assert!(decode_float32(b"F32R").is_err());
+        let mut tile = FLOAT32_MAGIC.to_vec();
+        tile.extend_from_slice(&2u32.to_le_bytes());
+        tile.extend_from_slice(&2u32.to_le_bytes());
+        tile.extend_from_slice(&[0; 12]);
+        assert!(decode_float32(&tile).is_err());
+    }
+
+    #[test]
+    fn float32_rejects_huge_dimensions() {
+        let mut tile = FLOAT32_MAGIC.to_vec();
+        tile.extend_from_slice(&u32::MAX.to_le_bytes());
+        tile.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_float32(&tile).is_err());
+    }
+}
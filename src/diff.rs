@@ -0,0 +1,419 @@
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use pmtiles::{TileCoord, TileId, TileType};
+use tokio::sync::OnceCell;
+
+use crate::{
+    dem::{DemEncoding, DemGrid, FLOAT32_METADATA_KEY},
+    ingest::TileCodec,
+    location::Location,
+    progress::{Progress, ProgressMode, ProgressMsg},
+    raster::Raster,
+    reader::{self, PmTilesReader},
+    tile::Tile,
+    writer::{OutputHeader, WriteMsg, WriteTileMsg, Writer},
+};
+
+/// Elevation difference (meters) drawn fully opaque in the difference archive.
+const DEM_FULL_SCALE: f64 = 10.0;
+
+/// Number of tiles listed for each kind of difference.
+const EXAMPLES: usize = 10;
+
+/// Number of recently compared tile pairs whose result is kept for repeats
+const COMPARED_CAPACITY: usize = 1024;
+
+/// Per-pixel difference statistics of one changed tile.
+#[derive(Clone, Copy, Debug, Default)]
+struct PixelStats {
+    /// Samples compared: channels for imagery, cells with a value in both tiles for DEMs
+    count: u64,
+    sum_sq: f64,
+    max_abs: f64,
+}
+
+impl PixelStats {
+    fn add(&mut self, d: f64) {
+        self.count += 1;
+        self.sum_sq += d * d;
+        self.max_abs = self.max_abs.max(d.abs());
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.count += other.count;
+        self.sum_sq += other.sum_sq;
+        self.max_abs = self.max_abs.max(other.max_abs);
+    }
+
+    fn rmse(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            (self.sum_sq / self.count as f64).sqrt()
+        }
+    }
+}
+
+/// How a tile present in both archives compares.
+#[derive(Clone)]
+enum TileDiff {
+    Identical,
+    /// Compared pixel by pixel, with a visualization of the difference
+    Changed(PixelStats, Option<Bytes>),
+    /// The bytes differ but the pixels can't be compared, e.g. different sizes
+    Incomparable(String),
+}
+
+/// Results of recently compared tiles, by the offsets of their data in A and B. Repeated
+/// tiles, such as empty sea tiles, are only read and compared once.
+#[derive(Default)]
+struct Compared {
+    results: HashMap<(u64, u64), Arc<OnceCell<TileDiff>>>,
+    /// Oldest first, for eviction
+    order: VecDeque<(u64, u64)>,
+}
+
+impl Compared {
+    /// The result for the pair at `offsets`, filled in by whichever tile compares it first.
+    fn get(&mut self, offsets: (u64, u64)) -> Arc<OnceCell<TileDiff>> {
+        if let Some(result) = self.results.get(&offsets) {
+            return result.clone();
+        }
+        let result = Arc::new(OnceCell::new());
+        self.results.insert(offsets, result.clone());
+        self.order.push_back(offsets);
+        if self.order.len() > COMPARED_CAPACITY {
+            let oldest = self.order.pop_front().unwrap();
+            self.results.remove(&oldest);
+        }
+        result
+    }
+}
+
+/// Compare pixels of two tiles. The visualization is transparent where they agree, red where
+/// `b` is higher and blue where it is lower, more opaque the larger the difference.
+fn compare_pixels(a: &[u8], b: &[u8], codec: TileCodec) -> Result<TileDiff> {
+    let mut stats = PixelStats::default();
+    let (width, height, diffs) = match codec {
+        TileCodec::Rgb => {
+            let (a, b) = (Raster::decode_png(a)?, Raster::decode_png(b)?);
+            if (a.width, a.height) != (b.width, b.height) {
+                return Ok(TileDiff::Incomparable(format!(
+                    "size changed from {}x{} to {}x{}",
+                    a.width, a.height, b.width, b.height
+                )));
+            }
+            let diffs = a
+                .data
+                .chunks_exact(4)
+                .zip(b.data.chunks_exact(4))
+                .map(|(pa, pb)| {
+                    let mut strongest = 0.0f64;
+                    for (&ca, &cb) in pa.iter().zip(pb) {
+                        let d = cb as f64 - ca as f64;
+                        stats.add(d);
+                        if d.abs() > strongest.abs() {
+                            strongest = d;
+                        }
+                    }
+                    strongest / 255.0
+                })
+                .collect::<Vec<_>>();
+            (a.width, a.height, diffs)
+        }
+        TileCodec::Dem(encoding) => {
+            let (a, b): (DemGrid, DemGrid) = (encoding.decode(a)?, encoding.decode(b)?);
+            if (a.width, a.height) != (b.width, b.height) {
+                return Ok(TileDiff::Incomparable(format!(
+                    "size changed from {}x{} to {}x{}",
+                    a.width, a.height, b.width, b.height
+                )));
+            }
+            let diffs = a
+                .values
+                .iter()
+                .zip(&b.values)
+                .map(|(ma, mb)| match (ma, mb) {
+                    (Some(ma), Some(mb)) => {
+                        stats.add(mb - ma);
+                        ((mb - ma) / DEM_FULL_SCALE).clamp(-1.0, 1.0)
+                    }
+                    (None, None) => 0.0,
+                    // A value appearing or disappearing is shown at full strength
+                    (None, Some(_)) => 1.0,
+                    (Some(_), None) => -1.0,
+                })
+                .collect::<Vec<_>>();
+            (a.width, a.height, diffs)
+        }
+    };
+    if diffs.iter().all(|&d| d == 0.0) {
+        // Different bytes, same pixels
+        return Ok(TileDiff::Changed(stats, None));
+    }
+    let data = diffs
+        .iter()
+        .flat_map(|&d| {
+            let alpha = (d.abs() * 255.0).round() as u8;
+            if d > 0.0 {
+                [255, 0, 0, alpha]
+            } else {
+                [0, 0, 255, alpha]
+            }
+        })
+        .collect();
+    let raster = Raster {
+        width,
+        height,
+        data,
+    };
+    Ok(TileDiff::Changed(stats, Some(raster.encode_png()?)))
+}
+
+/// Compare two archives tile by tile: coordinates only in one of them, byte-identical tiles,
+/// and per-pixel statistics for changed raster tiles. With `output`, changed tiles are drawn
+/// into a difference archive.
+pub async fn diff(
+    a_input: Location,
+    b_input: Location,
+    codec: Option<TileCodec>,
+    output: Option<Location>,
+    force: bool,
+    mode: ProgressMode,
+) -> Result<()> {
+    let a = reader::open(&a_input).await?;
+    let b = reader::open(&b_input).await?;
+    let a_header = OutputHeader::from_reader(&a).await?;
+    let b_header = OutputHeader::from_reader(&b).await?;
+    let codec = codec.unwrap_or_else(|| {
+        let metadata = serde_json::from_str(&a_header.metadata).unwrap_or_default();
+        TileCodec::detect(a_header.tile_type, &metadata)
+    });
+    // Only raster tiles in the same format can be compared pixel by pixel
+    let raster = a_header.tile_type == b_header.tile_type
+        && matches!(
+            (a_header.tile_type, codec),
+            (TileType::Png, _) | (TileType::Unknown, TileCodec::Dem(DemEncoding::Float32))
+        );
+    if output.is_some() && !raster {
+        bail!("a difference archive can only be written for PNG or float32 raster tiles");
+    }
+
+    let writer = match output {
+        Some(output) => {
+            let mut header = a_header.clone();
+            header.merge(&b_header)?;
+            header.tile_type = TileType::Png;
+            header.edit_metadata(|m| {
+                m.remove("encoding");
+                m.remove(FLOAT32_METADATA_KEY);
+            })?;
+            Some(Writer::new(output, force, header, None).await?)
+        }
+        None => None,
+    };
+    let (writer_tx, writer_rx) = flume::bounded::<WriteMsg>(1024);
+    let (progress_tx, progress_rx) = flume::unbounded::<ProgressMsg>();
+    let writer_task = writer.map(|writer| {
+        let progress_tx = progress_tx.clone();
+        tokio::task::spawn_blocking(move || writer.write(writer_rx, None, progress_tx))
+    });
+    // Progress counts compared coordinates; written tiles are only the changed ones
+    let progress =
+        tokio::task::spawn_blocking(move || Progress::new(mode).without_writes().run(progress_rx));
+
+    // Tiles in both archives are counted twice; the count is corrected as they turn up
+    let mut count = [&a, &b]
+        .iter()
+        .filter_map(|r| r.get_header().n_addressed_tiles)
+        .map(|n| n.get())
+        .sum::<u64>();
+    progress_tx.send(ProgressMsg::UpdateCount(count))?;
+
+    let mut only_a = Vec::new();
+    let mut only_b = Vec::new();
+    let mut identical = 0u64;
+    let mut changed: Vec<(TileCoord, PixelStats)> = Vec::new();
+    let mut incomparable = Vec::new();
+    let mut total = PixelStats::default();
+    let mut index = 0;
+
+    let concurrency = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+    let mut compared = Compared::default();
+    let mut pairs = merge_coords(a.clone(), b.clone())
+        .map_ok(|(coord, offset_a, offset_b)| {
+            let (a, b) = (a.clone(), b.clone());
+            let result = offset_a.zip(offset_b).map(|offsets| compared.get(offsets));
+            async move {
+                let Some(result) = result else {
+                    return Ok((coord, offset_a.is_some(), offset_b.is_some(), None));
+                };
+                let diff = result
+                    .get_or_try_init(|| async {
+                        let (data_a, data_b) =
+                            tokio::try_join!(a.get_tile(coord), b.get_tile(coord))?;
+                        let (Some(data_a), Some(data_b)) = (data_a, data_b) else {
+                            bail!("tile {} is listed but can't be read", Tile::from(coord));
+                        };
+                        if data_a == data_b {
+                            Ok(TileDiff::Identical)
+                        } else if raster {
+                            tokio::task::spawn_blocking(move || {
+                                compare_pixels(&data_a, &data_b, codec)
+                            })
+                            .await?
+                            .with_context(|| format!("while comparing tile {}", Tile::from(coord)))
+                        } else {
+                            Ok(TileDiff::Incomparable("not a raster tile".into()))
+                        }
+                    })
+                    .await?
+                    .clone();
+                Ok((coord, true, true, Some(diff)))
+            }
+        })
+        .try_buffered(concurrency);
+
+    while let Some((coord, in_a, in_b, diff)) = pairs.try_next().await? {
+        if in_a && in_b {
+            count = count.saturating_sub(1);
+            progress_tx.send(ProgressMsg::UpdateCount(count))?;
+        }
+        progress_tx.send(ProgressMsg::Processed(coord.into()))?;
+        match diff {
+            None if in_a => only_a.push(coord),
+            None => only_b.push(coord),
+            Some(TileDiff::Identical) => identical += 1,
+            Some(TileDiff::Incomparable(reason)) => incomparable.push((coord, reason)),
+            Some(TileDiff::Changed(stats, visual)) => {
+                total.merge(&stats);
+                changed.push((coord, stats));
+                if let (Some(data), Some(_)) = (visual, &writer_task) {
+                    writer_tx
+                        .send_async(WriteMsg::Tile(WriteTileMsg {
+                            index,
+                            tile: coord.into(),
+                            tile_data: Some(data),
+                            reservation: None,
+                            stats: None,
+                        }))
+                        .await?;
+                    index += 1;
+                }
+            }
+        }
+    }
+    if writer_task.is_some() {
+        writer_tx.send_async(WriteMsg::End { count: index }).await?;
+    }
+    drop(writer_tx);
+    // The writer reports the end of the run once the archive is finished
+    match writer_task {
+        Some(task) => task.await??,
+        None => progress_tx.send(ProgressMsg::Finished())?,
+    }
+    drop(progress_tx);
+    progress.await??;
+
+    let unit = match codec {
+        TileCodec::Dem(_) => " m",
+        TileCodec::Rgb => "",
+    };
+    println!("{a_input} -> {b_input}");
+    println!("  only in {a_input}: {}", only_a.len());
+    println!("  only in {b_input}: {}", only_b.len());
+    println!("  identical:      {identical}");
+    println!("  changed:        {}", changed.len() + incomparable.len());
+    if !changed.is_empty() {
+        println!(
+            "  max abs difference: {:.3}{unit}, RMSE: {:.3}{unit}",
+            total.max_abs,
+            total.rmse()
+        );
+    }
+    let list = |name: &str, coords: &[TileCoord]| {
+        if !coords.is_empty() {
+            let shown = coords
+                .iter()
+                .take(EXAMPLES)
+                .map(|c| Tile::from(*c).to_string())
+                .collect::<Vec<_>>();
+            println!("{name}: {}", shown.join(" "));
+        }
+    };
+    list(&format!("Only in {a_input}"), &only_a);
+    list(&format!("Only in {b_input}"), &only_b);
+    changed.sort_by(|(_, x), (_, y)| y.max_abs.partial_cmp(&x.max_abs).unwrap_or(Ordering::Equal));
+    if !changed.is_empty() {
+        println!("Most changed tiles:");
+        for (coord, stats) in changed.iter().take(EXAMPLES) {
+            println!(
+                "  {:<14} max abs {:.3}{unit}, RMSE {:.3}{unit}",
+                Tile::from(*coord).to_string(),
+                stats.max_abs,
+                stats.rmse()
+            );
+        }
+    }
+    for (coord, reason) in incomparable.iter().take(EXAMPLES) {
+        println!("  {:<14} {reason}", Tile::from(*coord).to_string());
+    }
+    if index > 0 {
+        println!("Wrote {index} difference tiles");
+    }
+    Ok(())
+}
+
+/// The coordinates of both archives in tile ID order, with the offset of their data in each
+/// archive that has them.
+fn merge_coords(
+    a: PmTilesReader,
+    b: PmTilesReader,
+) -> impl futures_util::Stream<Item = Result<(TileCoord, Option<u64>, Option<u64>)>> {
+    let id = |c: &TileCoord| TileId::from(*c).value();
+    stream::try_unfold(
+        (
+            reader::coords_in_order(a),
+            reader::coords_in_order(b),
+            None,
+            None,
+            false,
+        ),
+        move |(mut sa, mut sb, mut ha, mut hb, started)| async move {
+            if !started {
+                ha = sa.try_next().await?;
+                hb = sb.try_next().await?;
+            }
+            let item = match (ha, hb) {
+                (None, None) => return Ok(None),
+                (Some((ca, oa)), Some((cb, ob))) if id(&ca) == id(&cb) => {
+                    ha = sa.try_next().await?;
+                    hb = sb.try_next().await?;
+                    (ca, Some(oa), Some(ob))
+                }
+                (Some((ca, oa)), Some((cb, _))) if id(&ca) < id(&cb) => {
+                    ha = sa.try_next().await?;
+                    (ca, Some(oa), None)
+                }
+                (Some((ca, oa)), None) => {
+                    ha = sa.try_next().await?;
+                    (ca, Some(oa), None)
+                }
+                (_, Some((cb, ob))) => {
+                    hb = sb.try_next().await?;
+                    (cb, None, Some(ob))
+                }
+            };
+            Ok(Some((item, (sa, sb, ha, hb, true))))
+        },
+    )
+}
@@ -0,0 +1,199 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+
+use crate::tile::Tile;
+
+/// Tiles that failed to process, written as JSON lines. The file is only created once the
+/// first failure is recorded.
+///
+/// A resumed run appends to the report. The tiles after its checkpoint are done again, so a
+/// tile already in the report isn't added twice; one that failed before the interruption but
+/// succeeds when done again keeps its entry.
+pub struct ErrorReport {
+    path: PathBuf,
+    /// Add to an existing report rather than replacing it, when resuming
+    append: bool,
+    /// The open report, and the tiles already in it when appending
+    file: Mutex<Option<(BufWriter<File>, HashSet<String>)>>,
+    count: AtomicU64,
+}
+
+impl ErrorReport {
+    pub fn new(path: PathBuf, append: bool) -> Self {
+        Self {
+            path,
+            append,
+            file: Mutex::new(None),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Count `previous` failures recorded by an interrupted run as well.
+    pub fn with_count(self, previous: u64) -> Self {
+        self.count.store(previous, Ordering::Relaxed);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Append a failure for `tile` with its error chain.
+    pub fn record(&self, tile: &Tile, error: &anyhow::Error) -> Result<()> {
+        let line = json!({
+            "tile": tile.to_string(),
+            "z": tile.z(),
+            "x": tile.x(),
+            "y": tile.y(),
+            "error": error.to_string(),
+            "chain": error.chain().map(|e| e.to_string()).collect::<Vec<_>>(),
+        });
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            let listed = if self.append {
+                self.listed_tiles()?
+            } else {
+                HashSet::new()
+            };
+            let f = File::options()
+                .create(true)
+                .write(true)
+                .append(self.append)
+                .truncate(!self.append)
+                .open(&self.path)
+                .with_context(|| format!("Failed to create {}", self.path.display()))?;
+            *file = Some((BufWriter::new(f), listed));
+        }
+        let (f, listed) = file.as_mut().unwrap();
+        if !listed.contains(&tile.to_string()) {
+            writeln!(f, "{line}").context("write error report")?;
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The tiles in the report being appended to, if there is one.
+    fn listed_tiles(&self) -> Result<HashSet<String>> {
+        let report = match fs::read_to_string(&self.path) {
+            Ok(report) => report,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.path.display()));
+            }
+        };
+        let tiles = report
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .filter_map(|entry| entry["tile"].as_str().map(str::to_string))
+            .collect();
+        Ok(tiles)
+    }
+
+    /// Flush the report to disk.
+    pub fn flush(&self) -> Result<()> {
+        if let Some((f, _)) = self.file.lock().unwrap().as_mut() {
+            f.flush().context("flush error report")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use pmtiles::TileCoord;
+
+    use super::*;
+
+    fn tile(x: u32) -> Tile {
+        TileCoord::new(4, x, 7).unwrap().into()
+    }
+
+    fn entries(path: &Path) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn only_created_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = ErrorReport::new(dir.path().join("errors.jsonl"), false);
+        report.flush().unwrap();
+        assert!(!report.path().exists());
+        assert_eq!(report.count(), 0);
+    }
+
+    #[test]
+    fn records_the_error_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = ErrorReport::new(dir.path().join("errors.jsonl"), false);
+        let error = anyhow!("invalid PNG signature").context("decode frame");
+        report.record(&tile(3), &error).unwrap();
+        report.flush().unwrap();
+
+        assert_eq!(report.count(), 1);
+        let written = entries(report.path());
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0]["tile"], tile(3).to_string());
+        assert_eq!(written[0]["z"], 4);
+        assert_eq!(written[0]["x"], 3);
+        assert_eq!(written[0]["y"], 7);
+        assert_eq!(written[0]["error"], "decode frame");
+        assert_eq!(
+            written[0]["chain"],
+            json!(["decode frame", "invalid PNG signature"])
+        );
+    }
+
+    #[test]
+    fn replaces_an_earlier_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("errors.jsonl");
+        fs::write(&path, "{\"tile\":\"old\"}\n").unwrap();
+        let report = ErrorReport::new(path.clone(), false);
+        report.record(&tile(1), &anyhow!("failed")).unwrap();
+        report.flush().unwrap();
+        assert_eq!(entries(&path).len(), 1);
+    }
+
+    #[test]
+    fn resuming_appends_without_repeating_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("errors.jsonl");
+        let first = ErrorReport::new(path.clone(), false);
+        first.record(&tile(1), &anyhow!("failed")).unwrap();
+        // Recorded after the last checkpoint, so done again on resume
+        first.record(&tile(2), &anyhow!("failed")).unwrap();
+        first.flush().unwrap();
+
+        let resumed = ErrorReport::new(path.clone(), true).with_count(1);
+        resumed.record(&tile(2), &anyhow!("failed")).unwrap();
+        resumed.record(&tile(3), &anyhow!("failed")).unwrap();
+        resumed.flush().unwrap();
+
+        assert_eq!(resumed.count(), 3);
+        let tiles = entries(&path)
+            .iter()
+            .map(|e| e["x"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(tiles, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    io::{BufWriter, Seek, Write},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result, bail};
+use futures_util::{StreamExt, TryStreamExt, stream};
+use pmtiles::TileCoord;
+use rayon::prelude::*;
+use tiff::{
+    encoder::{DirectoryEncoder, TiffEncoder, TiffKind, colortype},
+    tags::Tag,
+};
+
+use crate::{
+    dem::DemGrid,
+    ingest::TileCodec,
+    location::Location,
+    mercator::{self, Bbox, ORIGIN_SHIFT},
+    progress::{Progress, ProgressMode, ProgressMsg},
+    raster::Raster,
+    reader, writer,
+};
+
+/// A decoded tile to be placed in the mosaic.
+enum Decoded {
+    Rgba(Raster),
+    Dem(DemGrid),
+}
+
+impl Decoded {
+    fn size(&self) -> (u32, u32) {
+        match self {
+            Decoded::Rgba(r) => (r.width, r.height),
+            Decoded::Dem(g) => (g.width, g.height),
+        }
+    }
+}
+
+/// Mosaic the tiles covering `bbox` at `zoom` into a single EPSG:3857 GeoTIFF.
+///
+/// Imagery (`TileCodec::Rgb`) is written as RGBA8. DEM encodings are decoded and written as
+/// float32 elevations in meters, with NaN for no-data.
+pub async fn export_geotiff(
+    input: Location,
+    output: PathBuf,
+    bbox: Bbox,
+    zoom: u8,
+    codec: TileCodec,
+    force: bool,
+    mode: ProgressMode,
+) -> Result<()> {
+    let in_pmt = reader::open(&input).await?;
+
+    let (x0, y0) = mercator::lonlat_to_tile(zoom, bbox.west, bbox.north);
+    let (x1, y1) = mercator::lonlat_to_tile(zoom, bbox.east, bbox.south);
+    let mut coords = Vec::new();
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            coords.push(TileCoord::new(zoom, x, y)?);
+        }
+    }
+
+    // Fail before reading anything, but only create the output once there's a mosaic to write
+    if !force && output.exists() {
+        bail!(
+            "{} already exists. Hint: try specifying --force if you want to overwrite it.",
+            output.display()
+        );
+    }
+
+    let (progress_tx, progress_rx) = flume::unbounded();
+    let progress =
+        tokio::task::spawn_blocking(move || Progress::new(mode).without_writes().run(progress_rx));
+    progress_tx.send(ProgressMsg::UpdateCount(coords.len() as u64))?;
+    let concurrency = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+    let tiles = stream::iter(coords)
+        .map(|coord| {
+            let in_pmt = in_pmt.clone();
+            let progress_tx = progress_tx.clone();
+            async move {
+                let data = in_pmt.get_tile(coord).await?;
+                progress_tx.send(ProgressMsg::Processed(coord.into()))?;
+                Ok::<_, anyhow::Error>(data.map(|d| (coord, d)))
+            }
+        })
+        .buffer_unordered(concurrency)
+        .try_filter_map(|t| async move { Ok(t) })
+        .try_collect::<Vec<_>>()
+        .await?;
+    progress_tx.send(ProgressMsg::Finished())?;
+    if tiles.is_empty() {
+        bail!("{input} has no tiles within the bbox at zoom {zoom}");
+    }
+
+    let out_path = output.display().to_string();
+    let (width, height) = tokio::task::spawn_blocking(move || {
+        let decoded = tiles
+            .into_par_iter()
+            .map(|(coord, data)| {
+                let decoded = match codec {
+                    TileCodec::Rgb => Decoded::Rgba(Raster::decode_png(&data)?),
+                    TileCodec::Dem(encoding) => Decoded::Dem(encoding.decode(&data)?),
+                };
+                Ok((coord, decoded))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let (tile_size, _) = decoded[0].1.size();
+        if decoded
+            .iter()
+            .any(|(_, d)| d.size() != (tile_size, tile_size))
+        {
+            bail!("tiles must be square and all the same size");
+        }
+
+        // Crop to the bbox, in global pixel coordinates at this zoom
+        let (gx0, gy0) = mercator::lonlat_to_global_pixel(zoom, tile_size, bbox.west, bbox.north);
+        let (gx1, gy1) = mercator::lonlat_to_global_pixel(zoom, tile_size, bbox.east, bbox.south);
+        let (px0, py0) = (gx0.floor() as i64, gy0.floor() as i64);
+        let (width, height) = (
+            (gx1.ceil() as i64 - px0) as usize,
+            (gy1.ceil() as i64 - py0) as usize,
+        );
+        // Where a pixel of a tile lands in the mosaic, if it's inside it
+        let place = |coord: &TileCoord, i: u32, j: u32| -> Option<usize> {
+            let x = coord.x() as i64 * tile_size as i64 + i as i64 - px0;
+            let y = coord.y() as i64 * tile_size as i64 + j as i64 - py0;
+            (x >= 0 && y >= 0 && (x as usize) < width && (y as usize) < height)
+                .then(|| y as usize * width + x as usize)
+        };
+
+        let resolution = 2.0 * ORIGIN_SHIFT / (tile_size as f64 * (1u64 << zoom) as f64);
+        let origin = (
+            -ORIGIN_SHIFT + px0 as f64 * resolution,
+            ORIGIN_SHIFT - py0 as f64 * resolution,
+        );
+        let out_f = writer::open_local(&output, force)?;
+        let mut tiff = TiffEncoder::new(BufWriter::new(out_f))?;
+
+        match codec {
+            TileCodec::Rgb => {
+                let mut data = vec![0u8; width * height * 4];
+                for (coord, d) in &decoded {
+                    let Decoded::Rgba(r) = d else { unreachable!() };
+                    for j in 0..tile_size {
+                        for i in 0..tile_size {
+                            if let Some(p) = place(coord, i, j) {
+                                data[p * 4..p * 4 + 4].copy_from_slice(r.pixel(i, j));
+                            }
+                        }
+                    }
+                }
+                let mut image = tiff.new_image::<colortype::RGBA8>(width as u32, height as u32)?;
+                write_georeference(image.encoder(), origin, resolution)?;
+                image.write_data(&data)?;
+            }
+            TileCodec::Dem(_) => {
+                let mut data = vec![f32::NAN; width * height];
+                for (coord, d) in &decoded {
+                    let Decoded::Dem(g) = d else { unreachable!() };
+                    for j in 0..tile_size {
+                        for i in 0..tile_size {
+                            if let Some(p) = place(coord, i, j) {
+                                let v = g.values[(j * tile_size + i) as usize];
+                                data[p] = v.map_or(f32::NAN, |v| v as f32);
+                            }
+                        }
+                    }
+                }
+                let mut image =
+                    tiff.new_image::<colortype::Gray32Float>(width as u32, height as u32)?;
+                write_georeference(image.encoder(), origin, resolution)?;
+                image.encoder().write_tag(Tag::GdalNodata, "nan")?;
+                image.write_data(&data)?;
+            }
+        }
+        Ok((width, height))
+    })
+    .await??;
+    progress_tx.send(ProgressMsg::Log(format!(
+        "Wrote {width}x{height} GeoTIFF to {out_path}."
+    )))?;
+    drop(progress_tx);
+    progress.await?
+}
+
+// GeoKeyDirectory values for EPSG:3857
+const GEO_KEY_DIRECTORY_3857: [u16; 16] = [
+    1, 1, 0, 3, // version, revision, minor revision, number of keys
+    1024, 0, 1, 1, // GTModelTypeGeoKey = ModelTypeProjected
+    1025, 0, 1, 1, // GTRasterTypeGeoKey = RasterPixelIsArea
+    3072, 0, 1, 3857, // ProjectedCSTypeGeoKey = EPSG:3857
+];
+
+fn write_georeference<W: Write + Seek, K: TiffKind>(
+    dir: &mut DirectoryEncoder<'_, W, K>,
+    origin: (f64, f64),
+    resolution: f64,
+) -> Result<()> {
+    dir.write_tag(Tag::ModelPixelScaleTag, &[resolution, resolution, 0.0][..])
+        .context("write ModelPixelScaleTag")?;
+    dir.write_tag(
+        Tag::ModelTiepointTag,
+        &[0.0, 0.0, 0.0, origin.0, origin.1, 0.0][..],
+    )
+    .context("write ModelTiepointTag")?;
+    dir.write_tag(Tag::GeoKeyDirectoryTag, &GEO_KEY_DIRECTORY_3857[..])
+        .context("write GeoKeyDirectoryTag")?;
+    Ok(())
+}
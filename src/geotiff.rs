@@ -0,0 +1,235 @@
+use std::{fs::File, io::BufReader, path::Path, str::FromStr};
+
+use anyhow::{Context, Error, Result, anyhow, bail};
+use tiff::{
+    decoder::{Decoder, DecodingResult, Limits},
+    tags::Tag,
+};
+
+use crate::mercator;
+
+/// Coordinate reference systems a GeoTIFF can be read in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Crs {
+    /// EPSG:3857 Web Mercator, in meters
+    WebMercator,
+    /// Geographic longitude/latitude in degrees: EPSG:4326 (WGS84), or EPSG:6668 / EPSG:4612
+    /// (JGD2011 / JGD2000), which are treated as identical to WGS84
+    Geographic,
+}
+
+impl FromStr for Crs {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.trim_start_matches("EPSG:").trim_start_matches("epsg:") {
+            "3857" | "900913" => Ok(Self::WebMercator),
+            "4326" | "6668" | "4612" => Ok(Self::Geographic),
+            _ => Err(anyhow!(
+                "unsupported CRS: {s}. supported: EPSG:3857, EPSG:4326, EPSG:6668, EPSG:4612"
+            )),
+        }
+    }
+}
+
+impl Crs {
+    fn from_epsg(code: u16) -> Result<Self> {
+        code.to_string().parse()
+    }
+
+    /// Convert a point in this CRS to longitude/latitude.
+    pub fn to_lonlat(self, x: f64, y: f64) -> (f64, f64) {
+        match self {
+            Self::WebMercator => mercator::meters_to_lonlat(x, y),
+            Self::Geographic => (x, y),
+        }
+    }
+
+    /// Convert an EPSG:3857 point to this CRS.
+    pub fn convert_web_mercator(self, x: f64, y: f64) -> (f64, f64) {
+        match self {
+            Self::WebMercator => (x, y),
+            Self::Geographic => mercator::meters_to_lonlat(x, y),
+        }
+    }
+}
+
+// GeoKeyDirectory keys
+const GT_MODEL_TYPE_GEO_KEY: u16 = 1024;
+const GEOGRAPHIC_TYPE_GEO_KEY: u16 = 2048;
+const PROJECTED_CS_TYPE_GEO_KEY: u16 = 3072;
+const MODEL_TYPE_PROJECTED: u16 = 1;
+const MODEL_TYPE_GEOGRAPHIC: u16 = 2;
+
+/// A GeoTIFF raster read fully into memory. Only north-up (unrotated) rasters are supported.
+pub struct GeoTiff {
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_pixel: usize,
+    pub crs: Crs,
+    /// CRS coordinates of the top-left corner of the top-left pixel
+    origin: (f64, f64),
+    /// Pixel size in CRS units; the y size is negative for north-up rasters
+    pixel_size: (f64, f64),
+    nodata: Option<f64>,
+    samples: DecodingResult,
+}
+
+impl GeoTiff {
+    /// Read a GeoTIFF. `crs` overrides the CRS declared in the file.
+    pub fn open(path: &Path, crs: Option<Crs>) -> Result<Self> {
+        let f = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut decoder = Decoder::new(BufReader::new(f))
+            .context("read tiff header")?
+            .with_limits(Limits::unlimited());
+        let (width, height) = decoder.dimensions()?;
+        let samples_per_pixel = match decoder.colortype()? {
+            tiff::ColorType::Gray(_) => 1,
+            tiff::ColorType::GrayA(_) => 2,
+            tiff::ColorType::RGB(_) => 3,
+            tiff::ColorType::RGBA(_) => 4,
+            other => bail!("unsupported GeoTIFF color type: {other:?}"),
+        };
+
+        let (origin, pixel_size) = read_transform(&mut decoder)?;
+        let crs = match crs {
+            Some(crs) => crs,
+            None => read_crs(&mut decoder)?,
+        };
+        let nodata = decoder
+            .get_tag_ascii_string(Tag::GdalNodata)
+            .ok()
+            .and_then(|s| s.trim_matches(char::from(0)).trim().parse().ok());
+
+        let samples = decoder.read_image().context("read tiff image data")?;
+        Ok(Self {
+            width,
+            height,
+            samples_per_pixel,
+            crs,
+            origin,
+            pixel_size,
+            nodata,
+            samples,
+        })
+    }
+
+    /// Bounds as `(west, south, east, north)` in longitude/latitude.
+    pub fn lonlat_bounds(&self) -> (f64, f64, f64, f64) {
+        let (x0, y0) = self.origin;
+        let x1 = x0 + self.width as f64 * self.pixel_size.0;
+        let y1 = y0 + self.height as f64 * self.pixel_size.1;
+        let (w, n) = self.crs.to_lonlat(x0.min(x1), y0.max(y1));
+        let (e, s) = self.crs.to_lonlat(x0.max(x1), y0.min(y1));
+        (w, s, e, n)
+    }
+
+    /// Fractional pixel coordinates (relative to the top-left corner) of a point in the
+    /// raster's CRS.
+    pub fn crs_to_pixel(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            (x - self.origin.0) / self.pixel_size.0,
+            (y - self.origin.1) / self.pixel_size.1,
+        )
+    }
+
+    /// The value of `band` at integer pixel coordinates; `None` outside the raster or for
+    /// no-data.
+    pub fn sample(&self, band: usize, px: i64, py: i64) -> Option<f64> {
+        if px < 0 || py < 0 || px >= self.width as i64 || py >= self.height as i64 {
+            return None;
+        }
+        let i = (py as usize * self.width as usize + px as usize) * self.samples_per_pixel + band;
+        let v = match &self.samples {
+            DecodingResult::U8(b) => b[i] as f64,
+            DecodingResult::U16(b) => b[i] as f64,
+            DecodingResult::U32(b) => b[i] as f64,
+            DecodingResult::U64(b) => b[i] as f64,
+            DecodingResult::I8(b) => b[i] as f64,
+            DecodingResult::I16(b) => b[i] as f64,
+            DecodingResult::I32(b) => b[i] as f64,
+            DecodingResult::I64(b) => b[i] as f64,
+            DecodingResult::F32(b) => b[i] as f64,
+            DecodingResult::F64(b) => b[i],
+            #[allow(unreachable_patterns)]
+            _ => return None,
+        };
+        if v.is_nan() || Some(v) == self.nodata {
+            None
+        } else {
+            Some(v)
+        }
+    }
+
+    /// Bilinear interpolation of `band` at fractional pixel coordinates, falling back to the
+    /// nearest pixel when a neighbor is no-data.
+    pub fn sample_bilinear(&self, band: usize, px: f64, py: f64) -> Option<f64> {
+        // Pixel centers are at +0.5
+        let (fx, fy) = (px - 0.5, py - 0.5);
+        let (x0, y0) = (fx.floor() as i64, fy.floor() as i64);
+        let (tx, ty) = (fx - x0 as f64, fy - y0 as f64);
+        let corners = [
+            self.sample(band, x0, y0),
+            self.sample(band, x0 + 1, y0),
+            self.sample(band, x0, y0 + 1),
+            self.sample(band, x0 + 1, y0 + 1),
+        ];
+        match corners {
+            [Some(a), Some(b), Some(c), Some(d)] => {
+                let top = a + (b - a) * tx;
+                let bottom = c + (d - c) * tx;
+                Some(top + (bottom - top) * ty)
+            }
+            _ => self.sample(band, px.floor() as i64, py.floor() as i64),
+        }
+    }
+}
+
+fn read_transform(decoder: &mut Decoder<BufReader<File>>) -> Result<((f64, f64), (f64, f64))> {
+    if let Ok(m) = decoder.get_tag_f64_vec(Tag::ModelTransformationTag) {
+        if m.len() < 8 {
+            bail!("invalid ModelTransformationTag");
+        }
+        if m[1] != 0.0 || m[4] != 0.0 {
+            bail!("rotated GeoTIFFs are not supported");
+        }
+        return Ok(((m[3], m[7]), (m[0], m[5])));
+    }
+    let tiepoint = decoder
+        .get_tag_f64_vec(Tag::ModelTiepointTag)
+        .context("GeoTIFF has neither ModelTransformationTag nor ModelTiepointTag")?;
+    let scale = decoder
+        .get_tag_f64_vec(Tag::ModelPixelScaleTag)
+        .context("GeoTIFF has no ModelPixelScaleTag")?;
+    if tiepoint.len() < 6 || scale.len() < 2 {
+        bail!("invalid GeoTIFF tiepoint or pixel scale");
+    }
+    let (i, j, x, y) = (tiepoint[0], tiepoint[1], tiepoint[3], tiepoint[4]);
+    Ok(((x - i * scale[0], y + j * scale[1]), (scale[0], -scale[1])))
+}
+
+fn read_crs(decoder: &mut Decoder<BufReader<File>>) -> Result<Crs> {
+    let keys = decoder
+        .get_tag_u16_vec(Tag::GeoKeyDirectoryTag)
+        .context("GeoTIFF has no GeoKeyDirectoryTag; specify the CRS with --crs")?;
+    // Header: version, revision, minor revision, number of keys; then 4 shorts per key:
+    // key id, tag location (0 = value is inline), count, value
+    let inline_key = |id: u16| {
+        keys.get(4..)?
+            .chunks_exact(4)
+            .find(|k| k[0] == id && k[1] == 0)
+            .map(|k| k[3])
+    };
+    match inline_key(GT_MODEL_TYPE_GEO_KEY) {
+        Some(MODEL_TYPE_PROJECTED) => {
+            let code = inline_key(PROJECTED_CS_TYPE_GEO_KEY)
+                .context("GeoTIFF has no ProjectedCSTypeGeoKey; specify the CRS with --crs")?;
+            Crs::from_epsg(code)
+        }
+        Some(MODEL_TYPE_GEOGRAPHIC) => {
+            let code = inline_key(GEOGRAPHIC_TYPE_GEO_KEY)
+                .context("GeoTIFF has no GeographicTypeGeoKey; specify the CRS with --crs")?;
+            Crs::from_epsg(code)
+        }
+        _ => bail!("unsupported GeoTIFF model type; specify the CRS with --crs"),
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Cursor,
+};
+
+use anyhow::{Context, Result};
+use futures_util::TryStreamExt;
+use pmtiles::{TileCoord, TileId};
+use serde_json::{Value, json};
+
+use crate::{
+    dem::{FLOAT32_HEADER_LEN, FLOAT32_MAGIC},
+    location::Location,
+    reader::{ConflictPolicy, PmTilesReader, Reader},
+};
+
+/// Tile counts and sizes of one zoom level.
+#[derive(Clone, Copy, Debug, Default)]
+struct ZoomStats {
+    /// Tiles addressed by the directory, counting every tile of a run
+    addressed: u64,
+    /// Distinct tile contents first seen at this zoom level
+    unique: u64,
+    /// Bytes of tile data stored for those contents
+    bytes: u64,
+}
+
+/// What a sampled tile turned out to be.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TileFormat {
+    format: &'static str,
+    width: Option<u32>,
+    height: Option<u32>,
+    color_type: Option<String>,
+}
+
+/// The first tile ID of zoom level `z`: (4^z - 1) / 3.
+fn zoom_base(z: u8) -> u64 {
+    1u64.checked_shl(2 * z as u32)
+        .map_or(u64::MAX, |n| (n - 1) / 3)
+}
+
+fn zoom_of(tile_id: u64) -> u8 {
+    (0..32).find(|&z| tile_id < zoom_base(z + 1)).unwrap_or(31)
+}
+
+/// Identify the image format of a tile from its leading bytes, with its dimensions and color
+/// type where the format is understood.
+fn sniff(data: &[u8]) -> TileFormat {
+    let mut info = TileFormat {
+        format: "unknown",
+        width: None,
+        height: None,
+        color_type: None,
+    };
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        info.format = "png";
+        if let Ok(reader) = png::Decoder::new(Cursor::new(data)).read_info() {
+            let png = reader.info();
+            info.width = Some(png.width);
+            info.height = Some(png.height);
+            info.color_type = Some(format!("{:?} {}-bit", png.color_type, png.bit_depth as u8));
+        }
+    } else if data.starts_with(FLOAT32_MAGIC) && data.len() >= FLOAT32_HEADER_LEN {
+        info.format = "float32";
+        info.width = Some(u32::from_le_bytes(data[4..8].try_into().unwrap()));
+        info.height = Some(u32::from_le_bytes(data[8..12].try_into().unwrap()));
+    } else if data.starts_with(b"\xff\xd8\xff") {
+        info.format = "jpeg";
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        info.format = "webp";
+    } else if data.len() >= 12 && &data[4..12] == b"ftypavif" {
+        info.format = "avif";
+    } else if data.starts_with(b"\x1f\x8b") {
+        info.format = "gzip";
+    }
+    info
+}
+
+/// Print what's inside an archive: its header, metadata, tile counts and sizes per zoom
+/// level, and the formats of a sample of its tiles.
+pub async fn info(input: Location, sample: usize, as_json: bool) -> Result<()> {
+    let reader = Reader::new(vec![input], Vec::new(), ConflictPolicy::First, false).await?;
+    let (input, pmt) = reader.inputs().next().unwrap();
+    let header = pmt.get_header();
+    let metadata = pmt.get_metadata().await?;
+    let metadata: Value = if metadata.trim().is_empty() {
+        json!({})
+    } else {
+        serde_json::from_str(&metadata).context("metadata is not valid JSON")?
+    };
+
+    // Walk the directories, counting runs as their length but their data once
+    let mut zooms: BTreeMap<u8, ZoomStats> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut unique_ids = Vec::new();
+    let mut entries = pmt.entries();
+    while let Some(entry) = entries.try_next().await? {
+        if seen.insert(entry.offset) {
+            let stats = zooms.entry(zoom_of(entry.tile_id)).or_default();
+            stats.unique += 1;
+            stats.bytes += entry.length as u64;
+            unique_ids.push(entry.tile_id);
+        }
+        // A run may cross into the next zoom level
+        let mut id = entry.tile_id;
+        let end = entry.tile_id + u64::from(entry.run_length);
+        while id < end {
+            let z = zoom_of(id);
+            let next = end.min(zoom_base(z + 1));
+            zooms.entry(z).or_default().addressed += next - id;
+            id = next;
+        }
+    }
+
+    let samples = sample_formats(pmt, &unique_ids, sample).await?;
+    let addressed = zooms.values().map(|s| s.addressed).sum::<u64>();
+    let unique = unique_ids.len() as u64;
+
+    if as_json {
+        let out = json!({
+            "input": input.to_string(),
+            "header": {
+                "tile_type": format!("{:?}", header.tile_type),
+                "tile_compression": format!("{:?}", header.tile_compression),
+                "internal_compression": format!("{:?}", header.internal_compression),
+                "clustered": header.clustered,
+                "min_zoom": header.min_zoom,
+                "max_zoom": header.max_zoom,
+                "bounds": [
+                    header.min_longitude,
+                    header.min_latitude,
+                    header.max_longitude,
+                    header.max_latitude,
+                ],
+                "center": [
+                    header.center_longitude,
+                    header.center_latitude,
+                    header.center_zoom,
+                ],
+            },
+            "metadata": metadata,
+            "addressed_tiles": addressed,
+            "unique_tiles": unique,
+            "zooms": zooms
+                .iter()
+                .map(|(z, s)| {
+                    json!({
+                        "zoom": z,
+                        "addressed_tiles": s.addressed,
+                        "unique_tiles": s.unique,
+                        "bytes": s.bytes,
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "samples": samples
+                .iter()
+                .map(|(f, count)| {
+                    json!({
+                        "format": f.format,
+                        "width": f.width,
+                        "height": f.height,
+                        "color_type": f.color_type,
+                        "count": count,
+                    })
+                })
+                .collect::<Vec<_>>(),
+        });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+
+    println!("{input}");
+    println!("  tile type:            {:?}", header.tile_type);
+    println!("  tile compression:     {:?}", header.tile_compression);
+    println!("  internal compression: {:?}", header.internal_compression);
+    println!("  clustered:            {}", header.clustered);
+    println!(
+        "  zoom:                 {}-{}",
+        header.min_zoom, header.max_zoom
+    );
+    println!(
+        "  bounds:               {},{},{},{}",
+        header.min_longitude, header.min_latitude, header.max_longitude, header.max_latitude
+    );
+    println!(
+        "  center:               {},{} z{}",
+        header.center_longitude, header.center_latitude, header.center_zoom
+    );
+    println!("  addressed tiles:      {addressed}");
+    println!("  unique tiles:         {unique}");
+    println!();
+    println!("Metadata:");
+    println!("{}", serde_json::to_string_pretty(&metadata)?);
+    println!();
+    println!(
+        "{:>6} {:>14} {:>14} {:>16}",
+        "zoom", "addressed", "unique", "bytes"
+    );
+    for (z, s) in &zooms {
+        println!(
+            "{z:>6} {:>14} {:>14} {:>16}",
+            s.addressed, s.unique, s.bytes
+        );
+    }
+    println!();
+    println!(
+        "Formats in a sample of {} tiles:",
+        samples.values().sum::<usize>()
+    );
+    for (f, count) in &samples {
+        let mut line = format!("  {count:>6} {}", f.format);
+        if let (Some(w), Some(h)) = (f.width, f.height) {
+            line += &format!(" {w}x{h}");
+        }
+        if let Some(color_type) = &f.color_type {
+            line += &format!(" {color_type}");
+        }
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Read up to `sample` distinct tiles, spread evenly over the archive, and count their formats.
+async fn sample_formats(
+    pmt: &PmTilesReader,
+    unique_ids: &[u64],
+    sample: usize,
+) -> Result<BTreeMap<TileFormat, usize>> {
+    let mut formats = BTreeMap::new();
+    let n = sample.min(unique_ids.len());
+    for i in 0..n {
+        let tile_id = unique_ids[i * unique_ids.len() / n];
+        let coord = TileCoord::from(TileId::new(tile_id)?);
+        if let Some(data) = pmt.get_tile(coord).await? {
+            *formats.entry(sniff(&data)).or_default() += 1;
+        }
+    }
+    Ok(formats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zoom_bases() {
+        assert_eq!([0, 1, 2, 3, 4].map(zoom_base), [0, 1, 5, 21, 85]);
+        assert_eq!(zoom_base(31), ((1u64 << 62) - 1) / 3);
+        assert_eq!(zoom_base(32), u64::MAX);
+    }
+
+    #[test]
+    fn zoom_of_tile_ids() {
+        assert_eq!([0, 1, 4, 5, 20, 21].map(zoom_of), [0, 1, 1, 2, 2, 3]);
+        assert_eq!(zoom_of(zoom_base(31)), 31);
+        assert_eq!(zoom_of(u64::MAX), 31);
+    }
+
+    #[test]
+    fn zoom_of_matches_pmtiles() {
+        for (z, x, y) in [(0, 0, 0), (3, 7, 0), (10, 512, 300), (20, 0, (1 << 20) - 1)] {
+            let id = TileId::from(TileCoord::new(z, x, y).unwrap()).value();
+            assert_eq!(zoom_of(id), z);
+            assert!(id >= zoom_base(z) && id < zoom_base(z + 1));
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::{Context, Error, Result, bail};
+use flume::Sender;
+use pmtiles::{Compression, TileCoord, TileId, TileType};
+use rayon::prelude::*;
+use serde_json::Value;
+use tokio::task::JoinSet;
+
+use crate::{
+    QUEUE_CAPACITY,
+    dem::{DemEncoding, DemGrid, FLOAT32_METADATA_KEY},
+    geotiff::{Crs, GeoTiff},
+    location::Location,
+    mercator,
+    progress::{Progress, ProgressMode, ProgressMsg, ProgressSender},
+    raster::Raster,
+    transform::NoDataPolicy,
+    writer::{self, OutputHeader, WriteMsg, WriteTileMsg, Writer},
+};
+
+/// How ingested tiles are encoded.
+#[derive(Clone, Copy, Debug)]
+pub enum TileCodec {
+    /// Elevation from the first band, packed with a DEM encoding
+    Dem(DemEncoding),
+    /// Imagery: gray, gray+alpha, RGB or RGBA bands copied to RGBA PNG
+    Rgb,
+}
+
+impl FromStr for TileCodec {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rgb" => Ok(Self::Rgb),
+            _ => s.parse().map(Self::Dem),
+        }
+    }
+}
+
+impl TileCodec {
+    /// How an archive's tiles are encoded, from its metadata: the TileJSON `encoding`, or the
+    /// description of float32 tiles. Anything else is taken as imagery. That includes GSI DEM
+    /// archives, whose metadata doesn't say what they are, so they have to be named.
+    pub fn detect(tile_type: TileType, metadata: &Value) -> Self {
+        match metadata["encoding"].as_str() {
+            Some("mapbox") => Self::Dem(DemEncoding::TerrainRgb),
+            Some("terrarium") => Self::Dem(DemEncoding::Terrarium),
+            Some("float32") => Self::Dem(DemEncoding::Float32),
+            _ if tile_type == TileType::Unknown && metadata.get(FLOAT32_METADATA_KEY).is_some() => {
+                Self::Dem(DemEncoding::Float32)
+            }
+            _ => Self::Rgb,
+        }
+    }
+}
+
+/// Cuts a GeoTIFF into Web Mercator tiles.
+pub struct Ingest {
+    input: PathBuf,
+    tiff: GeoTiff,
+    codec: TileCodec,
+    min_zoom: u8,
+    max_zoom: u8,
+    tile_size: u32,
+}
+
+impl Ingest {
+    pub fn new(
+        input: PathBuf,
+        crs: Option<Crs>,
+        codec: TileCodec,
+        min_zoom: u8,
+        max_zoom: u8,
+        tile_size: u32,
+    ) -> Result<Self> {
+        if min_zoom > max_zoom {
+            bail!("--min-zoom must not be greater than --max-zoom");
+        }
+        if tile_size != 256 && tile_size != 512 {
+            bail!("--tile-size must be 256 or 512, got: {tile_size}");
+        }
+        let tiff = GeoTiff::open(&input, crs)?;
+        Ok(Self {
+            input,
+            tiff,
+            codec,
+            min_zoom,
+            max_zoom,
+            tile_size,
+        })
+    }
+
+    /// Header and metadata for the output archive.
+    pub fn output_header(&self) -> Result<OutputHeader> {
+        let (west, south, east, north) = self.tiff.lonlat_bounds();
+        let mut header = OutputHeader {
+            tile_type: TileType::Png,
+            tile_compression: Compression::None,
+            min_zoom: self.min_zoom,
+            max_zoom: self.max_zoom,
+            min_longitude: west as f32,
+            min_latitude: south as f32,
+            max_longitude: east as f32,
+            max_latitude: north as f32,
+            center_zoom: self.min_zoom,
+            center_longitude: ((west + east) / 2.0) as f32,
+            center_latitude: ((south + north) / 2.0) as f32,
+            metadata: String::new(),
+        };
+        let name = self
+            .input
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        header.edit_metadata(|m| {
+            m.insert("name".into(), name.into());
+            m.insert("tileSize".into(), self.tile_size.into());
+        })?;
+        if let TileCodec::Dem(encoding) = self.codec {
+            encoding.update_header(&mut header)?;
+        }
+        Ok(header)
+    }
+
+    /// Every tile overlapping the raster, in tile ID order.
+    fn coords(&self) -> Result<Vec<TileCoord>> {
+        let (west, south, east, north) = self.tiff.lonlat_bounds();
+        let mut coords = Vec::new();
+        for z in self.min_zoom..=self.max_zoom {
+            let (x0, y0) = mercator::lonlat_to_tile(z, west, north);
+            let (x1, y1) = mercator::lonlat_to_tile(z, east, south);
+            for x in x0..=x1 {
+                for y in y0..=y1 {
+                    coords.push(TileCoord::new(z, x, y)?);
+                }
+            }
+        }
+        coords.sort_unstable_by_key(|c| TileId::from(*c).value());
+        Ok(coords)
+    }
+
+    /// Render one tile. `None` if the tile has no data.
+    fn render(&self, coord: TileCoord) -> Result<Option<bytes::Bytes>> {
+        let size = self.tile_size;
+        let bands = match self.codec {
+            TileCodec::Dem(_) => 1,
+            TileCodec::Rgb => self.tiff.samples_per_pixel,
+        };
+        let mut samples = Vec::with_capacity((size * size) as usize);
+        for py in 0..size {
+            for px in 0..size {
+                let (mx, my) = mercator::tile_pixel_to_meters(
+                    coord.z(),
+                    coord.x(),
+                    coord.y(),
+                    size,
+                    px as f64 + 0.5,
+                    py as f64 + 0.5,
+                );
+                let (sx, sy) = self.tiff.crs.convert_web_mercator(mx, my);
+                let (ix, iy) = self.tiff.crs_to_pixel(sx, sy);
+                let px_samples = (0..bands)
+                    .map(|band| self.tiff.sample_bilinear(band, ix, iy))
+                    .collect::<Option<Vec<_>>>();
+                samples.push(px_samples);
+            }
+        }
+        if samples.iter().all(Option::is_none) {
+            return Ok(None);
+        }
+
+        let data = match self.codec {
+            TileCodec::Dem(encoding) => {
+                let grid = DemGrid {
+                    width: size,
+                    height: size,
+                    values: samples.into_iter().map(|s| s.map(|s| s[0])).collect(),
+                    alpha: None,
+                };
+                encoding.encode(grid, NoDataPolicy::Transparent)?
+            }
+            TileCodec::Rgb => {
+                let mut data = vec![0u8; (size * size * 4) as usize];
+                for (px, s) in data.chunks_exact_mut(4).zip(samples) {
+                    let Some(s) = s else { continue };
+                    let v = |i: usize| s[i].round().clamp(0.0, 255.0) as u8;
+                    let rgba = match bands {
+                        1 => [v(0), v(0), v(0), 255],
+                        2 => [v(0), v(0), v(0), v(1)],
+                        3 => [v(0), v(1), v(2), 255],
+                        _ => [v(0), v(1), v(2), v(3)],
+                    };
+                    px.copy_from_slice(&rgba);
+                }
+                let raster = Raster {
+                    width: size,
+                    height: size,
+                    data,
+                };
+                raster.encode_png()?
+            }
+        };
+        Ok(Some(data))
+    }
+
+    pub fn run(&self, output: Sender<WriteMsg>, progress_tx: ProgressSender) -> Result<()> {
+        let coords = self.coords()?;
+        progress_tx.send(ProgressMsg::UpdateCount(coords.len() as u64))?;
+        progress_tx.send(ProgressMsg::Log(format!(
+            "Cutting {} into {} tiles at zoom {}-{}",
+            self.input.display(),
+            coords.len(),
+            self.min_zoom,
+            self.max_zoom
+        )))?;
+
+        let count = coords.len();
+        coords
+            .into_iter()
+            .enumerate()
+            .par_bridge()
+            .try_for_each_with(output.clone(), |output, (index, coord)| {
+                let tile_data = self.render(coord).with_context(|| {
+                    format!(
+                        "while rendering tile {}/{}/{}",
+                        coord.z(),
+                        coord.x(),
+                        coord.y()
+                    )
+                })?;
+                output.send(WriteMsg::Tile(WriteTileMsg {
+                    index,
+                    tile: coord.into(),
+                    tile_data,
+                    reservation: None,
+                    stats: None,
+                }))?;
+                progress_tx
+                    .send(ProgressMsg::Processed(coord.into()))
+                    .context("Failed to send progress message")?;
+                Ok::<(), anyhow::Error>(())
+            })?;
+        output.send(WriteMsg::End { count })?;
+        Ok(())
+    }
+}
+
+/// Cut a GeoTIFF into tiles with `ingest` and write them to `output`.
+pub async fn ingest_geotiff(
+    ingest: Ingest,
+    output: Location,
+    force: bool,
+    mode: ProgressMode,
+) -> Result<()> {
+    let (writer_tx, writer_rx) = flume::bounded::<WriteMsg>(QUEUE_CAPACITY);
+    let (progress_tx, progress_rx) = flume::unbounded::<ProgressMsg>();
+
+    let mut js = JoinSet::new();
+    let writer = Writer::new(output, force, ingest.output_header()?, None).await?;
+    let progress = Progress::new(mode);
+
+    let ingest_progress_tx = progress_tx.clone();
+    js.spawn_blocking(move || ingest.run(writer_tx, ingest_progress_tx));
+    js.spawn_blocking(move || writer.write(writer_rx, None, progress_tx));
+    js.spawn_blocking(move || progress.run(progress_rx));
+
+    writer::join_stages(js).await
+}
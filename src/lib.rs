@@ -0,0 +1,85 @@
+//! Transform raster tiles in PMTiles archives.
+//!
+//! [`convert`] runs the whole pipeline the command line does:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use pmtiles_raster_tool::{ConvertOptions, ErrorPolicy, Location, Transform, convert};
+//!
+//! let transform: Transform = "gsidempng-to-terrainrgbpng".parse()?;
+//! convert(
+//!     vec!["in.pmtiles".parse()?],
+//!     transform,
+//!     "s3://bucket/out.pmtiles".parse::<Location>()?,
+//!     ConvertOptions::default().with_on_error(ErrorPolicy::Skip),
+//! )
+//! .await
+//! # }
+//! ```
+//!
+//! For more control, connect a [`Reader`], [`Transformer`] and [`Writer`] with channels the
+//! way [`convert`] does. A custom transform implements [`TransformProcess`].
+
+mod bucket;
+mod budget;
+mod checkpoint;
+mod composite;
+mod convert;
+mod dedupe;
+mod dem;
+mod diff;
+mod error_report;
+mod export;
+mod geotiff;
+mod info;
+mod ingest;
+mod location;
+mod mercator;
+mod mvt;
+mod progress;
+mod prune;
+mod raster;
+mod reader;
+mod retile;
+mod stats;
+mod tile;
+mod transform;
+mod transformer;
+mod verify;
+mod writer;
+
+pub use budget::MemoryBudget;
+pub use convert::{ConvertOptions, DEFAULT_MAX_MEMORY, convert};
+pub use dem::DemEncoding;
+pub use error_report::ErrorReport;
+pub use location::Location;
+pub use progress::{ProgressMode, ProgressMsg, ProgressSender};
+pub use prune::PruneOptions;
+pub use reader::{ConflictPolicy, ReadMsg, ReadTileMsg, Reader};
+pub use tile::Tile;
+pub use transform::{
+    CustomTransform, Neighbors, Params, Transform, TransformProcess, register, register_shared,
+    registered,
+};
+pub use transformer::{ErrorPolicy, Transformer};
+pub use writer::{OutputHeader, WriteMsg, WriteTileMsg, Writer};
+
+/// The command line's other subcommands, for the binary.
+#[doc(hidden)]
+pub mod commands {
+    pub use crate::{
+        budget::ByteSize,
+        diff::diff,
+        export::export_geotiff,
+        geotiff::Crs,
+        info::info,
+        ingest::{Ingest, TileCodec, ingest_geotiff},
+        mercator::Bbox,
+        progress::LogWriter,
+        retile::{Retile, retile},
+        verify::verify,
+    };
+}
+
+/// Capacity of the channels between pipeline stages.
+const QUEUE_CAPACITY: usize = 2_usize.pow(16);
@@ -0,0 +1,60 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Error, bail};
+
+/// Where an archive lives: on the local filesystem, or in an S3-compatible bucket.
+#[derive(Clone, Debug)]
+pub enum Location {
+    Local(PathBuf),
+    /// `s3://bucket/key`
+    S3 {
+        bucket: String,
+        key: String,
+    },
+}
+
+impl FromStr for Location {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix("s3://") else {
+            return Ok(Self::Local(PathBuf::from(s)));
+        };
+        match rest.split_once('/') {
+            Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Self::S3 {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+            }),
+            _ => bail!("invalid S3 location: {s}. expected s3://bucket/key"),
+        }
+    }
+}
+
+impl Location {
+    /// A local path for a file that accompanies this archive, named after it with `suffix`
+    /// appended: next to a local archive, or in the working directory for S3.
+    pub fn sidecar_path(&self, suffix: &str) -> PathBuf {
+        let path = match self {
+            Location::Local(path) => path.as_path(),
+            Location::S3 { key, .. } => Path::new(key.as_str()),
+        };
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(suffix);
+        match self {
+            Location::Local(path) => path.with_file_name(name),
+            Location::S3 { .. } => PathBuf::from(name),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Local(path) => write!(f, "{}", path.display()),
+            Location::S3 { bucket, key } => write!(f, "s3://{bucket}/{key}"),
+        }
+    }
+}
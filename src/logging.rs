@@ -0,0 +1,48 @@
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow};
+use pmtiles_raster_tool::{ProgressMode, commands::LogWriter};
+use tracing_chrome::{ChromeLayerBuilder, FlushGuard};
+use tracing_subscriber::{EnvFilter, Layer, fmt, prelude::*};
+
+/// Keeps the Chrome trace open; it is completed when this is dropped at the end of `main`.
+pub struct LogGuard {
+    _chrome: Option<FlushGuard>,
+}
+
+/// Set up logging to stderr, at the level `RUST_LOG` asks for or else warnings plus this
+/// tool's debug (`-v`) or trace (`-vv`) events. Log lines are printed like the progress
+/// messages of `progress` mode: above the bars, or as JSON. With `trace`, every span of this
+/// tool is also written to that file as a Chrome trace, whatever the log level.
+pub fn init(verbose: u8, trace: Option<PathBuf>, progress: ProgressMode) -> Result<LogGuard> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(match verbose {
+            0 => "warn",
+            1 => "warn,pmtiles_raster_tool=debug",
+            _ => "warn,pmtiles_raster_tool=trace",
+        })
+    });
+    let stderr = fmt::layer()
+        .with_writer(move || LogWriter::new(progress))
+        .with_ansi(progress != ProgressMode::Json)
+        .with_filter(filter);
+
+    let (chrome, guard) = match trace {
+        Some(path) => {
+            let (layer, guard) = ChromeLayerBuilder::new()
+                .file(path)
+                .include_args(true)
+                .build();
+            let layer = layer.with_filter(EnvFilter::new("pmtiles_raster_tool=trace"));
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(stderr)
+        .with(chrome)
+        .try_init()
+        .map_err(|e| anyhow!("Failed to set up logging: {e}"))?;
+    Ok(LogGuard { _chrome: guard })
+}
@@ -1,49 +1,68 @@
 use anyhow::Result;
 
 mod cli;
-mod progress;
-mod reader;
-mod tile;
-mod transform;
-mod transformer;
-mod writer;
+mod logging;
 
-use cli::Cli;
-use tokio::task::JoinSet;
-
-use crate::{
-    progress::{Progress, ProgressMsg},
-    reader::ReadTileMsg,
-    transformer::Transformer,
-    writer::WriteTileMsg,
+use cli::{Action, Cli};
+use pmtiles_raster_tool::{
+    commands::{Ingest, Retile, diff, export_geotiff, info, ingest_geotiff, retile, verify},
+    convert, registered,
 };
 
-const QUEUE_CAPACITY: usize = 2_usize.pow(16);
-
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse_resolved()?;
-
-    let (reader_tx, reader_rx) = flume::bounded::<ReadTileMsg>(QUEUE_CAPACITY);
-    let (writer_tx, writer_rx) = flume::bounded::<WriteTileMsg>(QUEUE_CAPACITY);
-    let (progress_tx, progress_rx) = flume::unbounded::<ProgressMsg>();
-
-    let mut js = JoinSet::new();
-    let reader = reader::Reader::new(cli.input.clone()).await?;
-    let transformer = Transformer::new(cli.transform);
-    let writer =
-        writer::Writer::new(cli.output.clone(), cli.force, reader.pmtiles_reader()).await?;
-    let progress = Progress::new();
-
-    let reader_progress_tx = progress_tx.clone();
-    js.spawn(async move { reader.run(reader_tx, reader_progress_tx).await });
-    let transformer_progress_tx = progress_tx.clone();
-    js.spawn_blocking(move || transformer.run(reader_rx, writer_tx, transformer_progress_tx));
-    js.spawn_blocking(move || writer.write(writer_rx, progress_tx));
-    js.spawn_blocking(move || progress.run(progress_rx));
-
-    while let Some(res) = js.join_next().await {
-        res??;
+    let (action, global) = Cli::parse_resolved()?;
+    let _log_guard = logging::init(global.verbose, global.trace, global.progress)?;
+    let progress = global.progress;
+    match action {
+        Action::Convert(cli) => convert(cli.inputs, cli.transform, cli.output, cli.options).await,
+        Action::IngestGeotiff(args) => {
+            let ingest = Ingest::new(
+                args.input,
+                args.crs,
+                args.encoding,
+                args.min_zoom,
+                args.max_zoom,
+                args.tile_size,
+            )?;
+            ingest_geotiff(ingest, args.output, args.force, progress).await
+        }
+        Action::ExportGeotiff(args) => {
+            export_geotiff(
+                args.input,
+                args.output,
+                args.bbox,
+                args.zoom,
+                args.encoding,
+                args.force,
+                progress,
+            )
+            .await
+        }
+        Action::Retile(args) => {
+            let job = Retile::new(args.input, args.encoding, args.tile_size).await?;
+            retile(job, args.output, args.force, progress).await
+        }
+        Action::Info(args) => info(args.input, args.sample, args.json).await,
+        Action::Verify(args) => verify(args.input, args.encoding, args.report, progress).await,
+        Action::Diff(args) => {
+            diff(
+                args.a,
+                args.b,
+                args.encoding,
+                args.output,
+                args.force,
+                progress,
+            )
+            .await
+        }
+        Action::Transforms => {
+            for (name, description) in registered() {
+                println!("{name:<28} {description}");
+            }
+            #[cfg(feature = "wasm")]
+            println!("{:<28} A WebAssembly plugin loaded from PATH", "wasm:PATH");
+            Ok(())
+        }
     }
-    Ok(())
 }
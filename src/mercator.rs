@@ -0,0 +1,83 @@
+//! Web Mercator (EPSG:3857) tile math.
+
+use std::{f64::consts::PI, str::FromStr};
+
+use anyhow::{Error, anyhow, bail};
+
+/// WGS84 semi-major axis, the sphere radius used by EPSG:3857
+pub const EARTH_RADIUS: f64 = 6_378_137.0;
+/// Half the width of the EPSG:3857 world, in meters
+pub const ORIGIN_SHIFT: f64 = PI * EARTH_RADIUS;
+/// Latitude limit of Web Mercator
+pub const MAX_LATITUDE: f64 = 85.051_128_779_806_59;
+
+pub fn meters_to_lonlat(x: f64, y: f64) -> (f64, f64) {
+    let lon = (x / EARTH_RADIUS).to_degrees();
+    let lat = (y / EARTH_RADIUS).sinh().atan().to_degrees();
+    (lon, lat)
+}
+
+/// The EPSG:3857 coordinates of a point within a tile, where `(px, py)` is measured in
+/// pixels from the tile's top-left corner.
+pub fn tile_pixel_to_meters(z: u8, x: u32, y: u32, tile_size: u32, px: f64, py: f64) -> (f64, f64) {
+    let world = tile_size as f64 * (1u64 << z) as f64;
+    let gx = x as f64 * tile_size as f64 + px;
+    let gy = y as f64 * tile_size as f64 + py;
+    (
+        (gx / world * 2.0 - 1.0) * ORIGIN_SHIFT,
+        (1.0 - gy / world * 2.0) * ORIGIN_SHIFT,
+    )
+}
+
+/// Global pixel coordinates (tile index * tile size + pixel within the tile) of a
+/// longitude/latitude at zoom `z`.
+pub fn lonlat_to_global_pixel(z: u8, tile_size: u32, lon: f64, lat: f64) -> (f64, f64) {
+    let world = tile_size as f64 * (1u64 << z) as f64;
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    (
+        (lon + 180.0) / 360.0 * world,
+        (1.0 - lat.tan().asinh() / PI) / 2.0 * world,
+    )
+}
+
+/// The tile containing a longitude/latitude, clamped to the valid range at zoom `z`.
+pub fn lonlat_to_tile(z: u8, lon: f64, lat: f64) -> (u32, u32) {
+    let n = (1u64 << z) as f64;
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let x = ((lon + 180.0) / 360.0 * n).floor();
+    let y = ((1.0 - lat.tan().asinh() / PI) / 2.0 * n).floor();
+    let max = n - 1.0;
+    (x.clamp(0.0, max) as u32, y.clamp(0.0, max) as u32)
+}
+
+/// A longitude/latitude bounding box, parsed from `west,south,east,north`.
+#[derive(Clone, Copy, Debug)]
+pub struct Bbox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl FromStr for Bbox {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let v = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("invalid bbox: {s}: {e}"))?;
+        let [west, south, east, north] = v[..] else {
+            bail!("invalid bbox: {s}. expected west,south,east,north");
+        };
+        if west >= east || south >= north {
+            bail!("invalid bbox: {s}. west must be less than east and south less than north");
+        }
+        Ok(Self {
+            west,
+            south,
+            east,
+            north,
+        })
+    }
+}
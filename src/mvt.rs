@@ -0,0 +1,323 @@
+//! A minimal Mapbox Vector Tile (v2) encoder for line features, and a feature counter.
+
+use anyhow::{Result, bail};
+
+/// Attribute values supported by the encoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Double(f64),
+    Bool(bool),
+}
+
+/// A line or multi-line feature, in tile extent coordinates.
+pub struct LineFeature {
+    pub lines: Vec<Vec<(i32, i32)>>,
+    pub properties: Vec<(&'static str, Value)>,
+}
+
+pub struct Layer {
+    pub name: String,
+    pub extent: u32,
+    pub features: Vec<LineFeature>,
+}
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+const WIRE_FIXED32: u32 = 5;
+
+const GEOM_LINESTRING: u32 = 2;
+const CMD_MOVE_TO: u32 = 1;
+const CMD_LINE_TO: u32 = 2;
+
+fn write_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn write_key(out: &mut Vec<u8>, field: u32, wire: u32) {
+    write_varint(out, ((field << 3) | wire) as u64);
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(out, field, WIRE_LEN);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_packed(out: &mut Vec<u8>, field: u32, values: &[u32]) {
+    let mut buf = Vec::with_capacity(values.len() * 2);
+    for &v in values {
+        write_varint(&mut buf, v as u64);
+    }
+    write_bytes(out, field, &buf);
+}
+
+#[inline]
+fn zigzag(v: i32) -> u32 {
+    ((v << 1) ^ (v >> 31)) as u32
+}
+
+fn encode_geometry(lines: &[Vec<(i32, i32)>]) -> Vec<u32> {
+    let mut out = Vec::new();
+    let (mut cx, mut cy) = (0, 0);
+    for line in lines.iter().filter(|l| l.len() >= 2) {
+        out.push(CMD_MOVE_TO | (1 << 3));
+        out.push(zigzag(line[0].0 - cx));
+        out.push(zigzag(line[0].1 - cy));
+        (cx, cy) = line[0];
+        out.push(CMD_LINE_TO | (((line.len() - 1) as u32) << 3));
+        for &(x, y) in &line[1..] {
+            out.push(zigzag(x - cx));
+            out.push(zigzag(y - cy));
+            (cx, cy) = (x, y);
+        }
+    }
+    out
+}
+
+fn encode_value(v: Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    match v {
+        Value::Double(d) => {
+            write_key(&mut out, 3, WIRE_FIXED64);
+            out.extend_from_slice(&d.to_le_bytes());
+        }
+        Value::Bool(b) => {
+            write_key(&mut out, 7, WIRE_VARINT);
+            write_varint(&mut out, b as u64);
+        }
+    }
+    out
+}
+
+fn encode_layer(layer: &Layer) -> Vec<u8> {
+    let mut keys: Vec<&str> = Vec::new();
+    let mut values: Vec<Value> = Vec::new();
+    let mut features = Vec::new();
+
+    for (id, feature) in layer.features.iter().enumerate() {
+        let geometry = encode_geometry(&feature.lines);
+        if geometry.is_empty() {
+            continue;
+        }
+        let mut tags = Vec::with_capacity(feature.properties.len() * 2);
+        for &(key, value) in &feature.properties {
+            let k = keys.iter().position(|k| *k == key).unwrap_or_else(|| {
+                keys.push(key);
+                keys.len() - 1
+            });
+            let v = values.iter().position(|v| *v == value).unwrap_or_else(|| {
+                values.push(value);
+                values.len() - 1
+            });
+            tags.extend([k as u32, v as u32]);
+        }
+
+        let mut f = Vec::new();
+        write_key(&mut f, 1, WIRE_VARINT);
+        write_varint(&mut f, id as u64 + 1);
+        write_packed(&mut f, 2, &tags);
+        write_key(&mut f, 3, WIRE_VARINT);
+        write_varint(&mut f, GEOM_LINESTRING as u64);
+        write_packed(&mut f, 4, &geometry);
+        features.push(f);
+    }
+
+    let mut out = Vec::new();
+    write_key(&mut out, 15, WIRE_VARINT);
+    write_varint(&mut out, 2);
+    write_bytes(&mut out, 1, layer.name.as_bytes());
+    for f in &features {
+        write_bytes(&mut out, 2, f);
+    }
+    for k in &keys {
+        write_bytes(&mut out, 3, k.as_bytes());
+    }
+    for v in &values {
+        write_bytes(&mut out, 4, &encode_value(*v));
+    }
+    write_key(&mut out, 5, WIRE_VARINT);
+    write_varint(&mut out, layer.extent as u64);
+    out
+}
+
+/// Encode layers into a vector tile. Layers without features are left out.
+pub fn encode_tile(layers: &[Layer]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for layer in layers.iter().filter(|l| !l.features.is_empty()) {
+        write_bytes(&mut out, 3, &encode_layer(layer));
+    }
+    out
+}
+
+/// Read a varint at the start of `data`, advancing past it.
+fn read_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let Some((&byte, rest)) = data.split_first() else {
+            break;
+        };
+        *data = rest;
+        v |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(v);
+        }
+    }
+    bail!("invalid vector tile: truncated varint")
+}
+
+/// Call `f` with the field number and contents of every length-delimited field of the message
+/// in `data`, skipping the others.
+fn for_each_len_field(mut data: &[u8], mut f: impl FnMut(u32, &[u8]) -> Result<()>) -> Result<()> {
+    while !data.is_empty() {
+        let key = read_varint(&mut data)?;
+        let (field, wire) = ((key >> 3) as u32, (key & 7) as u32);
+        let len = match wire {
+            WIRE_VARINT => {
+                read_varint(&mut data)?;
+                continue;
+            }
+            WIRE_FIXED64 => 8,
+            WIRE_FIXED32 => 4,
+            WIRE_LEN => read_varint(&mut data)? as usize,
+            _ => bail!("invalid vector tile: unknown wire type {wire}"),
+        };
+        if len > data.len() {
+            bail!("invalid vector tile: field {field} is truncated");
+        }
+        let (value, rest) = data.split_at(len);
+        if wire == WIRE_LEN {
+            f(field, value)?;
+        }
+        data = rest;
+    }
+    Ok(())
+}
+
+/// The number of features in an uncompressed vector tile, over all its layers.
+pub fn feature_count(tile: &[u8]) -> Result<usize> {
+    let mut count = 0;
+    for_each_len_field(tile, |field, layer| {
+        if field == 3 {
+            for_each_len_field(layer, |field, _| {
+                count += (field == 2) as usize;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    })?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feature(ele: f64) -> LineFeature {
+        LineFeature {
+            lines: vec![vec![(1, 1), (3, 1), (3, 4)]],
+            properties: vec![("ele", Value::Double(ele)), ("major", Value::Bool(false))],
+        }
+    }
+
+    fn count(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack
+            .windows(needle.len())
+            .filter(|w| *w == needle)
+            .count()
+    }
+
+    #[test]
+    fn zigzag_encodes_signs() {
+        assert_eq!([0, -1, 1, -2, 2].map(zigzag), [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn geometry_uses_relative_commands() {
+        let lines = vec![
+            vec![(1, 1), (3, 1), (3, 4)],
+            vec![(2, 2)],
+            vec![(0, 0), (1, 0)],
+        ];
+        assert_eq!(
+            encode_geometry(&lines),
+            // MoveTo(1,1) LineTo(+2,0)(0,+3), the single point skipped, MoveTo(-3,-4) LineTo(+1,0)
+            vec![9, 2, 2, 18, 4, 0, 0, 6, 9, 5, 7, 10, 2, 0]
+        );
+    }
+
+    #[test]
+    fn varints() {
+        let mut out = Vec::new();
+        write_varint(&mut out, 1);
+        write_varint(&mut out, 300);
+        assert_eq!(out, [0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn empty_layers_are_left_out() {
+        let layer = Layer {
+            name: "contour".into(),
+            extent: 4096,
+            features: Vec::new(),
+        };
+        assert!(encode_tile(&[layer]).is_empty());
+    }
+
+    #[test]
+    fn layer_shares_keys_and_values() {
+        let layer = Layer {
+            name: "contour".into(),
+            extent: 4096,
+            features: vec![feature(10.0), feature(10.0), feature(20.0)],
+        };
+        let tile = encode_tile(&[layer]);
+        // Tile field 3 (layers), length-delimited
+        assert_eq!(tile[0], (3 << 3) | WIRE_LEN as u8);
+        assert_eq!(count(&tile, b"contour"), 1);
+        assert_eq!(count(&tile, b"ele"), 1);
+        assert_eq!(count(&tile, b"major"), 1);
+        assert_eq!(count(&tile, &10.0f64.to_le_bytes()), 1);
+        assert_eq!(count(&tile, &20.0f64.to_le_bytes()), 1);
+        // Layer version 2
+        assert_eq!(count(&tile, &[15 << 3, 2]), 1);
+    }
+
+    #[test]
+    fn counts_features() {
+        let layers = [
+            Layer {
+                name: "contour".into(),
+                extent: 4096,
+                features: vec![feature(10.0), feature(20.0)],
+            },
+            Layer {
+                name: "index".into(),
+                extent: 4096,
+                features: vec![feature(100.0)],
+            },
+        ];
+        assert_eq!(feature_count(&encode_tile(&layers)).unwrap(), 3);
+        assert_eq!(feature_count(&[]).unwrap(), 0);
+        // A layer without features
+        let mut empty = Vec::new();
+        write_bytes(&mut empty, 1, b"contour");
+        let mut tile = Vec::new();
+        write_bytes(&mut tile, 3, &empty);
+        assert_eq!(feature_count(&tile).unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_truncated_tiles() {
+        let tile = encode_tile(&[Layer {
+            name: "contour".into(),
+            extent: 4096,
+            features: vec![feature(10.0)],
+        }]);
+        assert!(feature_count(&tile[..tile.len() - 1]).is_err());
+    }
+}
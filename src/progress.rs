@@ -1,9 +1,43 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
 use anyhow::Result;
-use flume::Receiver;
+use flume::{Receiver, RecvTimeoutError};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde_json::json;
+
+use crate::{
+    prune::DropReason,
+    stats::{Counters, RunStats, TileStats},
+    tile::Tile,
+};
+
+/// What became of a tile in the transformer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileOutcome {
+    Transformed,
+    /// Left out by --drop-empty or --drop-constant
+    Dropped(DropReason),
+    /// Failed and left out (--on-error skip)
+    Skipped,
+    /// Failed and copied unchanged (--on-error passthrough)
+    PassedThrough,
+}
 
-use crate::tile::Tile;
+/// A stage of the pipeline, for timing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Read,
+    Transform,
+    Write,
+}
 
+/// What the pipeline stages report as they go. More may be added, so match with a wildcard.
+#[non_exhaustive]
 pub enum ProgressMsg {
     Log(String),
 
@@ -13,6 +47,16 @@ pub enum ProgressMsg {
     /// A tile was processed.
     Processed(Tile),
 
+    /// A tile was transformed; counts as processed.
+    Transformed {
+        tile: Tile,
+        stats: TileStats,
+    },
+
+    /// Time a stage spent working, summed over its threads. May be sent more than once per
+    /// stage.
+    StageTime(Stage, Duration),
+
     /// A tile was written.
     Written(Tile),
 
@@ -22,14 +66,89 @@ pub enum ProgressMsg {
 
 pub type ProgressSender = flume::Sender<ProgressMsg>;
 
-pub struct Progress {
+/// How progress is shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// Bars on a terminal, plain lines otherwise
+    #[default]
+    Auto,
+    /// Interactive progress bars
+    Bar,
+    /// A plain progress line every few seconds, for logs
+    Plain,
+    /// A JSON object per line: progress every few seconds, log messages and the end of the run
+    Json,
+    /// Only log messages
+    None,
+}
+
+/// How often plain and JSON progress lines are printed
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The progress bars being shown, so that [`LogWriter`] can print above them.
+static SHOWN_BARS: Mutex<Option<MultiProgress>> = Mutex::new(None);
+
+/// Puts bars in [`SHOWN_BARS`] until dropped.
+struct ShownBars;
+
+impl ShownBars {
+    fn new(m: &MultiProgress) -> Self {
+        *SHOWN_BARS.lock().unwrap() = Some(m.clone());
+        Self
+    }
+}
+
+impl Drop for ShownBars {
+    fn drop(&mut self) {
+        if let Ok(mut shown) = SHOWN_BARS.lock() {
+            *shown = None;
+        }
+    }
+}
+
+/// A writer for `tracing_subscriber::fmt` that prints log lines like [`ProgressMsg::Log`]:
+/// above the progress bars while they are shown, as `{"event": "log"}` objects in
+/// [`ProgressMode::Json`], and to stderr otherwise. Each write is taken to be one formatted
+/// event, as the fmt layer writes them.
+pub struct LogWriter {
+    json: bool,
+}
+
+impl LogWriter {
+    pub fn new(mode: ProgressMode) -> Self {
+        Self {
+            json: mode == ProgressMode::Json,
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let s = String::from_utf8_lossy(buf);
+        let s = s.trim_end_matches('\n');
+        if self.json {
+            eprintln!("{}", json!({ "event": "log", "message": s }));
+        } else if let Some(m) = &*SHOWN_BARS.lock().unwrap() {
+            m.println(s)?;
+        } else {
+            io::stderr().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+struct Bars {
     m: MultiProgress,
     tile_processed: ProgressBar,
     tile_written: ProgressBar,
 }
 
-impl Progress {
-    pub fn new() -> Self {
+impl Bars {
+    fn new() -> Self {
         let m = MultiProgress::new();
 
         let tile_processed = m.add(ProgressBar::new(0));
@@ -54,34 +173,295 @@ impl Progress {
             tile_written,
         }
     }
+}
 
-    pub fn run(&self, rx: Receiver<ProgressMsg>) -> Result<()> {
-        while let Ok(msg) = rx.recv() {
-            match msg {
-                ProgressMsg::Log(s) => {
-                    self.m.println(s)?;
+/// Shows the progress of a run and prints the log messages of its stages. Every message for
+/// the user goes through here, so they come out in the format `mode` asks for.
+pub struct Progress {
+    mode: ProgressMode,
+    /// Only in [`ProgressMode::Bar`]
+    bars: Option<Bars>,
+    started: Instant,
+    total: u64,
+    processed: u64,
+    written: u64,
+    /// Tiles done by the interrupted run being resumed, left out of the rate
+    resumed: u64,
+    /// Whether tiles are written, or only read
+    writes: bool,
+    stats: Option<RunStats>,
+    /// Where messages are sent on to instead of being shown
+    forward: Option<ProgressSender>,
+}
+
+impl Progress {
+    pub fn new(mode: ProgressMode) -> Self {
+        let mode = match mode {
+            ProgressMode::Auto if std::io::stderr().is_terminal() => ProgressMode::Bar,
+            ProgressMode::Auto => ProgressMode::Plain,
+            mode => mode,
+        };
+        Self {
+            mode,
+            bars: (mode == ProgressMode::Bar).then(Bars::new),
+            started: Instant::now(),
+            total: 0,
+            processed: 0,
+            written: 0,
+            resumed: 0,
+            writes: true,
+            stats: None,
+            forward: None,
+        }
+    }
+
+    /// For commands that only read tiles: progress is measured in processed tiles, and
+    /// written tiles aren't shown.
+    pub fn without_writes(mut self) -> Self {
+        self.writes = false;
+        if let Some(bars) = &self.bars {
+            bars.m.remove(&bars.tile_written);
+        }
+        self
+    }
+
+    /// Count the `done` tiles of an interrupted run as processed and written, as they are
+    /// included in the total.
+    pub fn resumed_from(mut self, done: u64) -> Self {
+        self.resumed = done;
+        self.processed = done;
+        self.written = done;
+        if let Some(bars) = &self.bars {
+            bars.tile_processed.set_position(done);
+            bars.tile_written.set_position(done);
+            // Rates and ETAs only count the tiles done from now on
+            bars.tile_processed.reset_eta();
+            bars.tile_written.reset_eta();
+        }
+        self
+    }
+
+    /// Send every message on to `tx` instead of showing it, so nothing is printed.
+    pub fn forward_to(mut self, tx: ProgressSender) -> Self {
+        self.mode = ProgressMode::None;
+        self.bars = None;
+        self.forward = Some(tx);
+        self
+    }
+
+    /// Also collect statistics about the run and write them to `path` as JSON when it
+    /// finishes. `counters` are those of the tiles an interrupted run already did.
+    pub fn with_report(mut self, path: PathBuf, counters: Counters) -> Self {
+        self.stats = Some(RunStats::new(path, counters));
+        self
+    }
+
+    fn log(&self, s: String) -> Result<()> {
+        match (&self.bars, self.mode) {
+            (Some(bars), _) => bars.m.println(s)?,
+            (None, ProgressMode::Json) => eprintln!("{}", json!({ "event": "log", "message": s })),
+            (None, _) => eprintln!("{s}"),
+        }
+        Ok(())
+    }
+
+    /// Print a progress line in plain or JSON mode.
+    fn report(&self, event: &str) {
+        if let Some(line) = self.line(event, self.started.elapsed().as_secs_f64()) {
+            eprintln!("{line}");
+        }
+    }
+
+    /// The progress line for `event` after `elapsed` seconds, in plain or JSON mode.
+    fn line(&self, event: &str, elapsed: f64) -> Option<String> {
+        let done = if self.writes {
+            self.written
+        } else {
+            self.processed
+        };
+        let rate = (elapsed > 0.0).then(|| (done - self.resumed) as f64 / elapsed);
+        let eta = rate
+            .filter(|&r| r > 0.0 && self.total >= done)
+            .map(|r| (self.total - done) as f64 / r);
+        match self.mode {
+            ProgressMode::Plain => {
+                let mut line = format!("processed {}/{}", self.processed, self.total);
+                if self.writes {
+                    line += &format!(", written {}/{}", self.written, self.total);
+                }
+                if let Some(rate) = rate {
+                    line += &format!(", {rate:.1} tiles/s");
+                }
+                if let Some(eta) = eta.filter(|_| event == "progress") {
+                    line += &format!(", eta {}s", eta.round());
+                }
+                Some(line)
+            }
+            ProgressMode::Json => Some(
+                json!({
+                    "event": event,
+                    "processed": self.processed,
+                    "written": self.writes.then_some(self.written),
+                    "total": self.total,
+                    "elapsed_seconds": elapsed,
+                    "tiles_per_second": rate,
+                    "eta_seconds": eta,
+                })
+                .to_string(),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn run(mut self, rx: Receiver<ProgressMsg>) -> Result<()> {
+        let _shown = self.bars.as_ref().map(|bars| ShownBars::new(&bars.m));
+        let mut last_report = Instant::now();
+        loop {
+            let msg = match rx.recv_timeout(REPORT_INTERVAL.saturating_sub(last_report.elapsed())) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => {
+                    self.report("progress");
+                    last_report = Instant::now();
+                    continue;
                 }
+                // Keep going after `Finished` until every stage is done, for their last
+                // messages
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Some(stats) = &mut self.stats {
+                stats.record(&msg);
+            }
+            if let Some(tx) = &self.forward {
+                // The caller may stop listening; the run carries on regardless
+                let _ = tx.send(msg);
+                continue;
+            }
+            match msg {
+                ProgressMsg::Log(s) => self.log(s)?,
                 ProgressMsg::UpdateCount(count) => {
-                    self.tile_written.set_length(count);
-                    self.tile_processed.set_length(count);
+                    self.total = count;
+                    if let Some(bars) = &self.bars {
+                        bars.tile_written.set_length(count);
+                        bars.tile_processed.set_length(count);
+                    }
                 }
-                ProgressMsg::Processed(tile) => {
-                    self.tile_processed.inc(1);
-                    let tile_str = format!("{:<14}", tile.to_string());
-                    self.tile_processed.set_message(tile_str);
+                ProgressMsg::Processed(tile) | ProgressMsg::Transformed { tile, .. } => {
+                    self.processed += 1;
+                    if let Some(bars) = &self.bars {
+                        bars.tile_processed.inc(1);
+                        let tile_str = format!("{:<14}", tile.to_string());
+                        bars.tile_processed.set_message(tile_str);
+                    }
                 }
                 ProgressMsg::Written(tile) => {
-                    self.tile_written.inc(1);
-                    let tile_str = format!("{:<14}", tile.to_string());
-                    self.tile_written.set_message(tile_str);
+                    self.written += 1;
+                    if let Some(bars) = &self.bars {
+                        bars.tile_written.inc(1);
+                        let tile_str = format!("{:<14}", tile.to_string());
+                        bars.tile_written.set_message(tile_str);
+                    }
                 }
+                ProgressMsg::StageTime(..) => {}
                 ProgressMsg::Finished() => {
-                    self.tile_written.finish();
-                    self.tile_processed.finish();
-                    break;
+                    if let Some(bars) = &self.bars {
+                        bars.tile_written.finish();
+                        bars.tile_processed.finish();
+                    }
+                    self.report("finished");
                 }
             }
         }
+        if let Some(stats) = &self.stats {
+            stats.write()?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn progress(mode: ProgressMode, processed: u64, written: u64) -> Progress {
+        let mut progress = Progress::new(mode);
+        progress.total = 100;
+        progress.processed = processed;
+        progress.written = written;
+        progress
+    }
+
+    #[test]
+    fn plain_lines() {
+        let p = progress(ProgressMode::Plain, 30, 25);
+        assert_eq!(
+            p.line("progress", 10.0).unwrap(),
+            "processed 30/100, written 25/100, 2.5 tiles/s, eta 30s"
+        );
+        // No ETA once done
+        assert_eq!(
+            p.line("finished", 10.0).unwrap(),
+            "processed 30/100, written 25/100, 2.5 tiles/s"
+        );
+        assert_eq!(
+            p.line("progress", 0.0).unwrap(),
+            "processed 30/100, written 25/100"
+        );
+
+        let read_only = progress(ProgressMode::Plain, 30, 0).without_writes();
+        assert_eq!(
+            read_only.line("progress", 10.0).unwrap(),
+            "processed 30/100, 3.0 tiles/s, eta 23s"
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let p = progress(ProgressMode::Json, 30, 25);
+        let line: Value = serde_json::from_str(&p.line("progress", 10.0).unwrap()).unwrap();
+        assert_eq!(
+            line,
+            json!({
+                "event": "progress",
+                "processed": 30,
+                "written": 25,
+                "total": 100,
+                "elapsed_seconds": 10.0,
+                "tiles_per_second": 2.5,
+                "eta_seconds": 30.0,
+            })
+        );
+
+        let read_only = progress(ProgressMode::Json, 30, 0).without_writes();
+        let line: Value = serde_json::from_str(&read_only.line("finished", 0.0).unwrap()).unwrap();
+        assert_eq!(line["written"], Value::Null);
+        assert_eq!(line["tiles_per_second"], Value::Null);
+    }
+
+    #[test]
+    fn resumed_runs_start_from_the_done_tiles() {
+        let mut p = Progress::new(ProgressMode::Plain).resumed_from(20);
+        p.total = 100;
+        assert_eq!(
+            p.line("progress", 0.0).unwrap(),
+            "processed 20/100, written 20/100"
+        );
+        // Only this run's tiles count towards the rate
+        p.processed += 10;
+        p.written += 5;
+        assert_eq!(
+            p.line("progress", 10.0).unwrap(),
+            "processed 30/100, written 25/100, 0.5 tiles/s, eta 150s"
+        );
+    }
+
+    #[test]
+    fn quiet_modes_print_nothing() {
+        assert!(
+            progress(ProgressMode::None, 30, 25)
+                .line("progress", 10.0)
+                .is_none()
+        );
+    }
+}
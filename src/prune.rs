@@ -0,0 +1,253 @@
+use std::{collections::BTreeMap, fmt::Display, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::{
+    dem::{FLOAT32_HEADER_LEN, FLOAT32_MAGIC},
+    mvt,
+    raster::{Raster, is_nodata},
+    stats::Counters,
+};
+
+/// Why a transformed tile was left out of the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// No pixel carries a value, or a vector tile has no features
+    Empty,
+    /// Every pixel has the same value
+    Constant,
+}
+
+/// Which transformed tiles to leave out of the output, so clients overzoom the parent instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PruneOptions {
+    pub empty: bool,
+    pub constant: bool,
+}
+
+impl PruneOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.empty || self.constant
+    }
+
+    /// Decide whether to drop a transformed tile. PNG and float32 tiles are inspected pixel by
+    /// pixel, and uncompressed vector tiles (`vector`) have their features counted; any other
+    /// tile is only dropped as empty when it has no data at all. `gsi` tells whether PNG tiles
+    /// are GSI DEM, whose no-data sentinel counts as empty.
+    pub fn check(&self, data: &[u8], gsi: bool, vector: bool) -> Result<Option<DropReason>> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+        let reason = if data.is_empty() {
+            Some(DropReason::Empty)
+        } else if vector {
+            let compressed = data.starts_with(GZIP_MAGIC);
+            (!compressed && mvt::feature_count(data)? == 0).then_some(DropReason::Empty)
+        } else if data.starts_with(PNG_SIGNATURE) {
+            let raster = Raster::decode_png(data)?;
+            classify(raster.data.chunks_exact(4), |px| is_nodata(px, gsi))
+        } else if data.starts_with(FLOAT32_MAGIC) {
+            // Compare bits, so NaN (no-data) samples count as equal to each other
+            let samples = data[FLOAT32_HEADER_LEN.min(data.len())..]
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+            classify(samples, |bits| f32::from_bits(*bits).is_nan())
+        } else {
+            None
+        };
+        Ok(reason.filter(|r| match r {
+            DropReason::Empty => self.empty,
+            DropReason::Constant => self.constant,
+        }))
+    }
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+
+fn classify<T: PartialEq>(
+    mut pixels: impl Iterator<Item = T>,
+    is_empty: impl Fn(&T) -> bool,
+) -> Option<DropReason> {
+    let first = pixels.next()?;
+    let mut empty = is_empty(&first);
+    for px in pixels {
+        if empty && !is_empty(&px) {
+            empty = false;
+        }
+        if !empty && px != first {
+            return None;
+        }
+    }
+    Some(if empty {
+        DropReason::Empty
+    } else {
+        DropReason::Constant
+    })
+}
+
+/// Number of dropped tiles per zoom level.
+#[derive(Default)]
+pub struct DropCounts {
+    /// zoom -> (empty, constant)
+    counts: Mutex<BTreeMap<u8, (u64, u64)>>,
+}
+
+impl DropCounts {
+    /// Start from the counts of the tiles an interrupted run already did.
+    pub fn from_counters(counters: &Counters) -> Self {
+        let counts = counters
+            .dropped()
+            .map(|(z, empty, constant)| (z, (empty, constant)))
+            .collect();
+        Self {
+            counts: Mutex::new(counts),
+        }
+    }
+
+    pub fn add(&self, z: u8, reason: DropReason) {
+        let mut counts = self.counts.lock().unwrap();
+        let (empty, constant) = counts.entry(z).or_default();
+        match reason {
+            DropReason::Empty => *empty += 1,
+            DropReason::Constant => *constant += 1,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.lock().unwrap().is_empty()
+    }
+}
+
+impl Display for DropCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Dropped tiles:")?;
+        writeln!(f, "{:>6} {:>12} {:>12}", "zoom", "empty", "constant")?;
+        for (z, (empty, constant)) in self.counts.lock().unwrap().iter() {
+            writeln!(f, "{z:>6} {empty:>12} {constant:>12}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mvt::{Layer, LineFeature};
+
+    const ALL: PruneOptions = PruneOptions {
+        empty: true,
+        constant: true,
+    };
+
+    fn png(pixels: &[[u8; 4]]) -> Vec<u8> {
+        Raster {
+            width: 2,
+            height: pixels.len() as u32 / 2,
+            data: pixels.concat(),
+        }
+        .encode_png()
+        .unwrap()
+        .to_vec()
+    }
+
+    #[test]
+    fn transparent_tiles_are_empty() {
+        let tile = png(&[[0, 0, 0, 0], [255, 255, 255, 0], [0, 0, 0, 0], [0, 0, 0, 0]]);
+        assert_eq!(
+            ALL.check(&tile, false, false).unwrap(),
+            Some(DropReason::Empty)
+        );
+    }
+
+    #[test]
+    fn gsi_nodata_is_empty_in_gsi_tiles_only() {
+        let tile = png(&[[0x80, 0, 0, 255]; 4]);
+        assert_eq!(
+            ALL.check(&tile, true, false).unwrap(),
+            Some(DropReason::Empty)
+        );
+        assert_eq!(
+            ALL.check(&tile, false, false).unwrap(),
+            Some(DropReason::Constant)
+        );
+        // No-data next to a value is neither
+        let mixed = png(&[
+            [0x80, 0, 0, 255],
+            [0, 0, 10, 255],
+            [0, 0, 10, 255],
+            [0, 0, 0, 0],
+        ]);
+        assert_eq!(ALL.check(&mixed, true, false).unwrap(), None);
+    }
+
+    #[test]
+    fn constant_tiles() {
+        let tile = png(&[[1, 134, 160, 255]; 4]);
+        assert_eq!(
+            ALL.check(&tile, false, false).unwrap(),
+            Some(DropReason::Constant)
+        );
+        let only_empty = PruneOptions {
+            empty: true,
+            constant: false,
+        };
+        assert_eq!(only_empty.check(&tile, false, false).unwrap(), None);
+    }
+
+    #[test]
+    fn mixed_tiles_are_kept() {
+        let tile = png(&[
+            [1, 134, 160, 255],
+            [1, 134, 170, 255],
+            [0, 0, 0, 0],
+            [0, 0, 0, 0],
+        ]);
+        assert_eq!(ALL.check(&tile, false, false).unwrap(), None);
+    }
+
+    #[test]
+    fn float32_tiles() {
+        let tile = |samples: &[f32]| {
+            let mut data = vec![0; FLOAT32_HEADER_LEN];
+            data[..FLOAT32_MAGIC.len()].copy_from_slice(FLOAT32_MAGIC);
+            data.extend(samples.iter().flat_map(|v| v.to_le_bytes()));
+            data
+        };
+        let empty = tile(&[f32::NAN; 4]);
+        assert_eq!(
+            ALL.check(&empty, false, false).unwrap(),
+            Some(DropReason::Empty)
+        );
+        let constant = tile(&[5.0; 4]);
+        assert_eq!(
+            ALL.check(&constant, false, false).unwrap(),
+            Some(DropReason::Constant)
+        );
+        let mixed = tile(&[5.0, 5.0, f32::NAN, 6.0]);
+        assert_eq!(ALL.check(&mixed, false, false).unwrap(), None);
+    }
+
+    #[test]
+    fn vector_tiles_without_features_are_empty() {
+        let line = LineFeature {
+            lines: vec![vec![(0, 0), (10, 0)]],
+            properties: Vec::new(),
+        };
+        let tile = mvt::encode_tile(&[Layer {
+            name: "contour".into(),
+            extent: 4096,
+            features: vec![line],
+        }]);
+        assert_eq!(ALL.check(&tile, false, true).unwrap(), None);
+        // One layer, named "a", without features
+        let featureless = b"\x1a\x03\x0a\x01a";
+        assert_eq!(
+            ALL.check(featureless, false, true).unwrap(),
+            Some(DropReason::Empty)
+        );
+        // Compressed tiles aren't decoded
+        let gzipped = b"\x1f\x8b\x08\x00\x00\x00\x00\x00";
+        assert_eq!(ALL.check(gzipped, false, true).unwrap(), None);
+    }
+}
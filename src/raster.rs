@@ -0,0 +1,119 @@
+use std::io::Cursor;
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use png::{BitDepth, ColorType, Decoder, Encoder, Transformations};
+
+/// A decoded tile, normalized to 8-bit RGBA.
+#[derive(Clone, Debug)]
+pub struct Raster {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+impl Raster {
+    /// Decode a PNG of any color type into RGBA8.
+    pub fn decode_png(input: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(Cursor::new(input));
+        decoder.set_transformations(Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().context("read png info")?;
+        let mut buf = vec![0u8; reader.output_buffer_size().context("PNG too large")?];
+        let info = reader.next_frame(&mut buf).context("decode frame")?;
+        buf.truncate(info.buffer_size());
+
+        let data = match info.color_type {
+            ColorType::Rgba => buf,
+            ColorType::Rgb => buf
+                .chunks_exact(3)
+                .flat_map(|s| [s[0], s[1], s[2], 255])
+                .collect(),
+            ColorType::GrayscaleAlpha => buf
+                .chunks_exact(2)
+                .flat_map(|s| [s[0], s[0], s[0], s[1]])
+                .collect(),
+            ColorType::Grayscale => buf.iter().flat_map(|&v| [v, v, v, 255]).collect(),
+            ColorType::Indexed => bail!("indexed PNG was not expanded"),
+        };
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            data,
+        })
+    }
+
+    pub fn encode_png(&self) -> Result<Bytes> {
+        let mut out = Vec::with_capacity(self.data.len() + 1024);
+        {
+            let mut enc = Encoder::new(&mut out, self.width, self.height);
+            enc.set_color(ColorType::Rgba);
+            enc.set_depth(BitDepth::Eight);
+            let mut writer = enc.write_header().context("write header")?;
+            writer
+                .write_image_data(&self.data)
+                .context("encode image data")?;
+        }
+        Ok(out.into())
+    }
+
+    #[inline]
+    pub fn pixel(&self, x: u32, y: u32) -> &[u8] {
+        let i = ((y * self.width + x) * 4) as usize;
+        &self.data[i..i + 4]
+    }
+
+    #[inline]
+    pub fn pixel_mut(&mut self, x: u32, y: u32) -> &mut [u8] {
+        let i = ((y * self.width + x) * 4) as usize;
+        &mut self.data[i..i + 4]
+    }
+}
+
+/// A decoded single-band tile at full precision (up to 16 bits per sample).
+#[derive(Clone, Debug)]
+pub struct GrayRaster {
+    pub width: u32,
+    pub height: u32,
+    /// Sample values; `None` where the pixel is fully transparent
+    pub values: Vec<Option<u16>>,
+}
+
+impl GrayRaster {
+    /// Decode a grayscale or grayscale+alpha PNG of any bit depth. Samples below 8 bits are
+    /// expanded to 8 bits; 16-bit samples are kept as-is.
+    pub fn decode_png(input: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(Cursor::new(input));
+        decoder.set_transformations(Transformations::EXPAND);
+        let mut reader = decoder.read_info().context("read png info")?;
+        let mut buf = vec![0u8; reader.output_buffer_size().context("PNG too large")?];
+        let info = reader.next_frame(&mut buf).context("decode frame")?;
+
+        let channels = match info.color_type {
+            ColorType::Grayscale => 1,
+            ColorType::GrayscaleAlpha => 2,
+            other => bail!("expected a grayscale PNG, got: {other:?}"),
+        };
+        let sample = |i: usize| match info.bit_depth {
+            BitDepth::Sixteen => u16::from_be_bytes([buf[i * 2], buf[i * 2 + 1]]),
+            _ => buf[i] as u16,
+        };
+        let values = (0..(info.width * info.height) as usize)
+            .map(|p| {
+                let transparent = channels == 2 && sample(p * 2 + 1) == 0;
+                (!transparent).then(|| sample(p * channels))
+            })
+            .collect();
+        Ok(Self {
+            width: info.width,
+            height: info.height,
+            values,
+        })
+    }
+}
+
+/// True for pixels that carry no value: fully transparent, or in GSI DEM tiles (`gsi`) the
+/// no-data sentinel (0x800000). Other tiles may use that color for real pixels.
+#[inline]
+pub fn is_nodata(px: &[u8], gsi: bool) -> bool {
+    px[3] == 0 || (gsi && px[0] == 0x80 && px[1] == 0 && px[2] == 0)
+}
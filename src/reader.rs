@@ -1,110 +1,683 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use bytes::Bytes;
 use flume::Sender;
-use futures_util::TryStreamExt;
-use pmtiles::{AsyncPmTilesReader, MmapBackend};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use futures_util::{StreamExt, TryStreamExt, stream, stream::BoxStream};
+use pmtiles::{
+    AsyncBackend, AsyncPmTilesReader, MmapBackend, PmtResult, S3Backend, TileCoord, TileId,
+    TileType,
+};
+use serde_json::json;
 use tokio::task::JoinSet;
+use tracing::Instrument;
 
 use crate::{
-    progress::{ProgressMsg, ProgressSender},
+    bucket,
+    budget::{MemoryBudget, Reservation},
+    composite::{self, FillSource, FillTile},
+    dedupe::{CacheSlot, Cached, TileCache},
+    location::Location,
+    progress::{ProgressMsg, ProgressSender, Stage},
     tile::Tile,
+    transform::Neighbors,
+    writer::OutputHeader,
 };
 
-pub type PmTilesReader = Arc<AsyncPmTilesReader<MmapBackend>>;
+pub type PmTilesReader = Arc<AsyncPmTilesReader<Backend>>;
+
+/// The storage an input archive is read from.
+pub enum Backend {
+    Mmap(MmapBackend),
+    /// Range requests against an S3-compatible bucket
+    S3(S3Backend),
+}
+
+impl AsyncBackend for Backend {
+    async fn read(&self, offset: usize, length: usize) -> PmtResult<Bytes> {
+        match self {
+            Backend::Mmap(b) => b.read(offset, length).await,
+            Backend::S3(b) => b.read(offset, length).await,
+        }
+    }
+}
+
+/// How to resolve a tile that is present in more than one input.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Use the tile from the first input listed
+    #[default]
+    First,
+    /// Use the tile from the last input listed
+    Last,
+    /// Start from the first input's tile and fill its no-data/transparent pixels from the
+    /// following inputs
+    Composite,
+}
 
 pub struct ReadTileMsg {
     pub index: usize,
     pub tile: Tile,
     pub tile_data: Bytes,
+    /// Tiles from other inputs and fallbacks used to fill no-data pixels in `tile_data`, in
+    /// order.
+    pub fill: Vec<FillTile>,
+    /// Adjacent tiles, only fetched when the transform asks for them
+    pub neighbors: Neighbors,
+    /// This tile's share of the memory budget, held until it is written
+    pub reservation: Reservation,
+    /// Set when the tile's input may repeat. Tiles that aren't the slot's owner have empty
+    /// `tile_data` and take the owner's result.
+    pub cached: Option<CacheSlot>,
 }
 
-pub struct Reader {
-    input: PathBuf,
+/// What the reader sends: every tile from the start index on, then the end of the input.
+pub enum ReadMsg {
+    Tile(ReadTileMsg),
+    /// Every tile has been read: indexes up to `count`, including those of a previous run.
+    End {
+        count: usize,
+    },
+}
+
+struct Source {
+    input: Location,
     reader: PmTilesReader,
 }
 
+pub struct Reader {
+    sources: Vec<Source>,
+    /// Archives that only fill no-data pixels; they don't contribute tiles of their own.
+    fallbacks: Vec<Source>,
+    on_conflict: ConflictPolicy,
+    /// Also read each tile's [`Neighbors`]
+    neighbors: bool,
+    /// Transform repeated input tiles once and reuse the result
+    dedupe: bool,
+    /// Whether the inputs are GSI DEM, whose no-data sentinel needs filling like
+    /// transparent pixels
+    gsi_nodata: bool,
+}
+
+/// Open a PMTiles archive on the local filesystem or in S3.
+pub async fn open(input: &Location) -> Result<PmTilesReader> {
+    let backend = match input {
+        Location::Local(path) => Backend::Mmap(MmapBackend::try_from(path).await?),
+        Location::S3 { bucket: name, key } => {
+            Backend::S3(S3Backend::from(*bucket::open(name)?, key.clone()))
+        }
+    };
+    let reader = AsyncPmTilesReader::try_from_source(backend)
+        .await
+        .with_context(|| format!("Failed to open {input}"))?;
+    Ok(Arc::new(reader))
+}
+
+/// Number of recently seen input tiles whose results are kept for reuse
+const CACHE_CAPACITY: usize = 1024;
+
+/// The archive's tile coordinates in tile ID order with the offset of their data, streamed
+/// from its directories. Runs of repeated tiles are expanded lazily.
+pub fn coords_in_order(reader: PmTilesReader) -> BoxStream<'static, Result<(TileCoord, u64)>> {
+    reader
+        .entries()
+        .map_err(anyhow::Error::from)
+        .map_ok(|e| {
+            stream::iter((0..u64::from(e.run_length)).map(move |i| {
+                let coord = TileCoord::from(TileId::new(e.tile_id + i)?);
+                Ok::<_, anyhow::Error>((coord, e.offset))
+            }))
+        })
+        .try_flatten()
+        .boxed()
+}
+
+/// Number of recently read fallback tiles kept, found or not
+const FALLBACK_CACHE_CAPACITY: usize = 256;
+
+/// The fallback archives, with the tiles recently read from them. Tiles in tile ID order are
+/// grouped by ancestor, so an ancestor filling many tiles is read and decoded once.
+struct Fallbacks {
+    readers: Vec<PmTilesReader>,
+    /// `(fallback index, tile ID)` to the tile, oldest first for eviction
+    cache: Mutex<(
+        HashMap<(usize, u64), Option<Arc<FillSource>>>,
+        VecDeque<(usize, u64)>,
+    )>,
+}
+
+impl Fallbacks {
+    fn new(readers: Vec<PmTilesReader>) -> Self {
+        Self {
+            readers,
+            cache: Mutex::default(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.readers.is_empty()
+    }
+
+    async fn get(&self, fallback: usize, coord: TileCoord) -> Result<Option<Arc<FillSource>>> {
+        let key = (fallback, TileId::from(coord).value());
+        if let Some(tile) = self.cache.lock().unwrap().0.get(&key) {
+            return Ok(tile.clone());
+        }
+        let tile = self.readers[fallback]
+            .get_tile(coord)
+            .await?
+            .map(FillSource::new);
+        let mut cache = self.cache.lock().unwrap();
+        let (tiles, order) = &mut *cache;
+        if tiles.insert(key, tile.clone()).is_none() {
+            order.push_back(key);
+            if order.len() > FALLBACK_CACHE_CAPACITY {
+                let oldest = order.pop_front().unwrap();
+                tiles.remove(&oldest);
+            }
+        }
+        Ok(tile)
+    }
+
+    /// The tile covering `coord` in each fallback, in order: the tile itself, or failing that
+    /// the nearest ancestor within the archive's zoom range.
+    async fn covering(&self, coord: TileCoord) -> Result<Vec<FillTile>> {
+        let mut fill = Vec::new();
+        for (i, reader) in self.readers.iter().enumerate() {
+            let header = reader.get_header();
+            let first = coord.z().saturating_sub(header.max_zoom);
+            let last = coord.z().saturating_sub(header.min_zoom);
+            for dz in first..=last {
+                let ancestor = TileCoord::new(coord.z() - dz, coord.x() >> dz, coord.y() >> dz)?;
+                if let Some(source) = self.get(i, ancestor).await? {
+                    let mask = (1u32 << dz) - 1;
+                    fill.push(FillTile {
+                        source,
+                        dz,
+                        dx: coord.x() & mask,
+                        dy: coord.y() & mask,
+                    });
+                    break;
+                }
+            }
+        }
+        Ok(fill)
+    }
+}
+
+/// The inputs and fallbacks as the read workers see them.
+struct TileSources {
+    readers: Vec<PmTilesReader>,
+    fallbacks: Fallbacks,
+    on_conflict: ConflictPolicy,
+    gsi_nodata: bool,
+}
+
+impl TileSources {
+    /// Read `coord` from up to `limit` of `inputs` that have it, in order. The first tile
+    /// read is the base, and the rest fill its no-data pixels, followed by the fallbacks if
+    /// there is anything left to fill.
+    async fn read(
+        &self,
+        coord: TileCoord,
+        inputs: impl IntoIterator<Item = usize>,
+        limit: usize,
+    ) -> Result<Option<(Bytes, Vec<FillTile>)>> {
+        let mut tiles = Vec::new();
+        for input in inputs {
+            if tiles.len() == limit {
+                break;
+            }
+            if let Some(data) = self.readers[input].get_tile(coord).await? {
+                tiles.push(data);
+            }
+        }
+        let mut tiles = tiles.into_iter();
+        let Some(base) = tiles.next() else {
+            return Ok(None);
+        };
+        let mut fill = tiles.map(FillTile::same_zoom).collect::<Vec<_>>();
+        // Fallbacks are only read for tiles with something to fill
+        if !self.fallbacks.is_empty() {
+            let data = base.clone();
+            let gsi = self.gsi_nodata;
+            let gaps =
+                tokio::task::spawn_blocking(move || composite::has_nodata(&data, gsi)).await??;
+            if gaps {
+                fill.extend(self.fallbacks.covering(coord).await?);
+            }
+        }
+        Ok(Some((base, fill)))
+    }
+
+    /// The tile at `coord` as the transformer would see it: picked by the conflict policy
+    /// and filled from the other inputs and the fallbacks.
+    async fn composited(&self, coord: TileCoord) -> Result<Option<Bytes>> {
+        let n = self.readers.len();
+        let read = match self.on_conflict {
+            ConflictPolicy::First => self.read(coord, 0..n, 1).await?,
+            ConflictPolicy::Last => self.read(coord, (0..n).rev(), 1).await?,
+            ConflictPolicy::Composite => self.read(coord, 0..n, n).await?,
+        };
+        let Some((base, fill)) = read else {
+            return Ok(None);
+        };
+        if fill.is_empty() {
+            return Ok(Some(base));
+        }
+        let gsi = self.gsi_nodata;
+        let data = tokio::task::spawn_blocking(move || composite::fill_nodata(&base, &fill, gsi))
+            .await??;
+        Ok(Some(data))
+    }
+
+    /// Read the east, south and south-east neighbors of `coord`. The east neighbor wraps
+    /// around the antimeridian; there is nothing south of the bottom row.
+    async fn neighbors(&self, coord: TileCoord) -> Result<Neighbors> {
+        let n = 1u32 << coord.z();
+        let east_x = (coord.x() + 1) % n;
+        let mut neighbors = Neighbors {
+            east: self
+                .composited(TileCoord::new(coord.z(), east_x, coord.y())?)
+                .await?,
+            ..Default::default()
+        };
+        if coord.y() + 1 < n {
+            let south_y = coord.y() + 1;
+            neighbors.south = self
+                .composited(TileCoord::new(coord.z(), coord.x(), south_y)?)
+                .await?;
+            neighbors.south_east = self
+                .composited(TileCoord::new(coord.z(), east_x, south_y)?)
+                .await?;
+        }
+        Ok(neighbors)
+    }
+}
+
 impl Reader {
-    pub async fn new(input: PathBuf) -> Result<Self> {
-        let reader = AsyncPmTilesReader::new_with_path(&input).await?;
+    pub async fn new(
+        inputs: Vec<Location>,
+        fallbacks: Vec<Location>,
+        on_conflict: ConflictPolicy,
+        neighbors: bool,
+    ) -> Result<Self> {
+        let mut sources = Vec::with_capacity(inputs.len());
+        for input in inputs {
+            let reader = open(&input).await?;
+            sources.push(Source { input, reader });
+        }
+        let mut fallback_sources = Vec::with_capacity(fallbacks.len());
+        for input in fallbacks {
+            let reader = open(&input).await?;
+            fallback_sources.push(Source { input, reader });
+        }
+        // Only PNG tiles can be composited; float32 DEM tiles have no transparency to fill
+        if !fallback_sources.is_empty() {
+            for source in sources.iter().chain(&fallback_sources) {
+                let tile_type = source.reader.get_header().tile_type;
+                if tile_type != TileType::Png {
+                    bail!(
+                        "fallbacks can only fill PNG tiles, but {} has {tile_type:?} tiles",
+                        source.input
+                    );
+                }
+            }
+        }
         Ok(Self {
-            input,
-            reader: Arc::new(reader),
+            sources,
+            fallbacks: fallback_sources,
+            on_conflict,
+            neighbors,
+            dedupe: true,
+            gsi_nodata: false,
         })
     }
 
+    /// Whether the inputs are GSI DEM tiles, so that their no-data sentinel is filled from
+    /// the fallbacks as well as transparent pixels.
+    pub fn with_gsi_nodata(mut self, gsi: bool) -> Self {
+        self.gsi_nodata = gsi;
+        self
+    }
+
+    /// Whether repeated input tiles may share one transformed result. Turn this off for
+    /// transforms whose output depends on the tile's coordinate.
+    pub fn with_dedupe(mut self, dedupe: bool) -> Self {
+        self.dedupe = dedupe;
+        self
+    }
+
+    /// The input archives, in the order given.
+    pub fn inputs(&self) -> impl Iterator<Item = (&Location, &PmTilesReader)> {
+        self.sources.iter().map(|s| (&s.input, &s.reader))
+    }
+
+    /// Read every tile from reorder index `start` on; earlier tiles were already written by
+    /// a previous run. Tiles are held back while `budget` is full.
+    #[tracing::instrument(name = "read", skip(self, budget, tile_tx, progress_tx))]
     pub async fn run(
         self,
-        tile_tx: Sender<ReadTileMsg>,
+        start: usize,
+        budget: Arc<MemoryBudget>,
+        tile_tx: Sender<ReadMsg>,
         progress_tx: ProgressSender,
     ) -> Result<()> {
-        let entries = self
-            .reader
-            .clone()
-            .entries()
-            .try_collect::<Vec<_>>()
-            .await?;
-        let mut coords = entries
-            .iter()
-            .flat_map(|e| e.iter_coords())
-            .collect::<Vec<_>>();
-        coords.sort_unstable();
-        let coords_count = coords.len();
-        progress_tx.send(ProgressMsg::UpdateCount(coords_count as u64))?;
-        progress_tx.send(ProgressMsg::Log(format!(
-            "Found {} tiles in the input: {}",
-            coords_count,
-            self.input.display()
-        )))?;
+        // Inputs are merged by walking their directories side by side in tile ID order, the
+        // order PMTiles stores them in, so reading starts right away and memory use doesn't
+        // grow with the number of tiles. The output comes out in tile ID order too.
+        let mut streams = Vec::with_capacity(self.sources.len());
+        let mut heads = Vec::with_capacity(self.sources.len());
+        let mut addressed = 0;
+        for source in &self.sources {
+            if let Some(n) = source.reader.get_header().n_addressed_tiles {
+                addressed += n.get();
+                progress_tx.send(ProgressMsg::Log(format!(
+                    "Found {} tiles in the input: {}",
+                    n, source.input
+                )))?;
+            }
+            let mut stream = coords_in_order(source.reader.clone());
+            heads.push(stream.try_next().await?);
+            streams.push(stream);
+        }
+        // An upper bound when inputs overlap; corrected once they have all been read
+        progress_tx.send(ProgressMsg::UpdateCount(addressed))?;
 
         // Fetch tiles concurrently with a fixed-size async worker pool to avoid per-tile task overhead.
         let concurrency = std::thread::available_parallelism()
             .map(|n| n.get())
             .unwrap_or(4);
 
-        let coords = Arc::new(coords);
-        let len = coords.len();
-        let next_index = Arc::new(AtomicUsize::new(0));
+        let tile_sources = Arc::new(TileSources {
+            readers: self.sources.iter().map(|s| s.reader.clone()).collect(),
+            fallbacks: Fallbacks::new(self.fallbacks.iter().map(|s| s.reader.clone()).collect()),
+            on_conflict: self.on_conflict,
+            gsi_nodata: self.gsi_nodata,
+        });
+        // (index, coordinate, (input, offset) of each input to read the tile from, cache slot)
+        let (work_tx, work_rx) =
+            flume::bounded::<(usize, TileCoord, Vec<(usize, u64)>, Option<CacheSlot>)>(
+                concurrency * 4,
+            );
+        // Repeated input tiles are only transformed once. Fallbacks and neighbors depend on
+        // the coordinate, so tiles using them can't be shared.
+        let cache = (self.dedupe && self.fallbacks.is_empty() && !self.neighbors)
+            .then(|| TileCache::new(CACHE_CAPACITY));
+        let mut reused = 0u64;
 
         let mut join_set: JoinSet<anyhow::Result<()>> = JoinSet::new();
         for _ in 0..concurrency {
-            let reader = self.reader.clone();
+            let tile_sources = tile_sources.clone();
             let tile_tx = tile_tx.clone();
-            let coords = coords.clone();
-            let next_index = next_index.clone();
-            join_set.spawn(async move {
-                loop {
-                    let i = next_index.fetch_add(1, Ordering::Relaxed);
-                    if i >= len {
-                        break;
+            let work_rx = work_rx.clone();
+            let with_neighbors = self.neighbors;
+            let budget = budget.clone();
+            let progress_tx = progress_tx.clone();
+            let worker = async move {
+                let mut busy = Duration::ZERO;
+                while let Ok((i, coord, sources, cached)) = work_rx.recv_async().await {
+                    if let Some(reuse) = cached.as_ref().filter(|c| !c.owner) {
+                        reuse.slot.wait_queued().await;
+                        tile_tx
+                            .send_async(ReadMsg::Tile(ReadTileMsg {
+                                index: i,
+                                tile: coord.into(),
+                                tile_data: Bytes::new(),
+                                fill: Vec::new(),
+                                neighbors: Neighbors::default(),
+                                reservation: budget.reserve(i, 0).await,
+                                cached,
+                            }))
+                            .await?;
+                        continue;
                     }
-                    // Assuming coords are Copy; if not, change to clone()
-                    let coord = coords[i];
-                    if let Some(tile_data) = reader.get_tile(coord).await? {
+                    let owned = cached.as_ref().map(|c| c.slot.clone());
+                    let span = tracing::trace_span!(
+                        "read_tile",
+                        tile = %Tile::from(coord),
+                        bytes = tracing::field::Empty,
+                    );
+                    let sent = async {
+                        let started = Instant::now();
+                        let inputs = sources.iter().map(|&(source, _)| source);
+                        // Every index has to reach the writer, or it would wait for it forever
+                        let Some((tile_data, fill)) =
+                            tile_sources.read(coord, inputs, sources.len()).await?
+                        else {
+                            bail!(
+                                "tile {} is listed in the directory but could not be read",
+                                Tile::from(coord)
+                            );
+                        };
+                        let neighbors = if with_neighbors {
+                            tile_sources.neighbors(coord).await?
+                        } else {
+                            Neighbors::default()
+                        };
+                        let bytes = tile_data.len()
+                            + fill.iter().map(|f| f.source.data.len()).sum::<usize>()
+                            + [&neighbors.east, &neighbors.south, &neighbors.south_east]
+                                .into_iter()
+                                .flatten()
+                                .map(|d| d.len())
+                                .sum::<usize>();
+                        busy += started.elapsed();
+                        tracing::Span::current().record("bytes", bytes);
+                        let reservation = budget.reserve(i, bytes as u64).await;
                         tile_tx
-                            .send_async(ReadTileMsg {
+                            .send_async(ReadMsg::Tile(ReadTileMsg {
                                 index: i,
                                 tile: coord.into(),
                                 tile_data,
-                            })
+                                fill,
+                                neighbors,
+                                reservation,
+                                cached,
+                            }))
                             .await?;
+                        anyhow::Ok(())
+                    }
+                    .instrument(span)
+                    .await;
+                    if let Some(slot) = owned {
+                        if let Err(e) = &sent {
+                            // Don't leave the tiles reusing this one waiting
+                            slot.set(Cached {
+                                input: Bytes::new(),
+                                output: Err(format!("{e:#}")),
+                            });
+                        }
+                        slot.mark_queued();
                     }
+                    sent?;
+                }
+                progress_tx.send(ProgressMsg::StageTime(Stage::Read, busy))?;
+                anyhow::Ok(())
+            };
+            join_set.spawn(worker.in_current_span());
+        }
+        drop(work_rx);
+
+        let mut index = 0;
+        let tile_id = |(c, _): &(TileCoord, u64)| TileId::from(*c).value();
+        while let Some(min) = heads.iter().flatten().map(tile_id).min() {
+            let mut coord = None;
+            let mut group = Vec::new();
+            for (i, head) in heads.iter_mut().enumerate() {
+                if let Some((c, offset)) = head.filter(|h| tile_id(h) == min) {
+                    coord = Some(c);
+                    group.push((i, offset));
+                    *head = streams[i].try_next().await?;
+                }
+            }
+            let coord = coord.unwrap();
+            // Tiles before `start` were written by a previous run
+            if index >= start {
+                let sources = match self.on_conflict {
+                    ConflictPolicy::First => group[..1].to_vec(),
+                    ConflictPolicy::Last => group[group.len() - 1..].to_vec(),
+                    ConflictPolicy::Composite => group,
+                };
+                let cached = cache.as_ref().map(|c| c.get(sources.clone()));
+                if cached.as_ref().is_some_and(|c| !c.owner) {
+                    reused += 1;
                 }
-                Ok(())
-            });
+                if work_tx
+                    .send_async((index, coord, sources, cached))
+                    .await
+                    .is_err()
+                {
+                    // The workers have stopped; their error is reported below
+                    break;
+                }
+            }
+            index += 1;
+        }
+        drop(work_tx);
+        progress_tx.send(ProgressMsg::UpdateCount(index as u64))?;
+        if self.sources.len() > 1 {
+            progress_tx.send(ProgressMsg::Log(format!(
+                "{} unique tiles after merging {} inputs",
+                index,
+                self.sources.len()
+            )))?;
         }
 
         // Await all workers
         while let Some(res) = join_set.join_next().await {
             res??;
         }
+        if reused > 0 {
+            progress_tx.send(ProgressMsg::Log(format!(
+                "Reused the transformed data of {reused} repeated tiles"
+            )))?;
+        }
+        tracing::debug!(tiles = index, reused, "finished reading");
+        tile_tx.send_async(ReadMsg::End { count: index }).await?;
 
         Ok(())
     }
 
-    pub fn pmtiles_reader(&self) -> Arc<AsyncPmTilesReader<MmapBackend>> {
-        self.reader.clone()
+    /// Identifies the inputs, to check that a checkpoint belongs to them: each archive's
+    /// header and metadata, plus the file size and modification time, or the S3 ETag.
+    pub async fn fingerprint(&self) -> Result<serde_json::Value> {
+        let mut inputs = Vec::new();
+        for source in self.sources.iter().chain(&self.fallbacks) {
+            let header = OutputHeader::from_reader(&source.reader).await?;
+            let version = match &source.input {
+                Location::Local(path) => {
+                    let meta = std::fs::metadata(path)?;
+                    let modified = meta
+                        .modified()?
+                        .duration_since(std::time::UNIX_EPOCH)?
+                        .as_nanos();
+                    json!({ "size": meta.len(), "modified": modified.to_string() })
+                }
+                Location::S3 { bucket: name, key } => {
+                    json!({ "etag": bucket::etag(&*bucket::open(name)?, key).await? })
+                }
+            };
+            inputs.push(json!({
+                "input": source.input.to_string(),
+                "header": format!("{header:?}"),
+                "version": version,
+            }));
+        }
+        Ok(json!(inputs))
+    }
+
+    /// Header and metadata for the output, covering the zoom range and bounds of every input.
+    pub async fn output_header(&self) -> Result<OutputHeader> {
+        let mut header = OutputHeader::from_reader(&self.sources[0].reader).await?;
+        for source in &self.sources[1..] {
+            let other = OutputHeader::from_reader(&source.reader).await?;
+            header
+                .merge(&other)
+                .with_context(|| format!("while merging {}", source.input))?;
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::Path};
+
+    use pmtiles::PmTilesWriter;
+
+    use super::*;
+
+    fn archive(path: &Path, tile_type: TileType, zooms: (u8, u8), tile: TileCoord) -> Location {
+        let mut writer = PmTilesWriter::new(tile_type)
+            .min_zoom(zooms.0)
+            .max_zoom(zooms.1)
+            .create(File::create(path).unwrap())
+            .unwrap();
+        writer.add_tile(tile, b"tile").unwrap();
+        writer.finalize().unwrap();
+        Location::Local(path.to_path_buf())
+    }
+
+    fn coord(z: u8, x: u32, y: u32) -> TileCoord {
+        TileCoord::new(z, x, y).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fallbacks_cover_from_the_nearest_ancestor() {
+        let dir = tempfile::tempdir().unwrap();
+        let location = archive(
+            &dir.path().join("fb.pmtiles"),
+            TileType::Png,
+            (0, 1),
+            coord(1, 0, 0),
+        );
+        let fallbacks = Fallbacks::new(vec![open(&location).await.unwrap()]);
+
+        let fill = fallbacks.covering(coord(1, 0, 0)).await.unwrap();
+        assert_eq!(fill.len(), 1);
+        assert_eq!((fill[0].dz, fill[0].dx, fill[0].dy), (0, 0, 0));
+        assert_eq!(fill[0].source.data, &b"tile"[..]);
+
+        let fill = fallbacks.covering(coord(3, 1, 2)).await.unwrap();
+        assert_eq!(fill.len(), 1);
+        assert_eq!((fill[0].dz, fill[0].dx, fill[0].dy), (2, 1, 2));
+
+        // Neither the z1 ancestor nor the z0 one exists
+        assert!(fallbacks.covering(coord(3, 7, 7)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn fallbacks_need_png_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = archive(
+            &dir.path().join("in.pmtiles"),
+            TileType::Unknown,
+            (0, 0),
+            coord(0, 0, 0),
+        );
+        let fallback = archive(
+            &dir.path().join("fb.pmtiles"),
+            TileType::Png,
+            (0, 0),
+            coord(0, 0, 0),
+        );
+        let err = Reader::new(
+            vec![input.clone()],
+            vec![fallback],
+            ConflictPolicy::First,
+            false,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!("fallbacks can only fill PNG tiles, but {input} has Unknown tiles")
+        );
     }
 }
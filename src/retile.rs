@@ -0,0 +1,511 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use flume::{Receiver, Sender};
+use futures_util::TryStreamExt;
+use pmtiles::{TileCoord, TileId};
+use rayon::prelude::*;
+use tokio::task::JoinSet;
+
+use crate::{
+    QUEUE_CAPACITY,
+    dem::DemGrid,
+    ingest::TileCodec,
+    location::Location,
+    progress::{Progress, ProgressMode, ProgressMsg, ProgressSender},
+    raster::Raster,
+    reader::{self, PmTilesReader},
+    transform::NoDataPolicy,
+    writer::{self, OutputHeader, WriteMsg, WriteTileMsg, Writer},
+};
+
+/// Input tiles read together, and the output tiles made from them.
+pub struct SiblingGroup {
+    /// Merging: the four children in `(0, 0), (1, 0), (0, 1), (1, 1)` order. Splitting: the
+    /// parent alone.
+    sources: Vec<Option<Bytes>>,
+    /// Output indexes and coordinates, in tile ID order
+    outputs: Vec<(usize, TileCoord)>,
+}
+
+/// What [`Retile::read`] sends: every sibling group, then the number of output tiles.
+pub enum GroupMsg {
+    Group(SiblingGroup),
+    End { count: usize },
+}
+
+/// Converts between 256px and 512px tiles: 2x2 children at zoom z+1 are combined into one
+/// tile at zoom z, or each tile is split into its four children at zoom z+1.
+pub struct Retile {
+    input: Location,
+    reader: PmTilesReader,
+    codec: TileCodec,
+    from_size: u32,
+    to_size: u32,
+}
+
+/// The children of `coord` in `(0, 0), (1, 0), (0, 1), (1, 1)` order.
+fn children(coord: TileCoord) -> Result<[TileCoord; 4]> {
+    let (z, x, y) = (coord.z() + 1, coord.x() * 2, coord.y() * 2);
+    Ok([
+        TileCoord::new(z, x, y)?,
+        TileCoord::new(z, x + 1, y)?,
+        TileCoord::new(z, x, y + 1)?,
+        TileCoord::new(z, x + 1, y + 1)?,
+    ])
+}
+
+/// The key of every sibling group, in tile ID order: the parents of the input tiles when
+/// merging, or the input tiles themselves when splitting.
+fn group_keys(coords: impl Iterator<Item = TileCoord>, merging: bool) -> Result<Vec<TileCoord>> {
+    let mut keys = if merging {
+        coords
+            .filter(|c| c.z() > 0)
+            .map(|c| TileCoord::new(c.z() - 1, c.x() / 2, c.y() / 2))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        coords.collect::<Vec<_>>()
+    };
+    keys.sort_unstable_by_key(|c| TileId::from(*c).value());
+    keys.dedup();
+    Ok(keys)
+}
+
+/// The input tiles to read for the `i`th group, keyed by `key`, and the output indexes and
+/// coordinates made from them.
+fn group_tiles(
+    i: usize,
+    key: TileCoord,
+    merging: bool,
+) -> Result<(Vec<TileCoord>, Vec<(usize, TileCoord)>)> {
+    if merging {
+        return Ok((children(key)?.to_vec(), vec![(i, key)]));
+    }
+    // A tile's children are consecutive in tile ID order
+    let mut children = children(key)?;
+    children.sort_unstable_by_key(|c| TileId::from(*c).value());
+    let outputs = children
+        .into_iter()
+        .enumerate()
+        .map(|(k, c)| (i * 4 + k, c))
+        .collect();
+    Ok((vec![key], outputs))
+}
+
+/// Place four `s`x`s` children, in [`children`] order, into one `2s`x`2s` tile. Missing
+/// children are left `empty`.
+fn merge_pixels<T: Copy>(children: &[Option<&[T]>], s: usize, empty: T) -> Vec<T> {
+    let t = s * 2;
+    let mut pixels = vec![empty; t * t];
+    for (k, child) in children.iter().enumerate() {
+        let Some(child) = child else { continue };
+        let (ox, oy) = ((k % 2) * s, (k / 2) * s);
+        for row in 0..s {
+            let at = (oy + row) * t + ox;
+            pixels[at..at + s].copy_from_slice(&child[row * s..(row + 1) * s]);
+        }
+    }
+    pixels
+}
+
+/// The `t`x`t` quadrant of a `2t`x`2t` parent that becomes its child `coord`.
+fn split_pixels<T: Copy>(parent: &[T], coord: TileCoord, t: usize) -> Vec<T> {
+    let s = t * 2;
+    let (ox, oy) = ((coord.x() % 2) as usize * t, (coord.y() % 2) as usize * t);
+    (0..t)
+        .flat_map(|row| {
+            let at = (oy + row) * s + ox;
+            parent[at..at + t].iter().copied()
+        })
+        .collect()
+}
+
+impl Retile {
+    pub async fn new(input: Location, codec: TileCodec, tile_size: u32) -> Result<Self> {
+        if tile_size != 256 && tile_size != 512 {
+            bail!("--tile-size must be 256 or 512, got: {tile_size}");
+        }
+        let reader = reader::open(&input).await?;
+
+        // Take the input's tile size from its first tile
+        let first = reader
+            .clone()
+            .entries()
+            .try_next()
+            .await?
+            .and_then(|e| e.iter_coords().next())
+            .with_context(|| format!("{input} has no tiles"))?;
+        let data = reader
+            .get_tile(first)
+            .await?
+            .with_context(|| format!("Failed to read the first tile of {input}"))?;
+        let from_size = match codec {
+            TileCodec::Rgb => Raster::decode_png(&data)?.width,
+            TileCodec::Dem(encoding) => encoding.decode(&data)?.width,
+        };
+        if from_size == tile_size {
+            bail!("{input} already has {tile_size}px tiles");
+        }
+        if from_size * 2 != tile_size && tile_size * 2 != from_size {
+            bail!("can only halve or double the tile size; {input} has {from_size}px tiles");
+        }
+
+        Ok(Self {
+            input,
+            reader,
+            codec,
+            from_size,
+            to_size: tile_size,
+        })
+    }
+
+    fn merging(&self) -> bool {
+        self.to_size > self.from_size
+    }
+
+    /// The input's header with the zoom range shifted by one level.
+    pub async fn output_header(&self) -> Result<OutputHeader> {
+        let mut header = OutputHeader::from_reader(&self.reader).await?;
+        if self.merging() {
+            if header.max_zoom == 0 {
+                bail!("{} only has zoom 0; there is nothing to merge", self.input);
+            }
+            header.min_zoom = header.min_zoom.max(1) - 1;
+            header.max_zoom -= 1;
+        } else {
+            header.min_zoom += 1;
+            header.max_zoom += 1;
+        }
+        header.center_zoom = header.center_zoom.clamp(header.min_zoom, header.max_zoom);
+        let tile_size = self.to_size;
+        header.edit_metadata(|m| {
+            m.insert("tileSize".into(), tile_size.into());
+        })?;
+        Ok(header)
+    }
+
+    /// Read the input in sibling groups: the four children of every output tile when
+    /// merging, or each input tile when splitting.
+    pub async fn read(
+        &self,
+        group_tx: Sender<GroupMsg>,
+        progress_tx: ProgressSender,
+    ) -> Result<()> {
+        let entries = self
+            .reader
+            .clone()
+            .entries()
+            .try_collect::<Vec<_>>()
+            .await?;
+        let coords = entries.iter().flat_map(|e| e.iter_coords());
+        let merging = self.merging();
+        let keys = group_keys(coords, merging)?;
+        let output_count = if merging { keys.len() } else { keys.len() * 4 };
+        progress_tx.send(ProgressMsg::UpdateCount(output_count as u64))?;
+        progress_tx.send(ProgressMsg::Log(format!(
+            "Retiling {} from {}px to {}px: {} output tiles",
+            self.input, self.from_size, self.to_size, output_count
+        )))?;
+
+        let concurrency = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+        let keys = Arc::new(keys);
+        let next_index = Arc::new(AtomicUsize::new(0));
+
+        let mut join_set: JoinSet<anyhow::Result<()>> = JoinSet::new();
+        for _ in 0..concurrency {
+            let reader = self.reader.clone();
+            let group_tx = group_tx.clone();
+            let keys = keys.clone();
+            let next_index = next_index.clone();
+            join_set.spawn(async move {
+                loop {
+                    let i = next_index.fetch_add(1, Ordering::Relaxed);
+                    if i >= keys.len() {
+                        break;
+                    }
+                    let (sources, outputs) = group_tiles(i, keys[i], merging)?;
+                    let mut data = Vec::with_capacity(sources.len());
+                    for source in sources {
+                        data.push(reader.get_tile(source).await?);
+                    }
+                    group_tx
+                        .send_async(GroupMsg::Group(SiblingGroup {
+                            sources: data,
+                            outputs,
+                        }))
+                        .await?;
+                }
+                Ok(())
+            });
+        }
+
+        while let Some(res) = join_set.join_next().await {
+            res??;
+        }
+        group_tx
+            .send_async(GroupMsg::End {
+                count: output_count,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Combine or split the groups from [`Self::read`] in parallel.
+    pub fn render(
+        &self,
+        input: Receiver<GroupMsg>,
+        output: Sender<WriteMsg>,
+        progress_tx: ProgressSender,
+    ) -> Result<()> {
+        input
+            .into_iter()
+            .par_bridge()
+            .try_for_each_with(output, |output, msg| {
+                let group = match msg {
+                    GroupMsg::Group(group) => group,
+                    GroupMsg::End { count } => {
+                        output.send(WriteMsg::End { count })?;
+                        return Ok(());
+                    }
+                };
+                let first = group.outputs[0].1;
+                let tiles = self.render_group(group).with_context(|| {
+                    format!("while retiling {}/{}/{}", first.z(), first.x(), first.y())
+                })?;
+                for msg in tiles {
+                    progress_tx
+                        .send(ProgressMsg::Processed(msg.tile.clone()))
+                        .context("Failed to send progress message")?;
+                    output.send(WriteMsg::Tile(msg))?;
+                }
+                Ok::<(), anyhow::Error>(())
+            })
+    }
+
+    fn render_group(&self, group: SiblingGroup) -> Result<Vec<WriteTileMsg>> {
+        match self.codec {
+            TileCodec::Rgb => self.retile_pixels(
+                group,
+                [0u8; 4],
+                |data| {
+                    let raster = Raster::decode_png(data)?;
+                    let pixels = raster
+                        .data
+                        .chunks_exact(4)
+                        .map(|p| [p[0], p[1], p[2], p[3]])
+                        .collect();
+                    Ok((raster.width, pixels))
+                },
+                |size, pixels| {
+                    if pixels.iter().all(|p| p[3] == 0) {
+                        return Ok(None);
+                    }
+                    let raster = Raster {
+                        width: size,
+                        height: size,
+                        data: pixels.into_iter().flatten().collect(),
+                    };
+                    raster.encode_png().map(Some)
+                },
+            ),
+            // Decode elevations so that missing children become no-data in the encoding
+            TileCodec::Dem(encoding) => self.retile_pixels(
+                group,
+                None,
+                |data| {
+                    let grid = encoding.decode(data)?;
+                    Ok((grid.width, grid.values))
+                },
+                |size, values| {
+                    if values.iter().all(Option::is_none) {
+                        return Ok(None);
+                    }
+                    let grid = DemGrid {
+                        width: size,
+                        height: size,
+                        values,
+                        alpha: None,
+                    };
+                    encoding.encode(grid, NoDataPolicy::Transparent).map(Some)
+                },
+            ),
+        }
+    }
+
+    /// Move pixels between tiles. `decode` returns a square tile's width and row-major
+    /// pixels; `encode` returns `None` for tiles left with no data.
+    fn retile_pixels<T: Copy>(
+        &self,
+        group: SiblingGroup,
+        empty: T,
+        decode: impl Fn(&[u8]) -> Result<(u32, Vec<T>)>,
+        encode: impl Fn(u32, Vec<T>) -> Result<Option<Bytes>>,
+    ) -> Result<Vec<WriteTileMsg>> {
+        let decoded = group
+            .sources
+            .iter()
+            .map(|s| s.as_deref().map(&decode).transpose())
+            .collect::<Result<Vec<_>>>()?;
+        for (width, pixels) in decoded.iter().flatten() {
+            if *width != self.from_size || pixels.len() != (width * width) as usize {
+                bail!(
+                    "expected {0}x{0} tiles, got one {width}px wide",
+                    self.from_size
+                );
+            }
+        }
+
+        let tiles: Vec<Option<Vec<T>>> = if self.merging() {
+            let children = decoded
+                .iter()
+                .map(|c| c.as_ref().map(|(_, pixels)| pixels.as_slice()))
+                .collect::<Vec<_>>();
+            vec![Some(merge_pixels(
+                &children,
+                self.from_size as usize,
+                empty,
+            ))]
+        } else {
+            group
+                .outputs
+                .iter()
+                .map(|&(_, c)| {
+                    let (_, parent) = decoded[0].as_ref()?;
+                    Some(split_pixels(parent, c, self.to_size as usize))
+                })
+                .collect()
+        };
+
+        group
+            .outputs
+            .into_iter()
+            .zip(tiles)
+            .map(|((index, coord), pixels)| {
+                Ok(WriteTileMsg {
+                    index,
+                    tile: coord.into(),
+                    tile_data: match pixels {
+                        Some(pixels) => encode(self.to_size, pixels)?,
+                        None => None,
+                    },
+                    reservation: None,
+                    stats: None,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Retile the input of `retile` and write the result to `output`.
+pub async fn retile(
+    retile: Retile,
+    output: Location,
+    force: bool,
+    mode: ProgressMode,
+) -> Result<()> {
+    let (group_tx, group_rx) = flume::bounded::<GroupMsg>(QUEUE_CAPACITY);
+    let (writer_tx, writer_rx) = flume::bounded::<WriteMsg>(QUEUE_CAPACITY);
+    let (progress_tx, progress_rx) = flume::unbounded::<ProgressMsg>();
+
+    let mut js = JoinSet::new();
+    let retile = Arc::new(retile);
+    let writer = Writer::new(output, force, retile.output_header().await?, None).await?;
+    let progress = Progress::new(mode);
+
+    let reader_retile = retile.clone();
+    let reader_progress_tx = progress_tx.clone();
+    js.spawn(async move { reader_retile.read(group_tx, reader_progress_tx).await });
+    let render_progress_tx = progress_tx.clone();
+    js.spawn_blocking(move || retile.render(group_rx, writer_tx, render_progress_tx));
+    js.spawn_blocking(move || writer.write(writer_rx, None, progress_tx));
+    js.spawn_blocking(move || progress.run(progress_rx));
+
+    writer::join_stages(js).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coord(z: u8, x: u32, y: u32) -> TileCoord {
+        TileCoord::new(z, x, y).unwrap()
+    }
+
+    #[test]
+    fn children_in_quadrant_order() {
+        assert_eq!(
+            children(coord(3, 2, 5)).unwrap(),
+            [
+                coord(4, 4, 10),
+                coord(4, 5, 10),
+                coord(4, 4, 11),
+                coord(4, 5, 11)
+            ]
+        );
+    }
+
+    #[test]
+    fn merging_groups_siblings_under_their_parent() {
+        let coords = [
+            coord(0, 0, 0),
+            coord(2, 3, 3),
+            coord(2, 0, 0),
+            coord(2, 1, 1),
+        ];
+        let keys = group_keys(coords.into_iter(), true).unwrap();
+        // Zoom 0 has no parent, and siblings share one group
+        assert_eq!(keys, vec![coord(1, 0, 0), coord(1, 1, 1)]);
+
+        let (sources, outputs) = group_tiles(1, keys[1], true).unwrap();
+        assert_eq!(sources, children(coord(1, 1, 1)).unwrap().to_vec());
+        assert_eq!(outputs, vec![(1, coord(1, 1, 1))]);
+    }
+
+    #[test]
+    fn splitting_numbers_children_in_tile_id_order() {
+        let keys = group_keys([coord(1, 1, 0), coord(1, 0, 0)].into_iter(), false).unwrap();
+        assert_eq!(keys, vec![coord(1, 0, 0), coord(1, 1, 0)]);
+
+        let (sources, outputs) = group_tiles(1, keys[1], false).unwrap();
+        assert_eq!(sources, vec![coord(1, 1, 0)]);
+        assert_eq!(
+            outputs.iter().map(|o| o.0).collect::<Vec<_>>(),
+            [4, 5, 6, 7]
+        );
+        let ids = outputs
+            .iter()
+            .map(|o| TileId::from(o.1).value())
+            .collect::<Vec<_>>();
+        assert!(ids.windows(2).all(|w| w[1] == w[0] + 1));
+    }
+
+    #[test]
+    fn merge_places_children_by_quadrant() {
+        let (a, b, d) = ([1, 2, 3, 4], [5, 6, 7, 8], [9, 10, 11, 12]);
+        let children = [Some(&a[..]), Some(&b[..]), None, Some(&d[..])];
+        assert_eq!(
+            merge_pixels(&children, 2, 0),
+            vec![
+                1, 2, 5, 6, //
+                3, 4, 7, 8, //
+                0, 0, 9, 10, //
+                0, 0, 11, 12,
+            ]
+        );
+    }
+
+    #[test]
+    fn split_takes_the_child_quadrant() {
+        let parent = (0..16).collect::<Vec<_>>();
+        assert_eq!(split_pixels(&parent, coord(2, 0, 0), 2), [0, 1, 4, 5]);
+        assert_eq!(split_pixels(&parent, coord(2, 1, 0), 2), [2, 3, 6, 7]);
+        assert_eq!(split_pixels(&parent, coord(2, 2, 1), 2), [8, 9, 12, 13]);
+        assert_eq!(split_pixels(&parent, coord(2, 3, 3), 2), [10, 11, 14, 15]);
+    }
+}
@@ -0,0 +1,454 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::{Value, json};
+
+use crate::{
+    dem::DemGrid,
+    progress::{ProgressMsg, Stage, TileOutcome},
+    prune::DropReason,
+};
+
+/// Minimum, maximum and mean of the elevations in a set of tiles.
+#[derive(Clone, Copy, Debug)]
+pub struct ElevationStats {
+    pub min: f64,
+    pub max: f64,
+    pub sum: f64,
+    /// Cells with a value
+    pub count: u64,
+}
+
+impl ElevationStats {
+    /// Summarize the cells of `grid` that have a value, if any do.
+    pub fn from_grid(grid: &DemGrid) -> Option<Self> {
+        let mut stats: Option<Self> = None;
+        for &m in grid.values.iter().flatten() {
+            let s = stats.get_or_insert(Self {
+                min: m,
+                max: m,
+                sum: 0.0,
+                count: 0,
+            });
+            s.min = s.min.min(m);
+            s.max = s.max.max(m);
+            s.sum += m;
+            s.count += 1;
+        }
+        stats
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// What became of one transformed tile, for the run's counters.
+#[derive(Clone, Copy, Debug)]
+pub struct TileStats {
+    pub outcome: TileOutcome,
+    pub bytes_in: u64,
+    /// The output size, if it is written
+    pub bytes_out: Option<u64>,
+    /// Took a repeated input tile's result rather than being read and transformed itself
+    pub reused: bool,
+    pub elevation: Option<ElevationStats>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ZoomStats {
+    tiles_in: u64,
+    /// Tiles that were read and transformed rather than reusing a repeated tile's result
+    unique_in: u64,
+    bytes_in: u64,
+    tiles_out: u64,
+    bytes_out: u64,
+    dropped_empty: u64,
+    dropped_constant: u64,
+    skipped: u64,
+    passed_through: u64,
+    elevation: Option<ElevationStats>,
+}
+
+impl ZoomStats {
+    fn dropped(&self) -> u64 {
+        self.dropped_empty + self.dropped_constant
+    }
+}
+
+/// Per-zoom counts of the tiles a run has transformed. A checkpoint saves the counts of the
+/// tiles it covers, so that a resumed run's failures, dropped tiles and report carry on from
+/// the interrupted one.
+#[derive(Clone, Debug, Default)]
+pub struct Counters {
+    zooms: BTreeMap<u8, ZoomStats>,
+}
+
+impl Counters {
+    pub fn record(&mut self, zoom: u8, tile: &TileStats) {
+        let z = self.zooms.entry(zoom).or_default();
+        z.tiles_in += 1;
+        z.unique_in += u64::from(!tile.reused);
+        z.bytes_in += tile.bytes_in;
+        match tile.outcome {
+            TileOutcome::Transformed => {}
+            TileOutcome::Dropped(DropReason::Empty) => z.dropped_empty += 1,
+            TileOutcome::Dropped(DropReason::Constant) => z.dropped_constant += 1,
+            TileOutcome::Skipped => z.skipped += 1,
+            TileOutcome::PassedThrough => z.passed_through += 1,
+        }
+        if let Some(bytes) = tile.bytes_out {
+            z.tiles_out += 1;
+            z.bytes_out += bytes;
+        }
+        if let Some(e) = &tile.elevation {
+            match &mut z.elevation {
+                Some(total) => total.merge(e),
+                None => z.elevation = Some(*e),
+            }
+        }
+    }
+
+    /// Tiles that failed to transform, skipped or passed through.
+    pub fn failed(&self) -> u64 {
+        self.zooms
+            .values()
+            .map(|z| z.skipped + z.passed_through)
+            .sum()
+    }
+
+    /// Dropped tiles per zoom level: `(zoom, empty, constant)`.
+    pub fn dropped(&self) -> impl Iterator<Item = (u8, u64, u64)> + '_ {
+        self.zooms
+            .iter()
+            .map(|(&zoom, z)| (zoom, z.dropped_empty, z.dropped_constant))
+            .filter(|&(_, empty, constant)| empty + constant > 0)
+    }
+
+    /// The raw counts, as saved in a checkpoint.
+    pub fn to_json(&self) -> Value {
+        self.zooms
+            .iter()
+            .map(|(zoom, z)| {
+                let counts = [
+                    z.tiles_in,
+                    z.unique_in,
+                    z.bytes_in,
+                    z.tiles_out,
+                    z.bytes_out,
+                    z.dropped_empty,
+                    z.dropped_constant,
+                    z.skipped,
+                    z.passed_through,
+                ];
+                let mut entry = json!({ "zoom": zoom, "counts": counts });
+                if let Some(e) = &z.elevation {
+                    entry["elevation"] = json!([e.min, e.max, e.sum, e.count]);
+                }
+                entry
+            })
+            .collect()
+    }
+
+    /// Read back counts saved with [`Self::to_json`].
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let invalid = || anyhow!("invalid counters: {value}");
+        let mut zooms = BTreeMap::new();
+        for entry in value.as_array().ok_or_else(invalid)? {
+            let zoom = entry["zoom"].as_u64().and_then(|z| u8::try_from(z).ok());
+            let counts = entry["counts"]
+                .as_array()
+                .map(|c| c.iter().map(Value::as_u64).collect::<Option<Vec<_>>>());
+            let (Some(zoom), Some(Some(counts))) = (zoom, counts) else {
+                return Err(invalid());
+            };
+            let [
+                tiles_in,
+                unique_in,
+                bytes_in,
+                tiles_out,
+                bytes_out,
+                dropped_empty,
+                dropped_constant,
+                skipped,
+                passed_through,
+            ] = counts[..]
+            else {
+                return Err(invalid());
+            };
+            let elevation = match &entry["elevation"] {
+                Value::Null => None,
+                e => match (e[0].as_f64(), e[1].as_f64(), e[2].as_f64(), e[3].as_u64()) {
+                    (Some(min), Some(max), Some(sum), Some(count)) => Some(ElevationStats {
+                        min,
+                        max,
+                        sum,
+                        count,
+                    }),
+                    _ => return Err(invalid()),
+                },
+            };
+            zooms.insert(
+                zoom,
+                ZoomStats {
+                    tiles_in,
+                    unique_in,
+                    bytes_in,
+                    tiles_out,
+                    bytes_out,
+                    dropped_empty,
+                    dropped_constant,
+                    skipped,
+                    passed_through,
+                    elevation,
+                },
+            );
+        }
+        Ok(Self { zooms })
+    }
+}
+
+/// Aggregates the progress messages of a run into a JSON report, written to `path` when the
+/// run finishes.
+pub struct RunStats {
+    path: PathBuf,
+    started: Instant,
+    counters: Counters,
+    stage_time: BTreeMap<&'static str, Duration>,
+}
+
+impl RunStats {
+    /// Start from `counters`, the counts of the tiles an interrupted run already did.
+    pub fn new(path: PathBuf, counters: Counters) -> Self {
+        Self {
+            path,
+            started: Instant::now(),
+            counters,
+            stage_time: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, msg: &ProgressMsg) {
+        match msg {
+            ProgressMsg::Transformed { tile, stats } => self.counters.record(tile.z(), stats),
+            ProgressMsg::StageTime(stage, busy) => {
+                let name = match stage {
+                    Stage::Read => "read",
+                    Stage::Transform => "transform",
+                    Stage::Write => "write",
+                };
+                *self.stage_time.entry(name).or_default() += *busy;
+            }
+            _ => {}
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let zooms = &self.counters.zooms;
+        let sum = |f: fn(&ZoomStats) -> u64| zooms.values().map(f).sum::<u64>();
+        let ratio = |out: u64, inp: u64| (inp > 0).then(|| out as f64 / inp as f64);
+        let (tiles_in, bytes_in) = (sum(|z| z.tiles_in), sum(|z| z.bytes_in));
+        let bytes_out = sum(|z| z.bytes_out);
+        let zooms = zooms
+            .iter()
+            .map(|(zoom, z)| {
+                let mut entry = json!({
+                    "zoom": zoom,
+                    "tiles_in": z.tiles_in,
+                    "unique_tiles_in": z.unique_in,
+                    "bytes_in": z.bytes_in,
+                    "tiles_out": z.tiles_out,
+                    "bytes_out": z.bytes_out,
+                    "compression_ratio": ratio(z.bytes_out, z.bytes_in),
+                    "dropped": z.dropped(),
+                    "skipped": z.skipped,
+                    "passed_through": z.passed_through,
+                });
+                if let Some(e) = &z.elevation {
+                    entry["elevation"] = json!({
+                        "min": e.min,
+                        "max": e.max,
+                        "mean": e.sum / e.count as f64,
+                    });
+                }
+                entry
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "elapsed_seconds": elapsed,
+            "tiles_in": tiles_in,
+            "unique_tiles_in": sum(|z| z.unique_in),
+            "bytes_in": bytes_in,
+            "tiles_out": sum(|z| z.tiles_out),
+            "bytes_out": bytes_out,
+            "compression_ratio": ratio(bytes_out, bytes_in),
+            "dropped": sum(ZoomStats::dropped),
+            "failed": sum(|z| z.skipped) + sum(|z| z.passed_through),
+            "skipped": sum(|z| z.skipped),
+            "passed_through": sum(|z| z.passed_through),
+            "tiles_per_second": (elapsed > 0.0).then(|| tiles_in as f64 / elapsed),
+            "stage_seconds": self
+                .stage_time
+                .iter()
+                .map(|(stage, busy)| (stage.to_string(), json!(busy.as_secs_f64())))
+                .collect::<serde_json::Map<_, _>>(),
+            "zooms": zooms,
+        })
+    }
+
+    pub fn write(&self) -> Result<()> {
+        let report = serde_json::to_string_pretty(&self.to_json())?;
+        std::fs::write(&self.path, report)
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pmtiles::TileCoord;
+
+    use super::*;
+
+    fn tile(
+        outcome: TileOutcome,
+        bytes_out: Option<u64>,
+        elevation: Option<(f64, f64)>,
+    ) -> TileStats {
+        TileStats {
+            outcome,
+            bytes_in: 100,
+            bytes_out,
+            reused: false,
+            elevation: elevation.map(|(min, max)| ElevationStats {
+                min,
+                max,
+                sum: min + max,
+                count: 2,
+            }),
+        }
+    }
+
+    fn transformed(z: u8, stats: TileStats) -> ProgressMsg {
+        ProgressMsg::Transformed {
+            tile: TileCoord::new(z, 0, 0).unwrap().into(),
+            stats,
+        }
+    }
+
+    fn sample() -> Counters {
+        let mut counters = Counters::default();
+        counters.record(
+            3,
+            &tile(TileOutcome::Transformed, Some(50), Some((-1.5, 10.0))),
+        );
+        counters.record(
+            3,
+            &tile(TileOutcome::Transformed, Some(70), Some((2.0, 30.25))),
+        );
+        counters.record(
+            3,
+            &tile(TileOutcome::Dropped(DropReason::Empty), None, None),
+        );
+        counters.record(
+            4,
+            &tile(TileOutcome::Dropped(DropReason::Constant), None, None),
+        );
+        counters.record(4, &tile(TileOutcome::Skipped, None, None));
+        counters.record(4, &tile(TileOutcome::PassedThrough, Some(100), None));
+        counters
+    }
+
+    #[test]
+    fn counters_round_trip_through_json() {
+        let counters = sample();
+        let json = counters.to_json();
+        let restored = Counters::from_json(&json).unwrap();
+        assert_eq!(restored.to_json(), json);
+        assert_eq!(restored.failed(), 2);
+        assert_eq!(
+            restored.dropped().collect::<Vec<_>>(),
+            [(3, 1, 0), (4, 0, 1)]
+        );
+        let elevation = restored.zooms[&3].elevation.unwrap();
+        assert_eq!(
+            (elevation.min, elevation.max, elevation.count),
+            (-1.5, 30.25, 4)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_counters() {
+        let zeros = [0u64; 9];
+        assert!(Counters::from_json(&json!({})).is_err());
+        assert!(Counters::from_json(&json!([{ "zoom": 3, "counts": [1, 2] }])).is_err());
+        assert!(Counters::from_json(&json!([{ "zoom": 300, "counts": zeros }])).is_err());
+        let entry = json!([{ "zoom": 3, "counts": zeros, "elevation": [1.0] }]);
+        assert!(Counters::from_json(&entry).is_err());
+        assert!(Counters::from_json(&json!([{ "zoom": 3, "counts": zeros }])).is_ok());
+    }
+
+    #[test]
+    fn report_sums_the_zoom_levels() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut stats = RunStats::new(dir.path().join("report.json"), Counters::default());
+        stats.record(&transformed(
+            3,
+            tile(TileOutcome::Transformed, Some(50), Some((0.0, 10.0))),
+        ));
+        stats.record(&transformed(
+            3,
+            tile(TileOutcome::Dropped(DropReason::Empty), None, None),
+        ));
+        stats.record(&transformed(5, tile(TileOutcome::Skipped, None, None)));
+        stats.record(&ProgressMsg::StageTime(Stage::Read, Duration::from_secs(2)));
+        stats.record(&ProgressMsg::StageTime(Stage::Read, Duration::from_secs(1)));
+
+        let report = stats.to_json();
+        assert_eq!(report["tiles_in"], 3);
+        assert_eq!(report["tiles_out"], 1);
+        assert_eq!(report["bytes_in"], 300);
+        assert_eq!(report["dropped"], 1);
+        assert_eq!(report["failed"], 1);
+        assert_eq!(report["stage_seconds"]["read"], 3.0);
+        let zooms = report["zooms"].as_array().unwrap();
+        assert_eq!(zooms.len(), 2);
+        assert_eq!(zooms[0]["zoom"], 3);
+        assert_eq!(zooms[0]["tiles_in"], 2);
+        assert_eq!(zooms[0]["compression_ratio"], 0.25);
+        assert_eq!(zooms[0]["elevation"]["mean"], 5.0);
+        assert_eq!(zooms[1]["skipped"], 1);
+
+        stats.write().unwrap();
+        let written: Value =
+            serde_json::from_str(&std::fs::read_to_string(dir.path().join("report.json")).unwrap())
+                .unwrap();
+        assert_eq!(written["zooms"], report["zooms"]);
+    }
+
+    #[test]
+    fn resumed_report_includes_the_earlier_run() {
+        let dir = tempfile::tempdir().unwrap();
+        // Saved with the checkpoint and read back on resume
+        let earlier = Counters::from_json(&sample().to_json()).unwrap();
+        let mut stats = RunStats::new(dir.path().join("report.json"), earlier);
+        stats.record(&transformed(
+            4,
+            tile(TileOutcome::Transformed, Some(10), None),
+        ));
+
+        let report = stats.to_json();
+        assert_eq!(report["tiles_in"], 7);
+        assert_eq!(report["tiles_out"], 4);
+        assert_eq!(report["failed"], 2);
+        assert_eq!(report["dropped"], 2);
+        assert_eq!(report["zooms"][1]["tiles_in"], 4);
+    }
+}
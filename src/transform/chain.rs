@@ -0,0 +1,107 @@
+use anyhow::{Result, bail};
+use bytes::Bytes;
+
+use crate::{dem::DemEncoding, tile::Tile, writer::OutputHeader};
+
+use crate::transform::{
+    Transform,
+    shared::{Neighbors, TransformProcess},
+};
+
+/// Transforms applied one after the other, written `a|b`: each one's output is the next one's
+/// input.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    first: Box<Transform>,
+    rest: Vec<Transform>,
+}
+
+impl Chain {
+    /// Only the first transform is given the tile's neighbors, as they are read from the
+    /// inputs rather than transformed.
+    pub fn from_transforms(transforms: Vec<Transform>) -> Result<Self> {
+        let mut transforms = transforms.into_iter();
+        let Some(first) = transforms.next() else {
+            bail!("a chain needs at least one transform");
+        };
+        let rest = transforms.collect::<Vec<_>>();
+        if rest.iter().any(Transform::needs_neighbors) {
+            bail!("only the first transform of a chain can use neighboring tiles");
+        }
+        Ok(Self {
+            first: Box::new(first),
+            rest,
+        })
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Transform> {
+        std::iter::once(&*self.first).chain(&self.rest)
+    }
+}
+
+impl TransformProcess for Chain {
+    fn transform(&self, input: &[u8]) -> Result<Bytes> {
+        let mut data = self.first.transform(input)?;
+        for t in &self.rest {
+            data = t.transform(&data)?;
+        }
+        Ok(data)
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        self.first.needs_neighbors()
+    }
+
+    fn uses_coordinate(&self) -> bool {
+        self.all().any(Transform::uses_coordinate)
+    }
+
+    fn transform_tile(&self, tile: &Tile, input: &[u8], neighbors: &Neighbors) -> Result<Bytes> {
+        let mut data = self.first.transform_tile(tile, input, neighbors)?;
+        for t in &self.rest {
+            data = t.transform_tile(tile, &data, &Neighbors::default())?;
+        }
+        Ok(data)
+    }
+
+    fn update_header(&self, header: &mut OutputHeader) -> Result<()> {
+        self.all().try_for_each(|t| t.update_header(header))
+    }
+
+    fn input_encoding(&self) -> Option<DemEncoding> {
+        self.first.input_encoding()
+    }
+
+    fn output_encoding(&self) -> Option<DemEncoding> {
+        self.rest.last().unwrap_or(&*self.first).output_encoding()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_chain() {
+        let transform: Transform = "gsidempng-to-float32|float32-to-terrainrgbpng:nodata=zero"
+            .parse()
+            .unwrap();
+        assert!(matches!(transform, Transform::Chain(_)));
+        assert_eq!(transform.input_encoding(), Some(DemEncoding::Gsi));
+        assert_eq!(transform.output_encoding(), Some(DemEncoding::TerrainRgb));
+        assert!(!transform.uses_coordinate());
+    }
+
+    #[test]
+    fn neighbors_only_for_the_first_transform() {
+        let contours = "contours:encoding=gsidem,interval=10";
+        let first = format!("{contours}|gsidempng-to-float32");
+        assert!(first.parse::<Transform>().unwrap().needs_neighbors());
+        let later = format!("gsidempng-to-float32|{contours}");
+        let err = later.parse::<Transform>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "only the first transform of a chain can use neighboring tiles"
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{Error, Result, anyhow, bail};
+use bytes::Bytes;
+use pmtiles::TileType;
+use serde_json::json;
+
+use crate::{
+    dem::{DemEncoding, DemGrid, FLOAT32_METADATA_KEY},
+    mvt::{self, LineFeature, Value},
+    tile::Tile,
+    writer::OutputHeader,
+};
+
+use crate::transform::shared::{Neighbors, Params, TransformProcess};
+
+const EXTENT: u32 = 4096;
+const DEFAULT_LEVELS: &str = "0:500:2500/9:100:500/11:50:250/13:10:50";
+
+/// Contour intervals used from `min_zoom` up to the next level's `min_zoom`.
+#[derive(Clone, Copy, Debug)]
+struct Level {
+    min_zoom: u8,
+    /// Interval between lines, in meters
+    minor: f64,
+    /// Every line at a multiple of this is marked as major
+    major: f64,
+}
+
+/// Contour intervals per zoom: `zoom:minor:major` entries separated by `/`.
+#[derive(Clone, Debug)]
+struct Levels(Vec<Level>);
+
+impl FromStr for Levels {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut levels = s
+            .split('/')
+            .map(|entry| {
+                let invalid =
+                    || anyhow!("invalid contour level: {entry}. expected zoom:minor:major");
+                let mut parts = entry.split(':');
+                let (Some(z), Some(minor), Some(major), None) =
+                    (parts.next(), parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(invalid());
+                };
+                let level = Level {
+                    min_zoom: z.parse().map_err(|_| invalid())?,
+                    minor: minor.parse().map_err(|_| invalid())?,
+                    major: major.parse().map_err(|_| invalid())?,
+                };
+                if level.minor <= 0.0 || level.major <= 0.0 {
+                    bail!("contour intervals must be positive: {entry}");
+                }
+                Ok(level)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        levels.sort_by_key(|l| l.min_zoom);
+        Ok(Self(levels))
+    }
+}
+
+impl Levels {
+    fn for_zoom(&self, z: u8) -> Option<&Level> {
+        self.0.iter().rev().find(|l| l.min_zoom <= z)
+    }
+}
+
+/// Trace contour lines from DEM tiles and encode them as Mapbox Vector Tiles.
+///
+/// Each tile is extended by one pixel into its east, south and south-east neighbors so that
+/// lines meet across tile edges.
+#[derive(Debug, Clone)]
+pub struct Contours {
+    encoding: DemEncoding,
+    levels: Levels,
+    /// Douglas-Peucker tolerance, in source pixels
+    simplify: f64,
+    layer: String,
+}
+
+impl Contours {
+    /// Options: `encoding=gsidem|terrainrgb|terrarium|float32` (required),
+    /// `levels=zoom:minor:major/...` or `interval=<m>[,major=<m>]` for every zoom,
+    /// `simplify=<px>` (default 0.5) and `layer=<name>` (default `contours`).
+    pub fn with_params(mut params: Params) -> Result<Self> {
+        let encoding = params
+            .take("encoding")?
+            .ok_or_else(|| anyhow!("contours needs the input DEM encoding: encoding=..."))?;
+        let interval: Option<f64> = params.take("interval")?;
+        let major: Option<f64> = params.take("major")?;
+        let levels: Option<Levels> = params.take("levels")?;
+        let levels = match (levels, interval) {
+            (Some(_), Some(_)) => bail!("use either levels or interval, not both"),
+            (Some(levels), None) => levels,
+            (None, Some(minor)) => {
+                let major = major.unwrap_or(minor * 5.0);
+                format!("0:{minor}:{major}").parse()?
+            }
+            (None, None) => DEFAULT_LEVELS.parse()?,
+        };
+        let simplify = params.take("simplify")?.unwrap_or(0.5);
+        let layer = params
+            .take("layer")?
+            .unwrap_or_else(|| "contours".to_string());
+        params.finish()?;
+        Ok(Self {
+            encoding,
+            levels,
+            simplify,
+            layer,
+        })
+    }
+
+    /// The tile's elevations plus one column and row from its neighbors. Missing neighbors
+    /// repeat the tile's own edge.
+    fn buffered_grid(&self, input: &[u8], neighbors: &Neighbors) -> Result<DemGrid> {
+        let own = self.encoding.decode(input)?;
+        let decode = |data: &Option<Bytes>| -> Result<Option<DemGrid>> {
+            data.as_ref()
+                .map(|d| self.encoding.decode(d))
+                .transpose()
+                .map(|g| g.filter(|g| g.width == own.width && g.height == own.height))
+        };
+        let east = decode(&neighbors.east)?;
+        let south = decode(&neighbors.south)?;
+        let south_east = decode(&neighbors.south_east)?;
+
+        let (w, h) = (own.width as usize, own.height as usize);
+        let own_at = |x: usize, y: usize| own.values[y.min(h - 1) * w + x.min(w - 1)];
+        let mut values = Vec::with_capacity((w + 1) * (h + 1));
+        for y in 0..=h {
+            for x in 0..=w {
+                let v = match (x == w, y == h) {
+                    (false, false) => own.values[y * w + x],
+                    (true, false) => east.as_ref().map_or(own_at(x, y), |g| g.values[y * w]),
+                    (false, true) => south.as_ref().map_or(own_at(x, y), |g| g.values[x]),
+                    (true, true) => south_east.as_ref().map_or(own_at(x, y), |g| g.values[0]),
+                };
+                values.push(v);
+            }
+        }
+        Ok(DemGrid {
+            width: own.width + 1,
+            height: own.height + 1,
+            values,
+            alpha: None,
+        })
+    }
+}
+
+impl TransformProcess for Contours {
+    fn transform(&self, _input: &[u8]) -> Result<Bytes> {
+        bail!("contours needs the tile coordinate and its neighbors")
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        true
+    }
+
+    fn transform_tile(&self, tile: &Tile, input: &[u8], neighbors: &Neighbors) -> Result<Bytes> {
+        let grid = self.buffered_grid(input, neighbors)?;
+        let Some(level) = self.levels.for_zoom(tile.z()) else {
+            return Ok(Bytes::new());
+        };
+        let tile_size = (grid.width - 1) as f64;
+        let scale = EXTENT as f64 / tile_size;
+        let tolerance = self.simplify;
+
+        let (lo, hi) = grid
+            .values
+            .iter()
+            .flatten()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        let mut features = Vec::new();
+        if lo <= hi {
+            let first = (lo / level.minor).ceil() as i64;
+            let last = (hi / level.minor).floor() as i64;
+            for k in first..=last {
+                let elevation = k as f64 * level.minor;
+                let lines = trace(&grid, elevation)
+                    .into_iter()
+                    .filter_map(|line| {
+                        // Pixel centers are at +0.5
+                        let line = simplify(&line, tolerance)
+                            .into_iter()
+                            .map(|(x, y)| {
+                                (
+                                    ((x + 0.5) * scale).round() as i32,
+                                    ((y + 0.5) * scale).round() as i32,
+                                )
+                            })
+                            .fold(Vec::<(i32, i32)>::new(), |mut acc, p| {
+                                if acc.last() != Some(&p) {
+                                    acc.push(p);
+                                }
+                                acc
+                            });
+                        (line.len() >= 2).then_some(line)
+                    })
+                    .collect::<Vec<_>>();
+                if lines.is_empty() {
+                    continue;
+                }
+                let ratio = elevation / level.major;
+                let major = (ratio - ratio.round()).abs() < 1e-9;
+                features.push(LineFeature {
+                    lines,
+                    properties: vec![
+                        ("ele", Value::Double(elevation)),
+                        ("major", Value::Bool(major)),
+                    ],
+                });
+            }
+        }
+
+        Ok(mvt::encode_tile(&[mvt::Layer {
+            name: self.layer.clone(),
+            extent: EXTENT,
+            features,
+        }])
+        .into())
+    }
+
+    fn update_header(&self, header: &mut OutputHeader) -> Result<()> {
+        header.tile_type = TileType::Mvt;
+        let (min_zoom, max_zoom) = (header.min_zoom, header.max_zoom);
+        header.edit_metadata(|m| {
+            m.remove("encoding");
+            m.remove(FLOAT32_METADATA_KEY);
+            m.insert("format".into(), json!("pbf"));
+            m.insert(
+                "vector_layers".into(),
+                json!([{
+                    "id": self.layer,
+                    "description": "Contour lines, in meters",
+                    "minzoom": min_zoom,
+                    "maxzoom": max_zoom,
+                    "fields": {
+                        "ele": "Number",
+                        "major": "Boolean",
+                    },
+                }]),
+            );
+        })
+    }
+}
+
+/// A point where a contour crosses a cell edge: horizontal or vertical, and the grid
+/// position of the edge's first corner. Neighboring cells share these keys, which is how
+/// segments are joined into lines.
+type EdgeKey = (bool, u32, u32);
+
+/// Trace the contour at `elevation` through `grid` with marching squares. Returns lines in
+/// grid coordinates; closed rings repeat their first point at the end.
+fn trace(grid: &DemGrid, elevation: f64) -> Vec<Vec<(f64, f64)>> {
+    let (w, h) = (grid.width, grid.height);
+    let at = |x: u32, y: u32| grid.values[(y * w + x) as usize];
+
+    let mut points: HashMap<EdgeKey, (f64, f64)> = HashMap::new();
+    let mut segments: Vec<(EdgeKey, EdgeKey)> = Vec::new();
+    let mut crossing = |key: EdgeKey, a: f64, b: f64| {
+        points.entry(key).or_insert_with(|| {
+            let t = (elevation - a) / (b - a);
+            let (horizontal, x, y) = key;
+            if horizontal {
+                (x as f64 + t, y as f64)
+            } else {
+                (x as f64, y as f64 + t)
+            }
+        });
+        key
+    };
+
+    for y in 0..h - 1 {
+        for x in 0..w - 1 {
+            let (Some(tl), Some(tr), Some(br), Some(bl)) =
+                (at(x, y), at(x + 1, y), at(x + 1, y + 1), at(x, y + 1))
+            else {
+                continue;
+            };
+            let case = (((tl >= elevation) as u8) << 3)
+                | (((tr >= elevation) as u8) << 2)
+                | (((br >= elevation) as u8) << 1)
+                | (bl >= elevation) as u8;
+            if case == 0 || case == 15 {
+                continue;
+            }
+            let (tl_bit, tr_bit, br_bit, bl_bit) =
+                (case >> 3, (case >> 2) & 1, (case >> 1) & 1, case & 1);
+            let top = (tl_bit != tr_bit).then(|| crossing((true, x, y), tl, tr));
+            let right = (tr_bit != br_bit).then(|| crossing((false, x + 1, y), tr, br));
+            let bottom = (bl_bit != br_bit).then(|| crossing((true, x, y + 1), bl, br));
+            let left = (tl_bit != bl_bit).then(|| crossing((false, x, y), tl, bl));
+
+            match (top, right, bottom, left) {
+                (Some(t), Some(r), Some(b), Some(l)) => {
+                    // Saddle: decide by the cell center
+                    let center_above = (tl + tr + br + bl) / 4.0 >= elevation;
+                    if center_above == (tl_bit == 1) {
+                        // The top-left/bottom-right diagonal is connected
+                        segments.push((t, r));
+                        segments.push((b, l));
+                    } else {
+                        segments.push((l, t));
+                        segments.push((r, b));
+                    }
+                }
+                (t, r, b, l) => {
+                    let mut ends = [t, r, b, l].into_iter().flatten();
+                    if let (Some(a), Some(b)) = (ends.next(), ends.next()) {
+                        segments.push((a, b));
+                    }
+                }
+            }
+        }
+    }
+
+    join_segments(&segments, &points)
+}
+
+/// Chain segments sharing an edge crossing into lines, starting with open ends.
+fn join_segments(
+    segments: &[(EdgeKey, EdgeKey)],
+    points: &HashMap<EdgeKey, (f64, f64)>,
+) -> Vec<Vec<(f64, f64)>> {
+    let mut by_key: HashMap<EdgeKey, Vec<usize>> = HashMap::new();
+    for (i, &(a, b)) in segments.iter().enumerate() {
+        by_key.entry(a).or_default().push(i);
+        by_key.entry(b).or_default().push(i);
+    }
+    let mut used = vec![false; segments.len()];
+    let walk = |start: EdgeKey, first: usize, used: &mut [bool]| {
+        let mut line = vec![points[&start]];
+        let (mut key, mut seg) = (start, first);
+        loop {
+            used[seg] = true;
+            let (a, b) = segments[seg];
+            key = if a == key { b } else { a };
+            line.push(points[&key]);
+            match by_key[&key].iter().find(|&&s| !used[s]) {
+                Some(&next) => seg = next,
+                None => break,
+            }
+        }
+        line
+    };
+
+    let mut lines = Vec::new();
+    let mut open_ends = by_key
+        .iter()
+        .filter(|(_, segs)| segs.len() == 1)
+        .map(|(&key, segs)| (key, segs[0]))
+        .collect::<Vec<_>>();
+    // Deterministic output regardless of hash order
+    open_ends.sort_unstable_by_key(|&(_, seg)| seg);
+    for (key, seg) in open_ends {
+        if !used[seg] {
+            lines.push(walk(key, seg, &mut used));
+        }
+    }
+    for seg in 0..segments.len() {
+        if !used[seg] {
+            lines.push(walk(segments[seg].0, seg, &mut used));
+        }
+    }
+    lines
+}
+
+/// Douglas-Peucker line simplification.
+fn simplify(line: &[(f64, f64)], tolerance: f64) -> Vec<(f64, f64)> {
+    if line.len() <= 2 || tolerance <= 0.0 {
+        return line.to_vec();
+    }
+    let mut keep = vec![false; line.len()];
+    keep[0] = true;
+    keep[line.len() - 1] = true;
+    let mut stack = vec![(0, line.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let (a, b) = (line[first], line[last]);
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let len = dx.hypot(dy);
+        let distance = |p: (f64, f64)| {
+            if len == 0.0 {
+                (p.0 - a.0).hypot(p.1 - a.1)
+            } else {
+                ((p.0 - a.0) * dy - (p.1 - a.1) * dx).abs() / len
+            }
+        };
+        let farthest = (first + 1..last)
+            .map(|i| (i, distance(line[i])))
+            .max_by(|x, y| x.1.total_cmp(&y.1));
+        if let Some((i, _)) = farthest.filter(|&(_, d)| d > tolerance) {
+            keep[i] = true;
+            stack.push((first, i));
+            stack.push((i, last));
+        }
+    }
+    line.iter()
+        .zip(keep)
+        .filter_map(|(&p, k)| k.then_some(p))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid(width: u32, values: &[Option<f64>]) -> DemGrid {
+        DemGrid {
+            width,
+            height: values.len() as u32 / width,
+            values: values.to_vec(),
+            alpha: None,
+        }
+    }
+
+    #[test]
+    fn traces_a_step() {
+        let g = grid(3, &[0.0, 10.0, 10.0, 0.0, 10.0, 10.0].map(Some));
+        let lines = trace(&g, 5.0);
+        assert_eq!(lines.len(), 1);
+        let mut ends = lines[0].clone();
+        ends.sort_by(|a, b| a.1.total_cmp(&b.1));
+        assert_eq!(ends, vec![(0.5, 0.0), (0.5, 1.0)]);
+    }
+
+    #[test]
+    fn traces_a_closed_ring() {
+        let mut values = [Some(0.0); 9];
+        values[4] = Some(10.0);
+        let lines = trace(&grid(3, &values), 5.0);
+        assert_eq!(lines.len(), 1);
+        let ring = &lines[0];
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring.first(), ring.last());
+        for p in [(1.0, 0.5), (1.5, 1.0), (1.0, 1.5), (0.5, 1.0)] {
+            assert!(ring.contains(&p), "{p:?} missing from {ring:?}");
+        }
+    }
+
+    #[test]
+    fn skips_cells_with_nodata() {
+        let g = grid(2, &[Some(0.0), Some(10.0), None, Some(10.0)]);
+        assert!(trace(&g, 5.0).is_empty());
+    }
+
+    #[test]
+    fn flat_grid_has_no_contours() {
+        let g = grid(2, &[Some(5.0); 4]);
+        assert!(trace(&g, 5.0).is_empty());
+        assert!(trace(&g, 7.0).is_empty());
+    }
+
+    #[test]
+    fn simplify_drops_collinear_points() {
+        let line = [(0.0, 0.0), (1.0, 0.0), (2.0, 0.01), (3.0, 0.0), (3.0, 3.0)];
+        assert_eq!(
+            simplify(&line, 0.1),
+            vec![(0.0, 0.0), (3.0, 0.0), (3.0, 3.0)]
+        );
+        assert_eq!(simplify(&line, 0.0), line.to_vec());
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{dem::DemEncoding, writer::OutputHeader};
+
+use crate::transform::{
+    nodata::NoDataPolicy,
+    shared::{Params, TransformProcess},
+};
+
+/// Convert between DEM encodings by decoding to elevations and re-encoding.
+#[derive(Debug, Clone)]
+pub struct DemConvert {
+    from: DemEncoding,
+    to: DemEncoding,
+    nodata: NoDataPolicy,
+}
+
+impl DemConvert {
+    /// Options: `nodata=zero|transparent|nearest|idw|idw<radius>|<meters>`. Defaults to
+    /// `transparent`, which keeps no-data as no-data in the target encoding.
+    pub fn with_params(from: DemEncoding, to: DemEncoding, mut params: Params) -> Result<Self> {
+        let nodata = params.take("nodata")?.unwrap_or(NoDataPolicy::Transparent);
+        params.finish()?;
+        Ok(Self { from, to, nodata })
+    }
+}
+
+impl TransformProcess for DemConvert {
+    fn transform(&self, input: &[u8]) -> Result<Bytes> {
+        let grid = self.from.decode(input)?;
+        self.to.encode(grid, self.nodata)
+    }
+
+    fn update_header(&self, header: &mut OutputHeader) -> Result<()> {
+        self.to.update_header(header)
+    }
+
+    fn input_encoding(&self) -> Option<DemEncoding> {
+        Some(self.from)
+    }
+
+    fn output_encoding(&self) -> Option<DemEncoding> {
+        Some(self.to)
+    }
+}
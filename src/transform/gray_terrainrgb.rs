@@ -0,0 +1,65 @@
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{
+    dem::{DemEncoding, DemGrid},
+    raster::GrayRaster,
+};
+
+use crate::transform::{
+    nodata::NoDataPolicy,
+    shared::{Params, TransformProcess},
+};
+
+/// Grayscale elevation PNGs (8 or 16 bits per sample) to Terrain-RGB.
+///
+/// Elevation in meters is `value * scale + offset`, so e.g. decimeter-valued tiles use
+/// `scale=0.1`.
+#[derive(Debug, Clone)]
+pub struct GrayPngToTerrainRgbPng {
+    scale: f64,
+    offset: f64,
+    /// Raw sample value that marks no-data
+    nodata_value: Option<u16>,
+    nodata: NoDataPolicy,
+}
+
+impl GrayPngToTerrainRgbPng {
+    /// Options: `scale=<f64>`, `offset=<f64>`, `nodata-value=<u16>`,
+    /// `nodata=zero|transparent|nearest|idw|idw<radius>|<meters>`
+    pub fn with_params(mut params: Params) -> Result<Self> {
+        let t = Self {
+            scale: params.take("scale")?.unwrap_or(1.0),
+            offset: params.take("offset")?.unwrap_or(0.0),
+            nodata_value: params.take("nodata-value")?,
+            nodata: params.take("nodata")?.unwrap_or_default(),
+        };
+        params.finish()?;
+        Ok(t)
+    }
+}
+
+impl TransformProcess for GrayPngToTerrainRgbPng {
+    fn transform(&self, input: &[u8]) -> Result<Bytes> {
+        let raster = GrayRaster::decode_png(input)?;
+        let values = raster
+            .values
+            .iter()
+            .map(|&v| {
+                v.filter(|&x| Some(x) != self.nodata_value)
+                    .map(|x| x as f64 * self.scale + self.offset)
+            })
+            .collect();
+        let grid = DemGrid {
+            width: raster.width,
+            height: raster.height,
+            values,
+            alpha: None,
+        };
+        DemEncoding::TerrainRgb.encode(grid, self.nodata)
+    }
+
+    fn output_encoding(&self) -> Option<DemEncoding> {
+        Some(DemEncoding::TerrainRgb)
+    }
+}
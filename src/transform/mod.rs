@@ -1,44 +1,164 @@
 use std::str::FromStr;
 
-use anyhow::{Error, anyhow};
+use anyhow::Error;
 
+use crate::{dem::DemEncoding, tile::Tile, writer::OutputHeader};
+
+mod chain;
+mod contours;
+mod dem_convert;
+mod gray_terrainrgb;
 mod gsidem_terrainrgb;
+mod nodata;
+mod registry;
 mod shared;
+#[cfg(feature = "wasm")]
+mod wasm;
 
-pub use shared::TransformProcess;
+pub use nodata::NoDataPolicy;
+pub use registry::{CustomTransform, register, register_shared, registered};
+pub use shared::{Neighbors, Params, TransformProcess};
+#[cfg(feature = "wasm")]
+pub use wasm::WasmTransform;
 
 /// Supported transforms
 #[derive(Clone, Debug)]
 pub enum Transform {
     /// Transform Japan's GSI DEM PNG format to Mapbox TerrainRGB tiles
     GsiDemPngToTerrainRgbPng(gsidem_terrainrgb::GsiDemPngToTerrainRgbPng),
+    /// Transform grayscale (8/16-bit) elevation PNGs to Mapbox TerrainRGB tiles
+    GrayPngToTerrainRgbPng(gray_terrainrgb::GrayPngToTerrainRgbPng),
+    /// Convert between GSI DEM, TerrainRGB and float32 DEM tiles
+    DemConvert(dem_convert::DemConvert),
+    /// Trace contour lines from DEM tiles into Mapbox Vector Tiles
+    Contours(contours::Contours),
+    /// A transform registered with [`register`]
+    Custom(CustomTransform),
+    /// A WebAssembly plugin loaded at runtime
+    #[cfg(feature = "wasm")]
+    Wasm(WasmTransform),
+    /// Transforms applied one after the other: `a|b`
+    Chain(chain::Chain),
 }
 
 impl FromStr for Transform {
     type Err = Error;
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        match s {
-            "gsidempng-to-terrainrgbpng" => Ok(Self::GsiDemPngToTerrainRgbPng(
-                gsidem_terrainrgb::GsiDemPngToTerrainRgbPng::new(),
-            )),
-            _ => Err(anyhow!(
-                "invalid transform: {s}. valid values: gsidempng-to-terrainrgbpng"
-            )),
+        if s.contains('|') {
+            let transforms = s
+                .split('|')
+                .map(str::parse)
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(Self::Chain(chain::Chain::from_transforms(transforms)?));
+        }
+        // The rest is a path rather than options
+        #[cfg(feature = "wasm")]
+        if let Some(path) = s.strip_prefix("wasm:") {
+            return Ok(Self::Wasm(WasmTransform::load(std::path::Path::new(path))?));
         }
+        #[cfg(not(feature = "wasm"))]
+        if s.starts_with("wasm:") {
+            anyhow::bail!("wasm plugins need the tool built with the wasm feature");
+        }
+        let (name, params) = s.split_once(':').unwrap_or((s, ""));
+        registry::create(name, Params::parse(params)?)
     }
 }
 
 impl TransformProcess for Transform {
-    fn new() -> Self
-    where
-        Self: Sized,
-    {
-        panic!("Transform::new() should not be called directly");
-    }
-
     fn transform(&self, input: &[u8]) -> anyhow::Result<bytes::Bytes> {
         match self {
             Transform::GsiDemPngToTerrainRgbPng(t) => t.transform(input),
+            Transform::GrayPngToTerrainRgbPng(t) => t.transform(input),
+            Transform::DemConvert(t) => t.transform(input),
+            Transform::Contours(t) => t.transform(input),
+            Transform::Custom(t) => t.transform(input),
+            #[cfg(feature = "wasm")]
+            Transform::Wasm(t) => t.transform(input),
+            Transform::Chain(t) => t.transform(input),
+        }
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        match self {
+            Transform::GsiDemPngToTerrainRgbPng(t) => t.needs_neighbors(),
+            Transform::GrayPngToTerrainRgbPng(t) => t.needs_neighbors(),
+            Transform::DemConvert(t) => t.needs_neighbors(),
+            Transform::Contours(t) => t.needs_neighbors(),
+            Transform::Custom(t) => t.needs_neighbors(),
+            #[cfg(feature = "wasm")]
+            Transform::Wasm(t) => t.needs_neighbors(),
+            Transform::Chain(t) => t.needs_neighbors(),
+        }
+    }
+
+    fn uses_coordinate(&self) -> bool {
+        match self {
+            Transform::GsiDemPngToTerrainRgbPng(t) => t.uses_coordinate(),
+            Transform::GrayPngToTerrainRgbPng(t) => t.uses_coordinate(),
+            Transform::DemConvert(t) => t.uses_coordinate(),
+            Transform::Contours(t) => t.uses_coordinate(),
+            Transform::Custom(t) => t.uses_coordinate(),
+            #[cfg(feature = "wasm")]
+            Transform::Wasm(t) => t.uses_coordinate(),
+            Transform::Chain(t) => t.uses_coordinate(),
+        }
+    }
+
+    fn transform_tile(
+        &self,
+        tile: &Tile,
+        input: &[u8],
+        neighbors: &Neighbors,
+    ) -> anyhow::Result<bytes::Bytes> {
+        match self {
+            Transform::GsiDemPngToTerrainRgbPng(t) => t.transform_tile(tile, input, neighbors),
+            Transform::GrayPngToTerrainRgbPng(t) => t.transform_tile(tile, input, neighbors),
+            Transform::DemConvert(t) => t.transform_tile(tile, input, neighbors),
+            Transform::Contours(t) => t.transform_tile(tile, input, neighbors),
+            Transform::Custom(t) => t.transform_tile(tile, input, neighbors),
+            #[cfg(feature = "wasm")]
+            Transform::Wasm(t) => t.transform_tile(tile, input, neighbors),
+            Transform::Chain(t) => t.transform_tile(tile, input, neighbors),
+        }
+    }
+
+    fn update_header(&self, header: &mut OutputHeader) -> anyhow::Result<()> {
+        match self {
+            Transform::GsiDemPngToTerrainRgbPng(t) => t.update_header(header),
+            Transform::GrayPngToTerrainRgbPng(t) => t.update_header(header),
+            Transform::DemConvert(t) => t.update_header(header),
+            Transform::Contours(t) => t.update_header(header),
+            Transform::Custom(t) => t.update_header(header),
+            #[cfg(feature = "wasm")]
+            Transform::Wasm(t) => t.update_header(header),
+            Transform::Chain(t) => t.update_header(header),
+        }
+    }
+
+    fn input_encoding(&self) -> Option<DemEncoding> {
+        match self {
+            Transform::GsiDemPngToTerrainRgbPng(t) => t.input_encoding(),
+            Transform::GrayPngToTerrainRgbPng(t) => t.input_encoding(),
+            Transform::DemConvert(t) => t.input_encoding(),
+            Transform::Contours(t) => t.input_encoding(),
+            Transform::Custom(t) => t.input_encoding(),
+            #[cfg(feature = "wasm")]
+            Transform::Wasm(t) => t.input_encoding(),
+            Transform::Chain(t) => t.input_encoding(),
+        }
+    }
+
+    fn output_encoding(&self) -> Option<DemEncoding> {
+        match self {
+            Transform::GsiDemPngToTerrainRgbPng(t) => t.output_encoding(),
+            Transform::GrayPngToTerrainRgbPng(t) => t.output_encoding(),
+            Transform::DemConvert(t) => t.output_encoding(),
+            Transform::Contours(t) => t.output_encoding(),
+            Transform::Custom(t) => t.output_encoding(),
+            #[cfg(feature = "wasm")]
+            Transform::Wasm(t) => t.output_encoding(),
+            Transform::Chain(t) => t.output_encoding(),
         }
     }
 }
@@ -0,0 +1,184 @@
+use std::{collections::VecDeque, str::FromStr};
+
+use anyhow::{Error, anyhow};
+
+/// What to do with elevation cells that have no value.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NoDataPolicy {
+    /// Treat no-data as 0 m
+    #[default]
+    Zero,
+    /// Emit fully transparent pixels
+    Transparent,
+    /// Use a fixed elevation, in meters
+    Fill(f64),
+    /// Copy the value of the nearest valid cell in the tile
+    Nearest,
+    /// Inverse-distance weighted average of the valid cells within `radius` pixels, falling back
+    /// to the nearest valid cell when there are none
+    Idw { radius: u32 },
+}
+
+const DEFAULT_IDW_RADIUS: u32 = 16;
+
+impl FromStr for NoDataPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "zero" => Ok(Self::Zero),
+            "transparent" => Ok(Self::Transparent),
+            "nearest" => Ok(Self::Nearest),
+            "idw" => Ok(Self::Idw {
+                radius: DEFAULT_IDW_RADIUS,
+            }),
+            _ => {
+                if let Some(radius) = s.strip_prefix("idw") {
+                    return Ok(Self::Idw {
+                        radius: radius.parse()?,
+                    });
+                }
+                // NaN and infinity parse as floats but aren't elevations
+                s.parse::<f64>()
+                    .ok()
+                    .filter(|v| v.is_finite())
+                    .map(Self::Fill)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "invalid no-data policy: {s}. valid values: zero, transparent, nearest, idw, idw<radius>, or an elevation in meters"
+                        )
+                    })
+            }
+        }
+    }
+}
+
+impl NoDataPolicy {
+    /// Whether the policy needs to look at neighboring cells.
+    pub fn interpolates(&self) -> bool {
+        matches!(self, Self::Nearest | Self::Idw { .. })
+    }
+
+    /// The value to substitute for a no-data cell without looking at its neighbors.
+    /// `None` means the cell stays no-data (transparent).
+    pub fn constant(&self) -> Option<f64> {
+        match self {
+            Self::Zero => Some(0.0),
+            Self::Fill(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Resolve the no-data (`None`) cells of a row-major elevation grid in place. Cells left as
+    /// `None` should be emitted as transparent.
+    pub fn resolve(&self, grid: &mut [Option<f64>], width: usize) {
+        match self {
+            Self::Transparent => {}
+            Self::Zero | Self::Fill(_) => {
+                let v = self.constant();
+                for cell in grid.iter_mut().filter(|c| c.is_none()) {
+                    *cell = v;
+                }
+            }
+            Self::Nearest => {
+                if let Some(nearest) = nearest_valid(grid, width) {
+                    for (cell, n) in grid.iter_mut().zip(nearest) {
+                        *cell = Some(n);
+                    }
+                }
+            }
+            Self::Idw { radius } => {
+                let Some(nearest) = nearest_valid(grid, width) else {
+                    return;
+                };
+                let src = grid.to_vec();
+                let height = grid.len() / width;
+                let r = *radius as isize;
+                for (i, cell) in grid.iter_mut().enumerate() {
+                    if cell.is_some() {
+                        continue;
+                    }
+                    let (x, y) = ((i % width) as isize, (i / width) as isize);
+                    let (mut sum, mut weights) = (0.0, 0.0);
+                    for ny in (y - r).max(0)..=(y + r).min(height as isize - 1) {
+                        for nx in (x - r).max(0)..=(x + r).min(width as isize - 1) {
+                            if let Some(v) = src[ny as usize * width + nx as usize] {
+                                let d2 = ((nx - x).pow(2) + (ny - y).pow(2)) as f64;
+                                sum += v / d2;
+                                weights += 1.0 / d2;
+                            }
+                        }
+                    }
+                    *cell = Some(if weights > 0.0 {
+                        sum / weights
+                    } else {
+                        nearest[i]
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// For every cell, the value of the closest valid cell (by 4-neighbour steps), found with a
+/// multi-source breadth-first search. Returns `None` if the grid has no valid cells.
+fn nearest_valid(grid: &[Option<f64>], width: usize) -> Option<Vec<f64>> {
+    let height = grid.len() / width;
+    let mut out = vec![f64::NAN; grid.len()];
+    let mut queue = VecDeque::new();
+    for (i, cell) in grid.iter().enumerate() {
+        if let Some(v) = cell {
+            out[i] = *v;
+            queue.push_back(i);
+        }
+    }
+    if queue.is_empty() {
+        return None;
+    }
+    while let Some(i) = queue.pop_front() {
+        let (x, y) = (i % width, i / width);
+        let neighbors = [
+            (x > 0).then(|| i - 1),
+            (x + 1 < width).then(|| i + 1),
+            (y > 0).then(|| i - width),
+            (y + 1 < height).then(|| i + width),
+        ];
+        for n in neighbors.into_iter().flatten() {
+            if out[n].is_nan() {
+                out[n] = out[i];
+                queue.push_back(n);
+            }
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("zero".parse::<NoDataPolicy>().unwrap(), NoDataPolicy::Zero);
+        assert_eq!(
+            "idw".parse::<NoDataPolicy>().unwrap(),
+            NoDataPolicy::Idw {
+                radius: DEFAULT_IDW_RADIUS
+            }
+        );
+        assert_eq!(
+            "idw4".parse::<NoDataPolicy>().unwrap(),
+            NoDataPolicy::Idw { radius: 4 }
+        );
+        assert_eq!(
+            "-12.5".parse::<NoDataPolicy>().unwrap(),
+            NoDataPolicy::Fill(-12.5)
+        );
+    }
+
+    #[test]
+    fn rejects_non_finite_fill() {
+        for s in ["nan", "NaN", "inf", "-inf", "infinity"] {
+            assert!(s.parse::<NoDataPolicy>().is_err(), "{s} was accepted");
+        }
+    }
+}
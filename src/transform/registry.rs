@@ -0,0 +1,356 @@
+use std::{
+    fmt,
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use anyhow::{Result, anyhow, bail};
+use bytes::Bytes;
+
+use crate::{dem::DemEncoding, tile::Tile, writer::OutputHeader};
+
+use super::{
+    Transform, contours, dem_convert, gray_terrainrgb, gsidem_terrainrgb,
+    shared::{Neighbors, Params, TransformProcess},
+};
+
+type Factory = Arc<dyn Fn(Params) -> Result<Transform> + Send + Sync>;
+
+struct Entry {
+    name: String,
+    description: String,
+    factory: Factory,
+}
+
+/// Every transform that can be named on the command line, built-in ones first.
+static REGISTRY: LazyLock<RwLock<Vec<Entry>>> = LazyLock::new(|| RwLock::new(builtins()));
+
+fn builtin(
+    name: &str,
+    description: &str,
+    factory: impl Fn(Params) -> Result<Transform> + Send + Sync + 'static,
+) -> Entry {
+    Entry {
+        name: name.to_string(),
+        description: description.to_string(),
+        factory: Arc::new(factory),
+    }
+}
+
+fn dem_conversion(from: DemEncoding, to: DemEncoding) -> impl Fn(Params) -> Result<Transform> {
+    move |params: Params| {
+        Ok(Transform::DemConvert(dem_convert::DemConvert::with_params(
+            from, to, params,
+        )?))
+    }
+}
+
+fn builtins() -> Vec<Entry> {
+    vec![
+        builtin(
+            "gsidempng-to-terrainrgbpng",
+            "Transform Japan's GSI DEM PNG format to Mapbox TerrainRGB tiles",
+            |params| {
+                Ok(Transform::GsiDemPngToTerrainRgbPng(
+                    gsidem_terrainrgb::GsiDemPngToTerrainRgbPng::with_params(params)?,
+                ))
+            },
+        ),
+        builtin(
+            "graypng-to-terrainrgbpng",
+            "Transform grayscale (8/16-bit) elevation PNGs to Mapbox TerrainRGB tiles",
+            |params| {
+                Ok(Transform::GrayPngToTerrainRgbPng(
+                    gray_terrainrgb::GrayPngToTerrainRgbPng::with_params(params)?,
+                ))
+            },
+        ),
+        builtin(
+            "gsidempng-to-float32",
+            "Transform GSI DEM tiles to float32 DEM tiles",
+            dem_conversion(DemEncoding::Gsi, DemEncoding::Float32),
+        ),
+        builtin(
+            "terrainrgbpng-to-float32",
+            "Transform TerrainRGB tiles to float32 DEM tiles",
+            dem_conversion(DemEncoding::TerrainRgb, DemEncoding::Float32),
+        ),
+        builtin(
+            "float32-to-gsidempng",
+            "Transform float32 DEM tiles to GSI DEM tiles",
+            dem_conversion(DemEncoding::Float32, DemEncoding::Gsi),
+        ),
+        builtin(
+            "float32-to-terrainrgbpng",
+            "Transform float32 DEM tiles to TerrainRGB tiles",
+            dem_conversion(DemEncoding::Float32, DemEncoding::TerrainRgb),
+        ),
+        builtin(
+            "contours",
+            "Trace contour lines from DEM tiles into Mapbox Vector Tiles",
+            |params| {
+                Ok(Transform::Contours(contours::Contours::with_params(
+                    params,
+                )?))
+            },
+        ),
+    ]
+}
+
+/// Make a transform of your own available by `name`, to [`Transform::from_str`] and so to
+/// the command line. `factory` is given the options that followed the name, and should
+/// reject any it doesn't know with [`Params::finish`].
+///
+/// ```
+/// # use pmtiles_raster_tool::{Params, Transform, TransformProcess, register};
+/// # #[derive(Clone)]
+/// # struct FloodDepth { scale: f64 }
+/// # impl TransformProcess for FloodDepth {
+/// #     fn transform(&self, input: &[u8]) -> anyhow::Result<bytes::Bytes> {
+/// #         Ok(input.iter().map(|&v| (v as f64 * self.scale) as u8).collect())
+/// #     }
+/// # }
+/// register("flood-depth", "Decode flood depth RGB tiles", |mut params: Params| {
+///     let scale = params.take("scale")?.unwrap_or(1.0);
+///     params.finish()?;
+///     Ok(FloodDepth { scale })
+/// })?;
+/// let transform: Transform = "flood-depth:scale=0.1".parse()?;
+/// # anyhow::Ok(())
+/// ```
+///
+/// Its output is taken to depend on the tile's coordinate, so every tile is transformed even
+/// where the inputs repeat one; see [`register_shared`] to transform repeats once.
+///
+/// [`Transform::from_str`]: std::str::FromStr::from_str
+pub fn register<T, F>(name: &str, description: &str, factory: F) -> Result<()>
+where
+    T: TransformProcess + 'static,
+    F: Fn(Params) -> Result<T> + Send + Sync + 'static,
+{
+    register_entry(name, description, factory, false)
+}
+
+/// Like [`register`], for a transform whose output only depends on the tile's data unless its
+/// [`TransformProcess::uses_coordinate`] says otherwise: tiles repeated in the inputs, such as
+/// empty sea tiles, are transformed once and share the result.
+pub fn register_shared<T, F>(name: &str, description: &str, factory: F) -> Result<()>
+where
+    T: TransformProcess + 'static,
+    F: Fn(Params) -> Result<T> + Send + Sync + 'static,
+{
+    register_entry(name, description, factory, true)
+}
+
+fn register_entry<T, F>(name: &str, description: &str, factory: F, shared: bool) -> Result<()>
+where
+    T: TransformProcess + 'static,
+    F: Fn(Params) -> Result<T> + Send + Sync + 'static,
+{
+    if name.is_empty() || name.contains([':', ',', '=', '|']) || name.contains(char::is_whitespace)
+    {
+        bail!(
+            "invalid transform name: {name:?}. names can't be empty or contain whitespace, ':', ',', '=' or '|'"
+        );
+    }
+    let mut registry = REGISTRY.write().unwrap();
+    if registry.iter().any(|e| e.name == name) {
+        bail!("transform {name} is already registered");
+    }
+    let spec_name = name.to_string();
+    registry.push(Entry {
+        name: name.to_string(),
+        description: description.to_string(),
+        factory: Arc::new(move |params: Params| {
+            let spec = format!("{spec_name}:{params}");
+            Ok(Transform::Custom(CustomTransform {
+                spec,
+                shared,
+                inner: Arc::new(factory(params)?),
+            }))
+        }),
+    });
+    Ok(())
+}
+
+/// The name and description of every transform that can be named, built-in ones first.
+pub fn registered() -> Vec<(String, String)> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .map(|e| (e.name.clone(), e.description.clone()))
+        .collect()
+}
+
+/// Make the transform registered as `name`.
+pub(super) fn create(name: &str, params: Params) -> Result<Transform> {
+    let factory = {
+        let registry = REGISTRY.read().unwrap();
+        match registry.iter().find(|e| e.name == name) {
+            Some(e) => e.factory.clone(),
+            None => {
+                let names = registry.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+                return Err(anyhow!(
+                    "invalid transform: {name}. valid values: {}",
+                    names.join(", ")
+                ));
+            }
+        }
+    };
+    // Called without the lock, so a factory may look up other transforms
+    factory(params)
+}
+
+/// [`TransformProcess`] without the `Clone` and `Sized` requirements, so registered
+/// transforms of any type can be held behind one pointer.
+trait DynTransform: Send + Sync {
+    fn transform(&self, input: &[u8]) -> Result<Bytes>;
+    fn needs_neighbors(&self) -> bool;
+    fn uses_coordinate(&self) -> bool;
+    fn transform_tile(&self, tile: &Tile, input: &[u8], neighbors: &Neighbors) -> Result<Bytes>;
+    fn update_header(&self, header: &mut OutputHeader) -> Result<()>;
+    fn input_encoding(&self) -> Option<DemEncoding>;
+    fn output_encoding(&self) -> Option<DemEncoding>;
+}
+
+impl<T: TransformProcess> DynTransform for T {
+    fn transform(&self, input: &[u8]) -> Result<Bytes> {
+        TransformProcess::transform(self, input)
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        TransformProcess::needs_neighbors(self)
+    }
+
+    fn uses_coordinate(&self) -> bool {
+        TransformProcess::uses_coordinate(self)
+    }
+
+    fn transform_tile(&self, tile: &Tile, input: &[u8], neighbors: &Neighbors) -> Result<Bytes> {
+        TransformProcess::transform_tile(self, tile, input, neighbors)
+    }
+
+    fn update_header(&self, header: &mut OutputHeader) -> Result<()> {
+        TransformProcess::update_header(self, header)
+    }
+
+    fn input_encoding(&self) -> Option<DemEncoding> {
+        TransformProcess::input_encoding(self)
+    }
+
+    fn output_encoding(&self) -> Option<DemEncoding> {
+        TransformProcess::output_encoding(self)
+    }
+}
+
+/// A transform registered with [`register`].
+#[derive(Clone)]
+pub struct CustomTransform {
+    /// The name and options it was created from, which identify it in checkpoints
+    spec: String,
+    /// Registered with [`register_shared`]
+    shared: bool,
+    inner: Arc<dyn DynTransform>,
+}
+
+impl fmt::Debug for CustomTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CustomTransform").field(&self.spec).finish()
+    }
+}
+
+impl TransformProcess for CustomTransform {
+    fn transform(&self, input: &[u8]) -> Result<Bytes> {
+        self.inner.transform(input)
+    }
+
+    fn needs_neighbors(&self) -> bool {
+        self.inner.needs_neighbors()
+    }
+
+    fn uses_coordinate(&self) -> bool {
+        !self.shared || self.inner.uses_coordinate()
+    }
+
+    fn transform_tile(&self, tile: &Tile, input: &[u8], neighbors: &Neighbors) -> Result<Bytes> {
+        self.inner.transform_tile(tile, input, neighbors)
+    }
+
+    fn update_header(&self, header: &mut OutputHeader) -> Result<()> {
+        self.inner.update_header(header)
+    }
+
+    fn input_encoding(&self) -> Option<DemEncoding> {
+        self.inner.input_encoding()
+    }
+
+    fn output_encoding(&self) -> Option<DemEncoding> {
+        self.inner.output_encoding()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests share the registry, so each registers its own names.
+    #[derive(Clone)]
+    struct Scale(u8);
+
+    impl TransformProcess for Scale {
+        fn transform(&self, input: &[u8]) -> Result<Bytes> {
+            Ok(input.iter().map(|v| v * self.0).collect())
+        }
+    }
+
+    fn scale(mut params: Params) -> Result<Scale> {
+        let factor = params.take("factor")?.unwrap_or(1);
+        params.finish()?;
+        Ok(Scale(factor))
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        for name in ["", "a:b", "a,b", "a=b", "a|b", "a b", "a\tb", " a"] {
+            let err = register(name, "", scale).unwrap_err();
+            assert!(
+                err.to_string().starts_with("invalid transform name"),
+                "{name:?}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        register("test-duplicate", "", scale).unwrap();
+        let err = register_shared("test-duplicate", "", scale).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "transform test-duplicate is already registered"
+        );
+        assert!(register("contours", "", scale).is_err());
+    }
+
+    #[test]
+    fn looks_up_registered_transforms() {
+        register("test-lookup", "Multiply every byte", scale).unwrap();
+        assert!(registered().iter().any(
+            |(name, description)| name == "test-lookup" && description == "Multiply every byte"
+        ));
+
+        let transform: Transform = "test-lookup:factor=3".parse().unwrap();
+        assert!(matches!(transform, Transform::Custom(_)));
+        assert_eq!(&transform.transform(&[1, 2]).unwrap()[..], [3, 6]);
+        assert!("test-lookup:offset=3".parse::<Transform>().is_err());
+    }
+
+    #[test]
+    fn shared_transforms_ignore_the_coordinate() {
+        register("test-per-tile", "", scale).unwrap();
+        register_shared("test-shared", "", scale).unwrap();
+        let per_tile: Transform = "test-per-tile".parse().unwrap();
+        let shared: Transform = "test-shared".parse().unwrap();
+        assert!(per_tile.uses_coordinate());
+        assert!(!shared.uses_coordinate());
+    }
+}
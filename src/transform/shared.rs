@@ -1,9 +1,147 @@
-use anyhow::Result;
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use anyhow::{Result, anyhow, bail};
 use bytes::Bytes;
 
+use crate::{dem::DemEncoding, tile::Tile, writer::OutputHeader};
+
+/// The tiles to the east, south and south-east of the tile being transformed, for transforms
+/// that need to see across its right and bottom edges. They are resolved like the tile itself:
+/// picked by the conflict policy and filled from the other inputs and fallbacks.
+#[derive(Clone, Debug, Default)]
+pub struct Neighbors {
+    pub east: Option<Bytes>,
+    pub south: Option<Bytes>,
+    pub south_east: Option<Bytes>,
+}
+
 pub trait TransformProcess: Send + Sync + Clone {
-    fn new() -> Self
-    where
-        Self: Sized;
     fn transform(&self, input: &[u8]) -> Result<Bytes>;
+
+    /// Whether [`Self::transform_tile`] should be given the tile's [`Neighbors`].
+    fn needs_neighbors(&self) -> bool {
+        false
+    }
+
+    /// Whether the output depends on the tile's coordinate, so that repeated input tiles
+    /// can't share one result.
+    fn uses_coordinate(&self) -> bool {
+        false
+    }
+
+    /// Transform a tile knowing where it is. Defaults to [`Self::transform`].
+    fn transform_tile(&self, _tile: &Tile, input: &[u8], _neighbors: &Neighbors) -> Result<Bytes> {
+        self.transform(input)
+    }
+
+    /// Adjust the output archive's header and metadata, e.g. when the tile format changes.
+    fn update_header(&self, _header: &mut OutputHeader) -> Result<()> {
+        Ok(())
+    }
+
+    /// The DEM encoding the input tiles are expected in, for transforms that read elevation.
+    fn input_encoding(&self) -> Option<DemEncoding> {
+        None
+    }
+
+    /// The DEM encoding of the output tiles, for transforms that produce elevation.
+    fn output_encoding(&self) -> Option<DemEncoding> {
+        None
+    }
+}
+
+/// Options given after a transform name: `name:key=value,key2=value2`.
+#[derive(Debug, Default)]
+pub struct Params(BTreeMap<String, String>);
+
+impl Params {
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut params = BTreeMap::new();
+        for pair in s.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid transform option: {pair}. expected key=value"))?;
+            params.insert(key.to_string(), value.to_string());
+        }
+        Ok(Self(params))
+    }
+
+    /// Remove and parse the option `key`, if it was given.
+    pub fn take<T>(&mut self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.0
+            .remove(key)
+            .map(|v| {
+                v.parse::<T>()
+                    .map_err(|e| anyhow!("invalid value for {key}: {v}: {e}"))
+            })
+            .transpose()
+    }
+
+    /// Fail if any option was not consumed by the transform.
+    pub fn finish(self) -> Result<()> {
+        if let Some(key) = self.0.keys().next() {
+            bail!("unknown transform option: {key}");
+        }
+        Ok(())
+    }
+}
+
+/// Formats the options back into `key=value,key2=value2`.
+impl Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{key}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_takes_options() {
+        let mut params = Params::parse("nodata=zero,scale=0.5").unwrap();
+        assert_eq!(params.take::<f64>("scale").unwrap(), Some(0.5));
+        assert_eq!(params.take::<String>("missing").unwrap(), None);
+        assert_eq!(params.to_string(), "nodata=zero");
+        assert_eq!(
+            params.take::<String>("nodata").unwrap().as_deref(),
+            Some("zero")
+        );
+        params.finish().unwrap();
+    }
+
+    #[test]
+    fn empty_options() {
+        let params = Params::parse("").unwrap();
+        assert_eq!(params.to_string(), "");
+        params.finish().unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_options() {
+        assert!(Params::parse("nodata").is_err());
+        let mut params = Params::parse("scale=abc").unwrap();
+        assert!(params.take::<f64>("scale").is_err());
+    }
+
+    #[test]
+    fn finish_reports_unknown_options() {
+        let params = Params::parse("typo=1").unwrap();
+        let err = params.finish().unwrap_err();
+        assert_eq!(err.to_string(), "unknown transform option: typo");
+    }
 }
@@ -0,0 +1,302 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use wasmtime::{
+    Config, Engine, Instance, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+};
+
+use crate::{
+    tile::Tile,
+    transform::shared::{Neighbors, TransformProcess},
+};
+
+/// Most memory a plugin instance may grow to.
+const MEMORY_LIMIT: usize = 512 << 20;
+
+/// Fuel, roughly a WebAssembly instruction each, a plugin may use to set up an instance or to
+/// transform a tile: some seconds of work, so that a plugin stuck in a loop fails the tile.
+const FUEL_LIMIT: u64 = 10_000_000_000;
+
+/// A transform in a WebAssembly module, named as `wasm:path/to/plugin.wasm`.
+///
+/// The module may not import anything, and exports:
+///
+/// - `memory`
+/// - `alloc(len: i32) -> i32`: room for `len` bytes of input
+/// - `transform(z: i32, x: i32, y: i32, ptr: i32, len: i32) -> (i32, i32)`: transform the
+///   tile at `z/x/y` whose data is at `ptr`, returning where the output is and its length.
+///   A negative length is a failure, with `-len` bytes of UTF-8 error message at `ptr`.
+/// - optionally `dealloc(ptr: i32, len: i32)`, called for the input and output of every tile
+#[derive(Clone)]
+pub struct WasmTransform {
+    path: PathBuf,
+    /// Size and modification time of the module, so that checkpoints notice it changing
+    version: String,
+    engine: Engine,
+    module: Module,
+    /// Instances not in use. A plugin keeps state in its memory between calls, so an instance
+    /// is taken by one thread at a time; there are as many as threads used it at once. They
+    /// are freed with the last clone of the transform.
+    idle: Arc<Mutex<Vec<Plugin>>>,
+    /// Fuel for each tile
+    fuel: u64,
+}
+
+impl fmt::Debug for WasmTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("WasmTransform")
+            .field(&self.path)
+            .field(&self.version)
+            .finish()
+    }
+}
+
+impl WasmTransform {
+    /// Compile the module at `path` and check that it can be instantiated.
+    pub fn load(path: &Path) -> Result<Self> {
+        let engine = Engine::new(Config::new().consume_fuel(true))?;
+        let module = Module::from_file(&engine, path)
+            .with_context(|| format!("Failed to load wasm plugin {}", path.display()))?;
+        let plugin = Plugin::instantiate(&engine, &module)
+            .with_context(|| format!("Invalid wasm plugin {}", path.display()))?;
+        let meta = std::fs::metadata(path)?;
+        let modified = meta
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos();
+        Ok(Self {
+            path: path.to_path_buf(),
+            version: format!("{} bytes, modified {modified}", meta.len()),
+            engine,
+            module,
+            idle: Arc::new(Mutex::new(vec![plugin])),
+            fuel: FUEL_LIMIT,
+        })
+    }
+}
+
+impl TransformProcess for WasmTransform {
+    fn transform(&self, _input: &[u8]) -> Result<Bytes> {
+        bail!("wasm plugins need the tile coordinate")
+    }
+
+    fn uses_coordinate(&self) -> bool {
+        true
+    }
+
+    fn transform_tile(&self, tile: &Tile, input: &[u8], _neighbors: &Neighbors) -> Result<Bytes> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut plugin = match idle {
+            Some(plugin) => plugin,
+            None => Plugin::instantiate(&self.engine, &self.module)?,
+        };
+        let result = plugin.call(tile, input, self.fuel);
+        // A failed call may leave the instance in any state; a fresh one is made instead
+        if result.is_ok() {
+            self.idle.lock().unwrap().push(plugin);
+        }
+        result
+    }
+}
+
+/// An instance of a plugin module.
+struct Plugin {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32, i32, i32, i32), (i32, i32)>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+}
+
+impl Plugin {
+    fn instantiate(engine: &Engine, module: &Module) -> Result<Self> {
+        if let Some(import) = module.imports().next() {
+            bail!(
+                "plugins may not import anything, but it imports {}::{}",
+                import.module(),
+                import.name()
+            );
+        }
+        let limits = StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build();
+        let mut store = Store::new(engine, limits);
+        store.limiter(|limits| limits);
+        // For the module's start function, if it has one
+        store.set_fuel(FUEL_LIMIT)?;
+        let instance = Instance::new(&mut store, module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("plugin doesn't export its memory")?;
+        let alloc = instance.get_typed_func(&mut store, "alloc")?;
+        let transform = instance.get_typed_func(&mut store, "transform")?;
+        let dealloc = instance.get_typed_func(&mut store, "dealloc").ok();
+        Ok(Self {
+            store,
+            memory,
+            alloc,
+            transform,
+            dealloc,
+        })
+    }
+
+    fn call(&mut self, tile: &Tile, input: &[u8], fuel: u64) -> Result<Bytes> {
+        let len = i32::try_from(input.len()).context("tile is too large for a wasm plugin")?;
+        self.store.set_fuel(fuel)?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, input)
+            .context("alloc returned memory out of bounds")?;
+        let (out_ptr, out_len) = self.transform.call(
+            &mut self.store,
+            (
+                i32::from(tile.z()),
+                tile.x() as i32,
+                tile.y() as i32,
+                ptr,
+                len,
+            ),
+        )?;
+        let start = out_ptr as u32 as usize;
+        let output = self
+            .memory
+            .data(&self.store)
+            .get(start..start + out_len.unsigned_abs() as usize)
+            .context("transform returned memory out of bounds")?;
+        let output = if out_len < 0 {
+            Err(anyhow::anyhow!(
+                "plugin failed: {}",
+                String::from_utf8_lossy(output)
+            ))
+        } else {
+            Ok(Bytes::copy_from_slice(output))
+        };
+        if let Some(dealloc) = &self.dealloc {
+            dealloc.call(&mut self.store, (ptr, len))?;
+            dealloc.call(&mut self.store, (out_ptr, out_len.wrapping_abs()))?;
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pmtiles::TileCoord;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Replaces the input's first byte with the zoom level, or fails for tiles at x = 0.
+    const ROUND_TRIP: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "no tile at x=0")
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "transform")
+                (param $z i32) (param $x i32) (param $y i32) (param $ptr i32) (param $len i32)
+                (result i32 i32)
+            (if (i32.eqz (local.get $x))
+              (then (return (i32.const 0) (i32.const -14))))
+            (i32.store8 (local.get $ptr) (local.get $z))
+            (local.get $ptr) (local.get $len)))
+    "#;
+
+    fn load(dir: &TempDir, wat: &str) -> Result<WasmTransform> {
+        let path = dir.path().join("plugin.wasm");
+        std::fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        WasmTransform::load(&path)
+    }
+
+    fn tile(x: u32) -> Tile {
+        TileCoord::new(5, x, 2).unwrap().into()
+    }
+
+    #[test]
+    fn transforms_tiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let plugin = load(&dir, ROUND_TRIP).unwrap();
+        let neighbors = Neighbors::default();
+        let output = plugin
+            .transform_tile(&tile(1), &[9, 8, 7], &neighbors)
+            .unwrap();
+        assert_eq!(&output[..], [5, 8, 7]);
+        let err = plugin
+            .transform_tile(&tile(0), &[9], &neighbors)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "plugin failed: no tile at x=0");
+        // A plugin's failure leaves it usable
+        assert!(plugin.transform_tile(&tile(1), &[9], &neighbors).is_ok());
+    }
+
+    #[test]
+    fn rejects_imports() {
+        let dir = tempfile::tempdir().unwrap();
+        let wat = r#"(module (import "env" "log" (func)) (memory (export "memory") 1))"#;
+        let err = load(&dir, wat).unwrap_err();
+        assert!(
+            format!("{err:#}")
+                .ends_with("plugins may not import anything, but it imports env::log"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn limits_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let pages = MEMORY_LIMIT / 65536 + 1;
+        assert!(
+            load(
+                &dir,
+                &format!(r#"(module (memory (export "memory") {pages}))"#)
+            )
+            .is_err()
+        );
+
+        // Growing past the limit fails, so alloc has no room to give
+        let wat = format!(
+            r#"(module
+                 (memory (export "memory") 1)
+                 (func (export "alloc") (param i32) (result i32)
+                   (memory.grow (i32.const {pages})))
+                 (func (export "transform") (param i32 i32 i32 i32 i32) (result i32 i32)
+                   (i32.const 0) (i32.const 0)))"#
+        );
+        let plugin = load(&dir, &wat).unwrap();
+        let err = plugin
+            .transform_tile(&tile(1), &[1], &Neighbors::default())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "alloc returned memory out of bounds");
+    }
+
+    #[test]
+    fn runs_out_of_fuel() {
+        let dir = tempfile::tempdir().unwrap();
+        let wat = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "transform") (param i32 i32 i32 i32 i32) (result i32 i32)
+                (loop $forever (br $forever))
+                unreachable))
+        "#;
+        let mut plugin = load(&dir, wat).unwrap();
+        plugin.fuel = 100_000;
+        let err = plugin
+            .transform_tile(&tile(1), &[1], &Neighbors::default())
+            .unwrap_err();
+        let trap = err.downcast_ref::<wasmtime::Trap>();
+        assert_eq!(trap, Some(&wasmtime::Trap::OutOfFuel), "{err:#}");
+    }
+
+    #[test]
+    fn identified_by_the_module() {
+        let dir = tempfile::tempdir().unwrap();
+        let before = format!("{:?}", load(&dir, ROUND_TRIP).unwrap());
+        let changed = ROUND_TRIP.replace("no tile at x=0", "no tile at x==0");
+        let after = format!("{:?}", load(&dir, &changed).unwrap());
+        assert_ne!(before, after);
+    }
+}
@@ -1,48 +1,231 @@
-use anyhow::{Context, Result};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow};
 use flume::{Receiver, Sender};
+use pmtiles::TileType;
 use rayon::prelude::*;
 
 use crate::{
-    progress::{ProgressMsg, ProgressSender},
-    reader::ReadTileMsg,
+    composite,
+    dedupe::Cached,
+    dem::DemEncoding,
+    error_report::ErrorReport,
+    progress::{ProgressMsg, ProgressSender, Stage, TileOutcome},
+    prune::{DropCounts, PruneOptions},
+    reader::ReadMsg,
+    stats::{Counters, ElevationStats, TileStats},
     transform::{Transform, TransformProcess},
-    writer::WriteTileMsg,
+    writer::{WriteMsg, WriteTileMsg},
 };
 
+/// What to do with a tile that fails to transform.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ErrorPolicy {
+    /// Stop the whole run at the first failure
+    #[default]
+    Abort,
+    /// Leave the tile out of the output
+    Skip,
+    /// Copy the input tile to the output unchanged
+    Passthrough,
+}
+
 /// The logic to run the transform processes in parallel and coordinate with the rest of the app.
 pub struct Transformer {
     transform: Transform,
+    on_error: ErrorPolicy,
+    report: ErrorReport,
+    prune: PruneOptions,
+    dropped: DropCounts,
+    /// Decode output tiles with this encoding to report their elevations
+    elevation: Option<DemEncoding>,
+    /// The output tiles are vector tiles, checked for features by `prune`
+    vector: bool,
 }
 
 impl Transformer {
-    /// Create a new transformer for the given transform
-    pub fn new(transform: Transform) -> Self {
-        Self { transform }
+    /// Create a new transformer for the given transform. Failures are recorded in `report`
+    /// unless `on_error` is [`ErrorPolicy::Abort`]; tiles matching `prune` are left out.
+    pub fn new(
+        transform: Transform,
+        on_error: ErrorPolicy,
+        report: ErrorReport,
+        prune: PruneOptions,
+    ) -> Self {
+        Self {
+            transform,
+            on_error,
+            report,
+            prune,
+            dropped: DropCounts::default(),
+            elevation: None,
+            vector: false,
+        }
+    }
+
+    /// Carry on from the dropped tiles of an interrupted run.
+    pub fn with_counters(mut self, counters: &Counters) -> Self {
+        self.dropped = DropCounts::from_counters(counters);
+        self
+    }
+
+    /// Report the elevation range of each output tile, decoded with `encoding`.
+    pub fn with_elevation_stats(mut self, encoding: Option<DemEncoding>) -> Self {
+        self.elevation = encoding;
+        self
+    }
+
+    /// The type of the output tiles, as the transform's header says.
+    pub fn with_output_type(mut self, tile_type: TileType) -> Self {
+        self.vector = tile_type == TileType::Mvt;
+        self
     }
 
     pub fn run(
         &self,
-        input: Receiver<ReadTileMsg>,
-        output: Sender<WriteTileMsg>,
+        input: Receiver<ReadMsg>,
+        output: Sender<WriteMsg>,
         progress_tx: ProgressSender,
     ) -> Result<()> {
+        let busy_nanos = AtomicU64::new(0);
+        // Only GSI DEM tiles treat the 0x800000 sentinel as no-data
+        let gsi_input = self.transform.input_encoding() == Some(DemEncoding::Gsi);
+        let gsi_output = self.transform.output_encoding() == Some(DemEncoding::Gsi);
+        // Rayon's threads don't inherit the caller's span, so it is passed along explicitly
+        let stage_span = tracing::debug_span!("transform");
+        let _entered = stage_span.enter();
         input.into_iter().par_bridge().try_for_each_with(
             (output, self.transform.clone()),
             |(output, transform), msg| {
-                let transformed_data = transform
-                    .transform(&msg.tile_data)
-                    .with_context(|| format!("while transforming tile {}", msg.tile))?;
-                output.send(WriteTileMsg {
+                let mut msg = match msg {
+                    ReadMsg::Tile(msg) => msg,
+                    // The writer checks that every index before it arrives
+                    ReadMsg::End { count } => {
+                        output.send(WriteMsg::End { count })?;
+                        return Ok(());
+                    }
+                };
+                let started = Instant::now();
+                let reused = msg.cached.as_ref().is_some_and(|c| !c.owner);
+                let span = tracing::trace_span!(
+                    parent: &stage_span,
+                    "transform_tile",
+                    tile = %msg.tile,
+                    bytes_in = msg.tile_data.len(),
+                    bytes_out = tracing::field::Empty,
+                    reused,
+                )
+                .entered();
+                let result = match msg.cached.as_ref() {
+                    // Another tile with the same input is (being) transformed; use its result
+                    Some(cached) if !cached.owner => {
+                        let shared = cached.slot.wait();
+                        msg.tile_data = shared.input;
+                        shared.output.map_err(|e| anyhow!(e))
+                    }
+                    _ => {
+                        let result = composite::fill_nodata(&msg.tile_data, &msg.fill, gsi_input)
+                            .context("while compositing")
+                            .and_then(|tile_data| {
+                                transform.transform_tile(&msg.tile, &tile_data, &msg.neighbors)
+                            });
+                        // Shared without the coordinate, which each tile adds for itself
+                        if let Some(cached) = &msg.cached {
+                            cached.slot.set(Cached {
+                                input: msg.tile_data.clone(),
+                                output: result.as_ref().cloned().map_err(|e| format!("{e:#}")),
+                            });
+                        }
+                        result
+                    }
+                }
+                .with_context(|| format!("while transforming tile {}", msg.tile));
+                // A tile that can't be checked is a failure like any other, for `on_error`
+                let result = result.and_then(|data| {
+                    let reason = self
+                        .prune
+                        .check(&data, gsi_output, self.vector)
+                        .with_context(|| format!("while checking tile {}", msg.tile))?;
+                    Ok((data, reason))
+                });
+                let (tile_data, outcome) = match result {
+                    Ok((_, Some(reason))) => {
+                        self.dropped.add(msg.tile.z(), reason);
+                        (None, TileOutcome::Dropped(reason))
+                    }
+                    Ok((data, None)) => (Some(data), TileOutcome::Transformed),
+                    Err(e) if self.on_error == ErrorPolicy::Abort => return Err(e),
+                    Err(e) => {
+                        tracing::debug!(
+                            tile = %msg.tile,
+                            error = format!("{e:#}"),
+                            "transform failed"
+                        );
+                        self.report.record(&msg.tile, &e)?;
+                        match self.on_error {
+                            ErrorPolicy::Passthrough => {
+                                (Some(msg.tile_data.clone()), TileOutcome::PassedThrough)
+                            }
+                            _ => (None, TileOutcome::Skipped),
+                        }
+                    }
+                };
+                let elevation = match (self.elevation, &tile_data) {
+                    (Some(encoding), Some(data)) if outcome == TileOutcome::Transformed => encoding
+                        .decode(data)
+                        .ok()
+                        .and_then(|grid| ElevationStats::from_grid(&grid)),
+                    _ => None,
+                };
+                let tile_data_len = tile_data.as_ref().map(|d| d.len() as u64);
+                span.record("bytes_out", tile_data_len);
+                drop(span);
+                busy_nanos.fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+                let stats = TileStats {
+                    outcome,
+                    bytes_in: msg.tile_data.len() as u64,
+                    bytes_out: tile_data_len,
+                    reused,
+                    elevation,
+                };
+                // Only the output is held from here on, possibly in the writer's reorder buffer
+                msg.reservation.resize(tile_data_len.unwrap_or(0));
+                output.send(WriteMsg::Tile(WriteTileMsg {
                     index: msg.index,
                     tile: msg.tile.clone(),
-                    tile_data: transformed_data,
-                })?;
+                    tile_data,
+                    reservation: Some(msg.reservation),
+                    stats: Some(stats),
+                }))?;
                 progress_tx
-                    .send(ProgressMsg::Processed(msg.tile))
+                    .send(ProgressMsg::Transformed {
+                        tile: msg.tile,
+                        stats,
+                    })
                     .context("Failed to send progress message")?;
                 Ok::<(), anyhow::Error>(())
             },
         )?;
+        progress_tx.send(ProgressMsg::StageTime(
+            Stage::Transform,
+            Duration::from_nanos(busy_nanos.into_inner()),
+        ))?;
+        self.report.flush()?;
+        if !self.dropped.is_empty() {
+            progress_tx.send(ProgressMsg::Log(
+                self.dropped.to_string().trim_end().to_string(),
+            ))?;
+        }
+        if self.report.count() > 0 {
+            progress_tx.send(ProgressMsg::Log(format!(
+                "{} tiles failed to transform. See {} for details.",
+                self.report.count(),
+                self.report.path().display()
+            )))?;
+        }
         Ok(())
     }
 }
@@ -0,0 +1,252 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use bytes::Bytes;
+use futures_util::{StreamExt, TryStreamExt, stream};
+use pmtiles::{Compression, TileCoord, TileId, TileType};
+use serde_json::Value;
+
+use crate::{
+    error_report::ErrorReport,
+    ingest::TileCodec,
+    location::Location,
+    mercator,
+    progress::{Progress, ProgressMode, ProgressMsg},
+    raster::Raster,
+    reader::{ConflictPolicy, Reader},
+};
+
+/// Elevations outside this range (meters) are reported as implausible: a little beyond the
+/// deepest ocean trench and the highest peak.
+const PLAUSIBLE_ELEVATION: std::ops::RangeInclusive<f64> = -11_500.0..=9_000.0;
+
+/// Check that `data` is a valid tile of the declared type, returning its dimensions for
+/// raster tiles.
+fn check_tile(
+    data: &[u8],
+    tile_type: TileType,
+    compression: Compression,
+    codec: TileCodec,
+) -> Result<Option<(u32, u32)>> {
+    let magic_ok = match tile_type {
+        TileType::Png => data.starts_with(b"\x89PNG\r\n\x1a\n"),
+        TileType::Jpeg => data.starts_with(b"\xff\xd8\xff"),
+        TileType::Webp => data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP",
+        TileType::Avif => data.len() >= 12 && &data[4..12] == b"ftypavif",
+        TileType::Mvt if compression == Compression::Gzip => data.starts_with(b"\x1f\x8b"),
+        _ => true,
+    };
+    if !magic_ok {
+        bail!("not a {tile_type:?} tile");
+    }
+    match (tile_type, codec) {
+        (TileType::Png | TileType::Unknown, TileCodec::Dem(encoding)) => {
+            let grid = encoding.decode(data).context("decode elevation")?;
+            let out_of_range = grid
+                .values
+                .iter()
+                .flatten()
+                .find(|m| !PLAUSIBLE_ELEVATION.contains(m));
+            if let Some(m) = out_of_range {
+                bail!("implausible elevation: {m} m");
+            }
+            Ok(Some((grid.width, grid.height)))
+        }
+        (TileType::Png, TileCodec::Rgb) => {
+            let raster = Raster::decode_png(data).context("decode png")?;
+            Ok(Some((raster.width, raster.height)))
+        }
+        // Other formats are only checked by their leading bytes
+        _ => Ok(None),
+    }
+}
+
+/// Check an archive end to end: the header against its directories, every distinct tile
+/// against the declared tile type and size, and DEM elevations against a plausible range.
+/// Tiles that fail are written to `report`; returns an error if anything failed.
+pub async fn verify(
+    input: Location,
+    codec: Option<TileCodec>,
+    report: Option<PathBuf>,
+    mode: ProgressMode,
+) -> Result<()> {
+    let reader = Reader::new(vec![input], Vec::new(), ConflictPolicy::First, false).await?;
+    let (input, pmt) = reader.inputs().next().unwrap();
+    let header = pmt.get_header();
+    let metadata = pmt.get_metadata().await?;
+    let metadata: Value = if metadata.trim().is_empty() {
+        Value::Object(Default::default())
+    } else {
+        serde_json::from_str(&metadata).context("metadata is not valid JSON")?
+    };
+    let codec = codec.unwrap_or_else(|| TileCodec::detect(header.tile_type, &metadata));
+    let mut problems = Vec::new();
+
+    // Directories: entries in order and within the data section, and the header's counts
+    let mut entries = pmt.entries();
+    let mut n_entries = 0u64;
+    let mut addressed = 0u64;
+    let mut offsets = HashSet::new();
+    let mut contents = Vec::new();
+    let mut next_id = 0;
+    let mut zooms = (u8::MAX, 0u8);
+    // Tile x/y range at each zoom level
+    let mut extents: Vec<Option<(u32, u32, u32, u32)>> = vec![None; 32];
+    while let Some(entry) = entries.try_next().await? {
+        n_entries += 1;
+        addressed += u64::from(entry.run_length);
+        let first = TileCoord::from(TileId::new(entry.tile_id)?);
+        if entry.tile_id < next_id {
+            problems.push(format!(
+                "directory entry for tile {} is out of order or overlaps the previous one",
+                entry.tile_id
+            ));
+        }
+        next_id = entry.tile_id + u64::from(entry.run_length);
+        if entry.length == 0 || entry.offset + u64::from(entry.length) > header.data_length {
+            problems.push(format!(
+                "tile data of {}/{}/{} ({} bytes at {}) is outside the data section",
+                first.z(),
+                first.x(),
+                first.y(),
+                entry.length,
+                entry.offset
+            ));
+            continue;
+        }
+        if offsets.insert(entry.offset) {
+            contents.push(first);
+        }
+        for id in entry.tile_id..next_id {
+            let c = TileCoord::from(TileId::new(id)?);
+            zooms = (zooms.0.min(c.z()), zooms.1.max(c.z()));
+            let e = &mut extents[c.z() as usize];
+            *e = Some(e.map_or((c.x(), c.y(), c.x(), c.y()), |(x0, y0, x1, y1)| {
+                (x0.min(c.x()), y0.min(c.y()), x1.max(c.x()), y1.max(c.y()))
+            }));
+        }
+    }
+    let counts = [
+        ("addressed tiles", header.n_addressed_tiles, addressed),
+        ("tile entries", header.n_tile_entries, n_entries),
+        (
+            "tile contents",
+            header.n_tile_contents,
+            offsets.len() as u64,
+        ),
+    ];
+    for (name, declared, actual) in counts {
+        if let Some(declared) = declared.filter(|d| d.get() != actual) {
+            problems.push(format!(
+                "header declares {declared} {name}, the directories have {actual}"
+            ));
+        }
+    }
+
+    // Zooms and bounds against the tiles present
+    if addressed > 0 {
+        if zooms != (header.min_zoom, header.max_zoom) {
+            problems.push(format!(
+                "header zoom range is {}-{}, the tiles span {}-{}",
+                header.min_zoom, header.max_zoom, zooms.0, zooms.1
+            ));
+        }
+        for (z, extent) in extents.iter().enumerate() {
+            let Some((x0, y0, x1, y1)) = *extent else {
+                continue;
+            };
+            let z = z as u8;
+            let (bx0, by0) = mercator::lonlat_to_tile(
+                z,
+                header.min_longitude as f64,
+                header.max_latitude as f64,
+            );
+            let (bx1, by1) = mercator::lonlat_to_tile(
+                z,
+                header.max_longitude as f64,
+                header.min_latitude as f64,
+            );
+            if x0 < bx0 || y0 < by0 || x1 > bx1 || y1 > by1 {
+                problems.push(format!(
+                    "zoom {z} has tiles outside the header bounds: x {x0}-{x1}, y {y0}-{y1}; \
+                     bounds cover x {bx0}-{bx1}, y {by0}-{by1}"
+                ));
+            }
+        }
+    }
+
+    // Tiles: every distinct content once
+    let expected_size = metadata["tileSize"].as_u64().map(|s| s as u32);
+    let report = ErrorReport::new(
+        report.unwrap_or_else(|| input.sidecar_path(".verify.jsonl")),
+        false,
+    );
+    let (progress_tx, progress_rx) = flume::unbounded();
+    let progress =
+        tokio::task::spawn_blocking(move || Progress::new(mode).without_writes().run(progress_rx));
+    progress_tx.send(ProgressMsg::UpdateCount(contents.len() as u64))?;
+    let concurrency = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+    let mut tiles = stream::iter(contents)
+        .map(|coord| {
+            let pmt = pmt.clone();
+            async move {
+                let data = pmt.get_tile(coord).await;
+                (coord, data)
+            }
+        })
+        .buffered(concurrency);
+    let mut first_size = None;
+    while let Some((coord, data)) = tiles.next().await {
+        progress_tx.send(ProgressMsg::Processed(coord.into()))?;
+        let result = data
+            .map_err(anyhow::Error::from)
+            .and_then(|data| data.ok_or_else(|| anyhow!("tile is listed but can't be read")))
+            .and_then(|data: Bytes| {
+                check_tile(&data, header.tile_type, header.tile_compression, codec)
+            })
+            .and_then(|size| {
+                let Some((w, h)) = size else {
+                    return Ok(());
+                };
+                let expected = expected_size.map(|s| (s, s)).or(first_size);
+                match expected {
+                    Some((ew, eh)) if (w, h) != (ew, eh) => {
+                        bail!("tile is {w}x{h}, expected {ew}x{eh}")
+                    }
+                    Some(_) => Ok(()),
+                    None => {
+                        first_size = Some((w, h));
+                        Ok(())
+                    }
+                }
+            });
+        if let Err(e) = result {
+            report.record(&coord.into(), &e)?;
+        }
+    }
+    report.flush()?;
+
+    for problem in &problems {
+        progress_tx.send(ProgressMsg::Log(format!("{input}: {problem}")))?;
+    }
+    if problems.is_empty() && report.count() == 0 {
+        progress_tx.send(ProgressMsg::Log(format!("{input}: OK ({addressed} tiles)")))?;
+    }
+    progress_tx.send(ProgressMsg::Finished())?;
+    drop(progress_tx);
+    progress.await??;
+    if !problems.is_empty() || report.count() > 0 {
+        let mut message = format!("{input} failed verification");
+        if report.count() > 0 {
+            message += &format!(
+                ": {} tiles are invalid. See {} for details.",
+                report.count(),
+                report.path().display()
+            );
+        }
+        bail!(message);
+    }
+    Ok(())
+}
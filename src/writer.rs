@@ -1,49 +1,241 @@
-use std::{collections::BTreeMap, fs::File, path::PathBuf};
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::File,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use bytes::Bytes;
 use flume::Receiver;
-use pmtiles::{PmTilesStreamWriter, PmTilesWriter};
+use pmtiles::{Compression, PmTilesStreamWriter, PmTilesWriter, TileType};
+use s3::Bucket;
+use tempfile::NamedTempFile;
+use tokio::task::JoinSet;
 
 use crate::{
-    progress::{ProgressMsg, ProgressSender},
+    bucket,
+    budget::{MemoryBudget, Reservation},
+    checkpoint::Checkpoint,
+    location::Location,
+    progress::{ProgressMsg, ProgressSender, Stage},
     reader::PmTilesReader,
+    stats::TileStats,
     tile::Tile,
 };
 
 pub struct WriteTileMsg {
     pub index: usize,
     pub tile: Tile,
-    pub tile_data: Bytes,
+    /// `None` when the tile is left out of the output. The index is still consumed so that
+    /// the tiles after it aren't held back.
+    pub tile_data: Option<Bytes>,
+    /// Released once the tile is written
+    pub reservation: Option<Reservation>,
+    /// What the tile adds to the run's counters, saved with the checkpoint once it's written
+    pub stats: Option<TileStats>,
 }
 
-pub struct Writer {
-    output: PathBuf,
-    out_pmt: PmTilesStreamWriter<File>,
+/// What the writer is sent: every tile, then the end of the input.
+pub enum WriteMsg {
+    Tile(WriteTileMsg),
+    /// All `count` indexes have been sent. Without it, the input was cut short, e.g. by a
+    /// failed stage, and the output isn't finalized.
+    End {
+        count: usize,
+    },
 }
 
-impl Writer {
-    pub async fn new(output: PathBuf, force: bool, in_pmt: PmTilesReader) -> Result<Self> {
-        // Open output according to `force` semantics:
-        // - force = true  -> create if missing, overwrite if exists (truncate)
-        // - force = false -> create only, fail if already exists
-        let out_pmt_f = if force {
-            File::options()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(&output)
-        } else {
-            File::options()
-                .create_new(true)
-                .write(true)
-                .open(&output)
+/// The output was left unfinished as its input stopped short of the end.
+#[derive(Debug)]
+pub struct Incomplete {
+    written: usize,
+    /// The number of tiles, if the end of the input was reached
+    count: Option<usize>,
+    held: usize,
+}
+
+impl fmt::Display for Incomplete {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self {
+            written,
+            count,
+            held,
+        } = self;
+        match count {
+            Some(count) => write!(
+                f,
+                "Output incomplete: {written} of {count} tiles written, {held} more held back"
+            ),
+            None => write!(
+                f,
+                "Output incomplete: the input stopped after {written} tiles"
+            ),
         }
-        .context("Failed to open output file. Hint: try specifying --force if you want to overwrite an existing file.")?;
+    }
+}
 
+impl std::error::Error for Incomplete {}
+
+/// Wait for the stages of a run that ends in [`Writer::write`]. An incomplete output is only
+/// reported when no other stage failed, as such a failure is what cut the input short.
+pub(crate) async fn join_stages(mut js: JoinSet<Result<()>>) -> Result<()> {
+    let mut incomplete = None;
+    while let Some(res) = js.join_next().await {
+        match res? {
+            Err(e) if e.is::<Incomplete>() => incomplete = Some(e),
+            res => res?,
+        }
+    }
+    incomplete.map_or(Ok(()), Err)
+}
+
+/// Header fields and metadata for the output archive.
+#[derive(Clone, Debug)]
+pub struct OutputHeader {
+    pub tile_type: TileType,
+    pub tile_compression: Compression,
+    pub min_zoom: u8,
+    pub max_zoom: u8,
+    pub min_longitude: f32,
+    pub min_latitude: f32,
+    pub max_longitude: f32,
+    pub max_latitude: f32,
+    pub center_zoom: u8,
+    pub center_longitude: f32,
+    pub center_latitude: f32,
+    pub metadata: String,
+}
+
+impl OutputHeader {
+    /// Inherit the header and metadata of an input archive.
+    pub async fn from_reader(in_pmt: &PmTilesReader) -> Result<Self> {
         let header = in_pmt.get_header();
         let metadata = in_pmt.get_metadata().await?;
-        let out_pmt = PmTilesWriter::new(header.tile_type)
+        Ok(Self {
+            tile_type: header.tile_type,
+            tile_compression: header.tile_compression,
+            min_zoom: header.min_zoom,
+            max_zoom: header.max_zoom,
+            min_longitude: header.min_longitude,
+            min_latitude: header.min_latitude,
+            max_longitude: header.max_longitude,
+            max_latitude: header.max_latitude,
+            center_zoom: header.center_zoom,
+            center_longitude: header.center_longitude,
+            center_latitude: header.center_latitude,
+            metadata,
+        })
+    }
+
+    /// Edit the metadata JSON object. Empty metadata is treated as `{}`.
+    pub fn edit_metadata(
+        &mut self,
+        f: impl FnOnce(&mut serde_json::Map<String, serde_json::Value>),
+    ) -> Result<()> {
+        let mut metadata = if self.metadata.trim().is_empty() {
+            serde_json::Map::new()
+        } else {
+            serde_json::from_str(&self.metadata).context("metadata is not a JSON object")?
+        };
+        f(&mut metadata);
+        self.metadata = serde_json::to_string(&metadata)?;
+        Ok(())
+    }
+
+    /// Widen the zoom range and bounds to also cover `other`. The center and metadata of
+    /// `self` are kept.
+    pub fn merge(&mut self, other: &Self) -> Result<()> {
+        if self.tile_type != other.tile_type {
+            bail!(
+                "tile type mismatch: {:?} vs {:?}",
+                self.tile_type,
+                other.tile_type
+            );
+        }
+        if self.tile_compression != other.tile_compression {
+            bail!(
+                "tile compression mismatch: {:?} vs {:?}",
+                self.tile_compression,
+                other.tile_compression
+            );
+        }
+        self.min_zoom = self.min_zoom.min(other.min_zoom);
+        self.max_zoom = self.max_zoom.max(other.max_zoom);
+        self.min_longitude = self.min_longitude.min(other.min_longitude);
+        self.min_latitude = self.min_latitude.min(other.min_latitude);
+        self.max_longitude = self.max_longitude.max(other.max_longitude);
+        self.max_latitude = self.max_latitude.max(other.max_latitude);
+        Ok(())
+    }
+}
+
+/// An S3 destination. The archive is staged in a local temporary file and uploaded once it
+/// has been finalized, because the PMTiles writer needs to seek back to rewrite the header.
+struct Upload {
+    bucket: Box<Bucket>,
+    key: String,
+    staging: NamedTempFile,
+}
+
+pub struct Writer {
+    output: Location,
+    out_pmt: PmTilesStreamWriter<File>,
+    upload: Option<Upload>,
+    checkpoint: Option<Checkpoint>,
+    /// First reorder index not yet written
+    next: usize,
+}
+
+pub fn open_local(output: &Path, force: bool) -> Result<File> {
+    // Open output according to `force` semantics:
+    // - force = true  -> create if missing, overwrite if exists (truncate)
+    // - force = false -> create only, fail if already exists
+    let f = if force {
+        File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(output)
+    } else {
+        File::options().create_new(true).write(true).open(output)
+    }
+    .context("Failed to open output file. Hint: try specifying --force if you want to overwrite an existing file.")?;
+    Ok(f)
+}
+
+impl Writer {
+    /// Create the output. With a `checkpoint` to resume from, the tiles it journaled are
+    /// written again first.
+    pub async fn new(
+        output: Location,
+        force: bool,
+        header: OutputHeader,
+        checkpoint: Option<Checkpoint>,
+    ) -> Result<Self> {
+        let (out_pmt_f, upload) = match &output {
+            Location::Local(path) => (open_local(path, force)?, None),
+            Location::S3 { bucket: name, key } => {
+                let bucket = bucket::open(name)?;
+                if !force && bucket::exists(&bucket, key).await? {
+                    bail!(
+                        "Output {output} already exists. Hint: try specifying --force if you want to overwrite it."
+                    );
+                }
+                let staging = NamedTempFile::new().context("Failed to create staging file")?;
+                let f = staging.reopen()?;
+                let upload = Upload {
+                    bucket,
+                    key: key.clone(),
+                    staging,
+                };
+                (f, Some(upload))
+            }
+        };
+
+        let mut out_pmt = PmTilesWriter::new(header.tile_type)
             .tile_compression(header.tile_compression)
             .min_zoom(header.min_zoom)
             .max_zoom(header.max_zoom)
@@ -55,41 +247,211 @@ impl Writer {
             )
             .center_zoom(header.center_zoom)
             .center(header.center_longitude, header.center_latitude)
-            .metadata(&metadata)
+            .metadata(&header.metadata)
             .create(out_pmt_f)?;
 
-        Ok(Self { output, out_pmt })
+        let next = match &checkpoint {
+            Some(checkpoint) => {
+                checkpoint.replay(|coord, data| Ok(out_pmt.add_tile(coord, data)?))?;
+                checkpoint.resume_from()
+            }
+            None => 0,
+        };
+
+        Ok(Self {
+            output,
+            out_pmt,
+            upload,
+            checkpoint,
+            next,
+        })
     }
 
+    /// Write tiles in index order. `budget` is told which index is needed next, so the tiles
+    /// ahead of it are held back rather than piling up in the reorder buffer.
+    ///
+    /// The output is only finalized once [`WriteMsg::End`] was received and every index
+    /// before it written. If the input stops short of that, an error is returned and the
+    /// checkpoint is kept to resume from.
+    #[tracing::instrument(name = "write", skip_all, fields(output = %self.output))]
     pub fn write(
         mut self,
-        tile_rx: Receiver<WriteTileMsg>,
+        tile_rx: Receiver<WriteMsg>,
+        budget: Option<Arc<MemoryBudget>>,
         progress_tx: ProgressSender,
     ) -> Result<()> {
-        let mut next = 0usize;
-        // reorder buffer
-        // TODO: use a more efficient structure
-        let mut buf = BTreeMap::new();
+        let mut next = self.next;
+        let mut busy = Duration::ZERO;
+        if let Some(budget) = &budget {
+            budget.advance(next);
+        }
+        // Reorder buffer: slot i holds index `next + i`
+        let mut buf: VecDeque<Option<WriteTileMsg>> = VecDeque::new();
+        let mut end = None;
         for msg in tile_rx {
-            buf.insert(msg.index, msg);
-            while let Some(msg) = buf.remove(&next) {
-                self.out_pmt.add_tile(*msg.tile, &msg.tile_data)?;
+            let msg = match msg {
+                WriteMsg::Tile(msg) => msg,
+                WriteMsg::End { count } => {
+                    end = Some(count);
+                    continue;
+                }
+            };
+            let slot = msg
+                .index
+                .checked_sub(next)
+                .with_context(|| format!("tile index {} was sent twice", msg.index))?;
+            if slot >= buf.len() {
+                buf.resize_with(slot + 1, || None);
+            }
+            buf[slot] = Some(msg);
+            while buf.front().is_some_and(Option::is_some) {
+                let msg = buf.pop_front().flatten().unwrap();
+                if let Some(tile_data) = &msg.tile_data {
+                    let _span = tracing::trace_span!(
+                        "write_tile",
+                        tile = %msg.tile,
+                        bytes = tile_data.len(),
+                    )
+                    .entered();
+                    let started = Instant::now();
+                    self.out_pmt.add_tile(*msg.tile, tile_data)?;
+                    if let Some(checkpoint) = &mut self.checkpoint {
+                        checkpoint.record(*msg.tile, tile_data)?;
+                    }
+                    busy += started.elapsed();
+                }
+                if let (Some(checkpoint), Some(stats)) = (&mut self.checkpoint, &msg.stats) {
+                    checkpoint.count(msg.tile.z(), stats);
+                }
                 progress_tx
                     .send(ProgressMsg::Written(msg.tile))
                     .context("Failed to send progress message")?;
                 next += 1;
+                if let Some(checkpoint) = &mut self.checkpoint {
+                    checkpoint.tick(next)?;
+                }
+                if let Some(budget) = &budget {
+                    budget.advance(next);
+                }
+            }
+        }
+        if end != Some(next) || !buf.is_empty() {
+            if let Some(checkpoint) = &mut self.checkpoint {
+                checkpoint.save(next)?;
             }
+            return Err(Incomplete {
+                written: next,
+                count: end,
+                held: buf.iter().flatten().count(),
+            }
+            .into());
         }
-        println!("Finished writing tiles, finalizing archive...");
         progress_tx.send(ProgressMsg::Log(
             "Finished writing tiles, finalizing archive...".to_string(),
         ))?;
-        self.out_pmt.finalize()?;
+        let started = Instant::now();
+        tracing::debug_span!("finalize").in_scope(|| self.out_pmt.finalize())?;
+        busy += started.elapsed();
+        tracing::debug!(tiles = next, elapsed = ?started.elapsed(), "finalized archive");
+        if let Some(upload) = self.upload {
+            let _span = tracing::debug_span!("upload", key = %upload.key).entered();
+            progress_tx.send(ProgressMsg::Log(format!("Uploading to {}...", self.output)))?;
+            tokio::runtime::Handle::current().block_on(bucket::upload(
+                upload.staging.path(),
+                &upload.bucket,
+                &upload.key,
+            ))?;
+        }
+        if let Some(checkpoint) = self.checkpoint {
+            checkpoint.remove()?;
+        }
         progress_tx.send(ProgressMsg::Log(format!(
             "Finished writing to {}.",
-            self.output.display()
+            self.output
         )))?;
+        progress_tx.send(ProgressMsg::StageTime(Stage::Write, busy))?;
         progress_tx.send(ProgressMsg::Finished())?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pmtiles::TileId;
+
+    use super::*;
+
+    fn header() -> OutputHeader {
+        OutputHeader {
+            tile_type: TileType::Png,
+            tile_compression: Compression::None,
+            min_zoom: 0,
+            max_zoom: 1,
+            min_longitude: -180.0,
+            min_latitude: -85.0,
+            max_longitude: 180.0,
+            max_latitude: 85.0,
+            center_zoom: 0,
+            center_longitude: 0.0,
+            center_latitude: 0.0,
+            metadata: "{}".to_string(),
+        }
+    }
+
+    fn tile(index: usize) -> WriteMsg {
+        WriteMsg::Tile(WriteTileMsg {
+            index,
+            tile: TileId::new(index as u64).unwrap().into(),
+            tile_data: Some(Bytes::from_static(b"tile")),
+            reservation: None,
+            stats: None,
+        })
+    }
+
+    /// Write tiles `0..sent` to a new output with a checkpoint, followed by `end`.
+    async fn write(dir: &Path, sent: usize, end: Option<usize>) -> Result<()> {
+        let checkpoint = Checkpoint::open(dir.join("out.resume"), "fp".into())?;
+        let output = Location::Local(dir.join("out.pmtiles"));
+        let writer = Writer::new(output, false, header(), Some(checkpoint)).await?;
+        let (tile_tx, tile_rx) = flume::unbounded();
+        let (progress_tx, _progress_rx) = flume::unbounded();
+        for index in 0..sent {
+            tile_tx.send(tile(index))?;
+        }
+        if let Some(count) = end {
+            tile_tx.send(WriteMsg::End { count })?;
+        }
+        drop(tile_tx);
+        writer.write(tile_rx, None, progress_tx)
+    }
+
+    #[tokio::test]
+    async fn finalizes_after_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), 4, Some(4)).await.unwrap();
+        assert!(!dir.path().join("out.resume").exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_the_checkpoint_when_the_input_stops_short() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = write(dir.path(), 2, None).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Output incomplete: the input stopped after 2 tiles"
+        );
+        let checkpoint = Checkpoint::open(dir.path().join("out.resume"), "fp".into()).unwrap();
+        assert_eq!(checkpoint.resume_from(), 2);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_checkpoint_when_tiles_are_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = write(dir.path(), 3, Some(4)).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Output incomplete: 3 of 4 tiles written, 0 more held back"
+        );
+        assert!(dir.path().join("out.resume").exists());
+    }
+}